@@ -0,0 +1,233 @@
+//! Which pieces of a torrent someone has: us, or one of our peers.
+
+use crate::peer::Message;
+use bytes::Bytes;
+
+/// A set of pieces out of a torrent's `npieces`, laid out as in the `Bitfield` message: the
+/// high bit of the first byte is piece 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    payload: Vec<u8>,
+    npieces: usize,
+}
+
+impl Bitfield {
+    /// A bitfield without any of `npieces` pieces.
+    pub fn new(npieces: usize) -> Self {
+        Self {
+            payload: vec![0; npieces.div_ceil(u8::BITS as usize)],
+            npieces,
+        }
+    }
+
+    /// A bitfield with every one of `npieces` pieces set.
+    pub fn all(npieces: usize) -> Self {
+        let mut bitfield = Self::new(npieces);
+        bitfield.payload.fill(0xff);
+        let spare = bitfield.payload.len() * (u8::BITS as usize) - npieces;
+        if let Some(last) = bitfield.payload.last_mut() {
+            *last &= 0xff << spare;
+        }
+        bitfield
+    }
+
+    /// Take in the payload of a `Bitfield` message for a torrent of `npieces` pieces.
+    pub fn from_payload(payload: &[u8], npieces: usize) -> anyhow::Result<Self> {
+        let mut bitfield = Self::new(npieces);
+        anyhow::ensure!(
+            payload.len() == bitfield.payload.len(),
+            "bitfield of {} bytes for {npieces} pieces",
+            payload.len()
+        );
+        bitfield.payload.copy_from_slice(payload);
+        anyhow::ensure!(
+            bitfield == Self::all(npieces).intersection(&bitfield),
+            "bitfield has spare bits set"
+        );
+        Ok(bitfield)
+    }
+
+    /// How many pieces the torrent has, whether they're in the set or not.
+    pub fn npieces(&self) -> usize {
+        self.npieces
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// The `Bitfield` message that tells a peer about these pieces.
+    pub fn to_message(&self) -> Message {
+        Message::Bitfield(Bytes::copy_from_slice(&self.payload))
+    }
+
+    pub fn has_piece(&self, piece_i: usize) -> bool {
+        piece_i < self.npieces && self.payload[piece_i / (u8::BITS as usize)] & mask(piece_i) != 0
+    }
+
+    /// Add `piece_i`, returning whether it wasn't there already.
+    ///
+    /// Panics if the torrent has no such piece.
+    pub fn set_piece(&mut self, piece_i: usize) -> bool {
+        assert!(piece_i < self.npieces, "no piece {piece_i}");
+        let byte = &mut self.payload[piece_i / (u8::BITS as usize)];
+        let was_set = *byte & mask(piece_i) != 0;
+        *byte |= mask(piece_i);
+        !was_set
+    }
+
+    /// Remove `piece_i`, returning whether it was there.
+    ///
+    /// Panics if the torrent has no such piece.
+    pub fn clear_piece(&mut self, piece_i: usize) -> bool {
+        assert!(piece_i < self.npieces, "no piece {piece_i}");
+        let byte = &mut self.payload[piece_i / (u8::BITS as usize)];
+        let was_set = *byte & mask(piece_i) != 0;
+        *byte &= !mask(piece_i);
+        was_set
+    }
+
+    /// How many pieces are in the set.
+    pub fn count(&self) -> usize {
+        self.payload
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    /// Whether every piece of the torrent is in the set.
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.npieces
+    }
+
+    pub fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
+        self.payload.iter().enumerate().flat_map(|(byte_i, byte)| {
+            (0..u8::BITS).filter_map(move |bit_i| {
+                let piece_i = byte_i * (u8::BITS as usize) + (bit_i as usize);
+                (byte & mask(piece_i) != 0).then_some(piece_i)
+            })
+        })
+    }
+
+    /// The pieces in both sets.
+    ///
+    /// Panics if the two are for torrents with different numbers of pieces.
+    pub fn intersection(&self, other: &Bitfield) -> Bitfield {
+        self.combine(other, |ours, theirs| ours & theirs)
+    }
+
+    /// The pieces in this set that aren't in `other`: for instance, what a peer has that we
+    /// don't.
+    ///
+    /// Panics if the two are for torrents with different numbers of pieces.
+    pub fn difference(&self, other: &Bitfield) -> Bitfield {
+        self.combine(other, |ours, theirs| ours & !theirs)
+    }
+
+    fn combine(&self, other: &Bitfield, op: impl Fn(u8, u8) -> u8) -> Bitfield {
+        assert_eq!(
+            self.npieces, other.npieces,
+            "bitfields of different torrents"
+        );
+        Bitfield {
+            payload: self
+                .payload
+                .iter()
+                .zip(&other.payload)
+                .map(|(&ours, &theirs)| op(ours, theirs))
+                .collect(),
+            npieces: self.npieces,
+        }
+    }
+}
+
+/// The bit for `piece_i` within its byte.
+fn mask(piece_i: usize) -> u8 {
+    0x80 >> (piece_i % (u8::BITS as usize))
+}
+
+#[test]
+fn bitfield_has() {
+    let bf = Bitfield::from_payload(&[0b10101010, 0b01010101], 16).unwrap();
+    assert!(bf.has_piece(0));
+    assert!(!bf.has_piece(1));
+    assert!(!bf.has_piece(7));
+    assert!(!bf.has_piece(8));
+    assert!(bf.has_piece(15));
+    assert!(!bf.has_piece(16));
+    assert_eq!(bf.count(), 8);
+}
+
+#[test]
+fn bitfield_set() {
+    let mut bf = Bitfield::new(10);
+    assert_eq!(bf.payload(), [0, 0]);
+    assert!(bf.set_piece(9));
+    assert!(!bf.set_piece(9));
+    assert!(bf.set_piece(0));
+    assert_eq!(bf.payload(), [0b10000000, 0b01000000]);
+    assert!(bf.has_piece(9));
+    assert_eq!(bf.count(), 2);
+    assert!(bf.clear_piece(0));
+    assert!(!bf.clear_piece(0));
+    assert_eq!(bf.payload(), [0, 0b01000000]);
+    assert_eq!(
+        bf.to_message(),
+        Message::Bitfield(Bytes::from_static(&[0, 0b01000000]))
+    );
+}
+
+#[test]
+fn bitfield_all() {
+    let bf = Bitfield::all(10);
+    assert_eq!(bf.payload(), [0xff, 0b11000000]);
+    assert_eq!(bf.pieces().collect::<Vec<_>>(), (0..10).collect::<Vec<_>>());
+    assert!(bf.is_complete() && !Bitfield::new(10).is_complete());
+    assert_eq!(Bitfield::all(16).payload(), [0xff, 0xff]);
+    assert!(Bitfield::all(0).payload().is_empty());
+}
+
+#[test]
+fn bitfield_iter() {
+    let bf = Bitfield::from_payload(&[0b10101010, 0b01010101], 16).unwrap();
+    let mut pieces = bf.pieces();
+    assert_eq!(pieces.next(), Some(0));
+    assert_eq!(pieces.next(), Some(2));
+    assert_eq!(pieces.next(), Some(4));
+    assert_eq!(pieces.next(), Some(6));
+    assert_eq!(pieces.next(), Some(9));
+    assert_eq!(pieces.next(), Some(11));
+    assert_eq!(pieces.next(), Some(13));
+    assert_eq!(pieces.next(), Some(15));
+    assert_eq!(pieces.next(), None);
+}
+
+#[test]
+fn bitfield_validation() {
+    assert!(Bitfield::from_payload(&[0xff, 0b11000000], 10).is_ok());
+    // too short, too long, and with bits set for pieces that don't exist
+    assert!(Bitfield::from_payload(&[0xff], 10).is_err());
+    assert!(Bitfield::from_payload(&[0xff, 0, 0], 10).is_err());
+    assert!(Bitfield::from_payload(&[0xff, 0b11100000], 10).is_err());
+    assert!(Bitfield::from_payload(&[0, 0b00000001], 10).is_err());
+    assert!(Bitfield::from_payload(&[], 0).is_ok());
+}
+
+#[test]
+fn bitfield_set_operations() {
+    let ours = Bitfield::from_payload(&[0b11001100, 0b10000000], 9).unwrap();
+    let theirs = Bitfield::from_payload(&[0b10101010, 0b00000000], 9).unwrap();
+    assert_eq!(
+        ours.intersection(&theirs).pieces().collect::<Vec<_>>(),
+        vec![0, 4]
+    );
+    assert_eq!(
+        theirs.difference(&ours).pieces().collect::<Vec<_>>(),
+        vec![2, 6]
+    );
+    assert_eq!(
+        ours.difference(&theirs).pieces().collect::<Vec<_>>(),
+        vec![1, 5, 8]
+    );
+    assert_eq!(ours.difference(&ours).count(), 0);
+}
@@ -0,0 +1,205 @@
+//! Deciding which peers we upload to: tit-for-tat with a rotating optimistic unchoke.
+
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddrV4;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Settings for the choker.
+#[derive(Debug, Clone)]
+pub struct ChokerConfig {
+    /// How many peers we upload to at the same time, including the optimistic unchoke.
+    pub slots: usize,
+
+    /// How often the regular upload slots are re-evaluated.
+    pub interval: Duration,
+
+    /// How often the optimistic unchoke moves on to another peer.
+    pub optimistic_interval: Duration,
+
+    /// A peer that leaves our requests unanswered for this long is considered to be snubbing us,
+    /// and no longer gets a regular upload slot.
+    pub snub_timeout: Duration,
+}
+
+impl Default for ChokerConfig {
+    fn default() -> Self {
+        Self {
+            slots: 4,
+            interval: Duration::from_secs(10),
+            optimistic_interval: Duration::from_secs(30),
+            snub_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// What the choker needs to know about a peer.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Candidate {
+    pub(crate) addr: SocketAddrV4,
+    /// Whether the peer wants to download from us.
+    pub(crate) interested: bool,
+    pub(crate) snubbed: bool,
+    /// Bytes of piece data received from the peer so far.
+    pub(crate) downloaded: u64,
+    /// Bytes of piece data sent to the peer so far.
+    pub(crate) uploaded: u64,
+}
+
+pub(crate) struct Choker {
+    config: ChokerConfig,
+    /// The peers' byte counters as of the previous round, to compute their rates from.
+    previous: HashMap<SocketAddrV4, (u64, u64)>,
+    last_round: Option<Instant>,
+    optimistic: Option<(SocketAddrV4, Instant)>,
+}
+
+impl Choker {
+    pub(crate) fn new(config: ChokerConfig) -> Self {
+        Self {
+            config,
+            previous: HashMap::new(),
+            last_round: None,
+            optimistic: None,
+        }
+    }
+
+    pub(crate) fn config(&self) -> &ChokerConfig {
+        &self.config
+    }
+
+    /// When the next round is due.
+    pub(crate) fn next_round(&self) -> Instant {
+        match self.last_round {
+            Some(last) => last + self.config.interval,
+            None => Instant::now(),
+        }
+    }
+
+    /// Decide which of `candidates` to unchoke, choking the rest.
+    ///
+    /// The regular slots go to the interested peers that gave us the most since the last round
+    /// (or that took the most from us, once we're `seeding` and have no use for downloads), and
+    /// the remaining slot rotates between the other interested peers so that new peers get a
+    /// chance to prove themselves.
+    pub(crate) fn round(
+        &mut self,
+        candidates: &[Candidate],
+        seeding: bool,
+    ) -> HashSet<SocketAddrV4> {
+        let now = Instant::now();
+        // every peer's rate is over the same period, so bytes since the last round rank the same
+        let mut regular: Vec<_> = candidates
+            .iter()
+            .filter(|peer| peer.interested && !peer.snubbed)
+            .map(|peer| {
+                let (downloaded, uploaded) =
+                    self.previous.get(&peer.addr).copied().unwrap_or_default();
+                let rate = if seeding {
+                    peer.uploaded.saturating_sub(uploaded)
+                } else {
+                    peer.downloaded.saturating_sub(downloaded)
+                };
+                (rate, peer.addr)
+            })
+            .collect();
+        regular.sort_unstable_by_key(|&(rate, _)| std::cmp::Reverse(rate));
+        let mut unchoked: HashSet<_> = regular
+            .into_iter()
+            .take(self.config.slots.saturating_sub(1))
+            .map(|(_, addr)| addr)
+            .collect();
+
+        if self.config.slots > 0 {
+            let keep = self.optimistic.filter(|&(addr, since)| {
+                now.duration_since(since) < self.config.optimistic_interval
+                    && !unchoked.contains(&addr)
+                    && candidates
+                        .iter()
+                        .any(|peer| peer.addr == addr && peer.interested)
+            });
+            self.optimistic = keep.or_else(|| {
+                let choked: Vec<_> = candidates
+                    .iter()
+                    .filter(|peer| peer.interested && !unchoked.contains(&peer.addr))
+                    .map(|peer| peer.addr)
+                    .collect();
+                choked
+                    .choose(&mut rand::thread_rng())
+                    .map(|&addr| (addr, now))
+            });
+            if let Some((addr, _)) = self.optimistic {
+                unchoked.insert(addr);
+            }
+        }
+
+        self.previous = candidates
+            .iter()
+            .map(|peer| (peer.addr, (peer.downloaded, peer.uploaded)))
+            .collect();
+        self.last_round = Some(now);
+        unchoked
+    }
+}
+
+#[test]
+fn choker_round() {
+    let peer = |port, interested, snubbed, downloaded, uploaded| Candidate {
+        addr: SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, port),
+        interested,
+        snubbed,
+        downloaded,
+        uploaded,
+    };
+    let mut choker = Choker::new(ChokerConfig {
+        slots: 3,
+        ..Default::default()
+    });
+    let candidates = [
+        peer(1, true, false, 100, 0),
+        peer(2, true, false, 300, 0),
+        peer(3, true, false, 200, 0),
+        // would be the fastest, but isn't interested or is snubbing us
+        peer(4, false, false, 1000, 0),
+        peer(5, true, true, 1000, 0),
+    ];
+    let unchoked = choker.round(&candidates, false);
+    assert_eq!(unchoked.len(), 3);
+    assert!(unchoked.contains(&candidates[1].addr));
+    assert!(unchoked.contains(&candidates[2].addr));
+    assert!(!unchoked.contains(&candidates[3].addr));
+    let optimistic = choker.optimistic.unwrap().0;
+    assert!(optimistic == candidates[0].addr || optimistic == candidates[4].addr);
+
+    // rates are over the last round only, and the optimistic unchoke sticks around
+    let candidates = [
+        peer(1, true, false, 1100, 0),
+        peer(2, true, false, 300, 0),
+        peer(3, true, false, 250, 0),
+        peer(4, false, false, 1000, 0),
+        peer(5, true, true, 1000, 0),
+    ];
+    let unchoked = choker.round(&candidates, false);
+    assert!(unchoked.contains(&candidates[0].addr));
+    assert!(unchoked.contains(&candidates[2].addr));
+    if optimistic != candidates[0].addr {
+        assert!(unchoked.contains(&optimistic));
+    }
+
+    // when seeding, it's about what peers take from us
+    let mut choker = Choker::new(ChokerConfig {
+        slots: 2,
+        ..Default::default()
+    });
+    let candidates = [peer(1, true, false, 0, 10), peer(2, true, false, 500, 5)];
+    let unchoked = choker.round(&candidates, true);
+    assert_eq!(unchoked.len(), 2);
+    assert_eq!(choker.optimistic.unwrap().0, candidates[1].addr);
+
+    let mut choker = Choker::new(ChokerConfig {
+        slots: 0,
+        ..Default::default()
+    });
+    assert!(choker.round(&candidates, false).is_empty());
+}
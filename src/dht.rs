@@ -0,0 +1,795 @@
+//! A Mainline DHT node (BEP 5), used to find peers without a tracker.
+
+use crate::discovery::{self, DiscoveredPeer, PeerOrigin, PeerSource, Progress};
+use anyhow::Context;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use krpc::{Args, Message, Response};
+use routing::{distance, RoutingTable, K};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::{oneshot, watch};
+
+mod krpc;
+mod routing;
+
+pub use routing::{Node, NodeId};
+
+/// Well-known nodes to join the DHT through.
+pub const DEFAULT_BOOTSTRAP: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// How long we wait for a node to answer a query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many queries a lookup keeps in flight at once.
+const ALPHA: usize = 3;
+
+/// How often the secret used to hand out tokens changes.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+/// How long we store a peer that announced itself to us.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// How often stored peers that have gone stale are dropped.
+const PEER_EXPIRY: Duration = Duration::from_secs(60);
+
+/// How many torrents we store announced peers for.
+const MAX_TORRENTS: usize = 2000;
+
+/// How many announced peers we store per torrent, which also keeps our `get_peers` answers
+/// within a datagram.
+const MAX_PEERS_PER_TORRENT: usize = 100;
+
+/// The longest we wait before reading from the socket again after it keeps failing.
+const MAX_RECEIVE_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How often a [`DhtSource`] repeats its lookup.
+const LOOKUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    /// The UDP address to listen on.
+    pub bind: SocketAddr,
+
+    /// Nodes (`host:port`) to contact when we know of no other nodes.
+    pub bootstrap: Vec<String>,
+
+    /// File to load known nodes from at startup, and save them to as we go.
+    pub node_cache: Option<PathBuf>,
+
+    /// Our node id. Random if not given.
+    pub id: Option<NodeId>,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 6881)),
+            bootstrap: DEFAULT_BOOTSTRAP
+                .iter()
+                .map(|&node| node.to_string())
+                .collect(),
+            node_cache: None,
+            id: None,
+        }
+    }
+}
+
+type Reply = Result<Response, (i64, String)>;
+
+/// A handle to a running DHT node. Cloning it gives another handle to the same node.
+///
+/// The node stops once all handles are dropped.
+#[derive(Clone)]
+pub struct Dht {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    id: NodeId,
+    socket: Arc<UdpSocket>,
+    config: DhtConfig,
+    table: Mutex<RoutingTable>,
+    pending: Mutex<HashMap<Vec<u8>, oneshot::Sender<Reply>>>,
+    next_transaction: AtomicU16,
+    peers: Mutex<Announced>,
+    secrets: Mutex<Secrets>,
+    receiver: Mutex<Option<tokio::task::JoinHandle<()>>>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Some(receiver) = self
+            .receiver
+            .get_mut()
+            .expect("lock is never poisoned")
+            .take()
+        {
+            receiver.abort();
+        }
+    }
+}
+
+struct Secrets {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated: Instant,
+}
+
+impl Secrets {
+    fn rotate_if_due(&mut self) {
+        if self.rotated.elapsed() > TOKEN_ROTATION {
+            self.previous = self.current;
+            self.current = rand::random();
+            self.rotated = Instant::now();
+        }
+    }
+
+    fn token(secret: &[u8; 20], addr: &SocketAddrV4) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        hasher.update(addr.ip().octets());
+        hasher.finalize().to_vec()
+    }
+}
+
+/// The peers that announced themselves to us, by torrent, with when they did.
+struct Announced {
+    torrents: HashMap<[u8; 20], HashMap<SocketAddrV4, Instant>>,
+    expired: Instant,
+}
+
+impl Announced {
+    fn new(now: Instant) -> Self {
+        Self {
+            torrents: HashMap::new(),
+            expired: now,
+        }
+    }
+
+    fn expire_if_due(&mut self, now: Instant) {
+        if now.duration_since(self.expired) < PEER_EXPIRY {
+            return;
+        }
+        self.expired = now;
+        self.torrents.retain(|_, peers| {
+            peers.retain(|_, announced| now.duration_since(*announced) < PEER_TTL);
+            !peers.is_empty()
+        });
+    }
+
+    /// Store `peer` for the torrent, unless we're storing as many torrents as we're willing to.
+    fn insert(&mut self, info_hash: [u8; 20], peer: SocketAddrV4, now: Instant) -> bool {
+        self.expire_if_due(now);
+        if !self.torrents.contains_key(&info_hash) && self.torrents.len() >= MAX_TORRENTS {
+            return false;
+        }
+        let peers = self.torrents.entry(info_hash).or_default();
+        if !peers.contains_key(&peer) && peers.len() >= MAX_PEERS_PER_TORRENT {
+            // the peer that announced longest ago is the likeliest to be gone
+            let oldest = peers
+                .iter()
+                .min_by_key(|&(_, &announced)| announced)
+                .map(|(&oldest, _)| oldest)
+                .expect("full, so not empty");
+            peers.remove(&oldest);
+        }
+        peers.insert(peer, now);
+        true
+    }
+
+    fn get(&mut self, info_hash: &[u8; 20], now: Instant) -> Vec<SocketAddrV4> {
+        self.expire_if_due(now);
+        self.torrents
+            .get(info_hash)
+            .map(|peers| {
+                peers
+                    .iter()
+                    .filter(|&(_, &announced)| now.duration_since(announced) < PEER_TTL)
+                    .map(|(&peer, _)| peer)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// What an iterative lookup found.
+struct Lookup {
+    peers: HashSet<SocketAddrV4>,
+    /// The closest nodes that answered, along with the token they gave us (for `get_peers`).
+    closest: Vec<(Node, Option<Vec<u8>>)>,
+}
+
+impl Dht {
+    pub async fn bind(config: DhtConfig) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(config.bind)
+            .await
+            .with_context(|| format!("bind dht socket to {}", config.bind))?;
+        let id = config.id.unwrap_or_else(rand::random);
+        let mut table = RoutingTable::new(id);
+        if let Some(cache) = &config.node_cache {
+            // a missing or broken cache just means we bootstrap from scratch
+            if let Ok(compact) = tokio::fs::read(cache).await {
+                for node in krpc::decode_nodes(&compact) {
+                    table.insert(node);
+                }
+            }
+        }
+
+        let inner = Arc::new(Inner {
+            id,
+            socket: Arc::new(socket),
+            config,
+            table: Mutex::new(table),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(rand::random()),
+            peers: Mutex::new(Announced::new(Instant::now())),
+            secrets: Mutex::new(Secrets {
+                current: rand::random(),
+                previous: rand::random(),
+                rotated: Instant::now(),
+            }),
+            receiver: Mutex::new(None),
+        });
+        let receiver = tokio::spawn(receive(Arc::clone(&inner.socket), Arc::downgrade(&inner)));
+        *inner.receiver.lock().expect("lock is never poisoned") = Some(receiver);
+        Ok(Self { inner })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.id
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.inner.socket.local_addr()?)
+    }
+
+    /// Number of nodes in the routing table.
+    pub fn nodes(&self) -> usize {
+        self.inner.table().len()
+    }
+
+    /// Join the DHT through the configured bootstrap nodes, and fill the routing table with the
+    /// nodes closest to us.
+    pub async fn bootstrap(&self) -> anyhow::Result<()> {
+        let mut routers = Vec::new();
+        for node in &self.inner.config.bootstrap {
+            match tokio::net::lookup_host(node.as_str()).await {
+                Ok(addrs) => routers.extend(addrs.filter_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(addr),
+                    SocketAddr::V6(_) => None,
+                })),
+                Err(e) => eprintln!("failed to resolve dht bootstrap node {node}: {e}"),
+            }
+        }
+        let target = self.inner.id;
+        futures_util::future::join_all(
+            routers
+                .into_iter()
+                .map(|router| self.find_node(router, target)),
+        )
+        .await;
+        self.lookup(target, false).await;
+        anyhow::ensure!(self.nodes() > 0, "no dht nodes reachable");
+        self.save().await;
+        Ok(())
+    }
+
+    /// Check that the node at `addr` is alive, and learn its id.
+    pub async fn ping(&self, addr: SocketAddrV4) -> anyhow::Result<NodeId> {
+        let response = self.query(addr, "ping", Args::default()).await?;
+        krpc::node_id(&response.id).context("invalid node id")
+    }
+
+    async fn find_node(&self, addr: SocketAddrV4, target: NodeId) -> anyhow::Result<Vec<Node>> {
+        let args = Args {
+            target: Some(ByteBuf::from(target.to_vec())),
+            ..Default::default()
+        };
+        let response = self.query(addr, "find_node", args).await?;
+        Ok(response
+            .nodes
+            .map(|nodes| krpc::decode_nodes(&nodes))
+            .unwrap_or_default())
+    }
+
+    /// Find peers for a torrent.
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddrV4> {
+        self.lookup(info_hash, true)
+            .await
+            .peers
+            .into_iter()
+            .collect()
+    }
+
+    /// Find peers for a torrent, and tell the nodes closest to it that we accept connections for
+    /// it on `port`.
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16) -> Vec<SocketAddrV4> {
+        let lookup = self.lookup(info_hash, true).await;
+        let announces = lookup
+            .closest
+            .into_iter()
+            .filter_map(|(node, token)| Some((node, token?)))
+            .map(|(node, token)| {
+                let args = Args {
+                    info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+                    port: Some(port),
+                    token: Some(ByteBuf::from(token)),
+                    ..Default::default()
+                };
+                async move { self.query(node.addr, "announce_peer", args).await }
+            });
+        futures_util::future::join_all(announces).await;
+        lookup.peers.into_iter().collect()
+    }
+
+    /// Write the routing table to the node cache, if one is configured.
+    pub async fn save(&self) {
+        let Some(cache) = &self.inner.config.node_cache else {
+            return;
+        };
+        let nodes: Vec<_> = self.inner.table().nodes().collect();
+        if let Err(e) = tokio::fs::write(cache, krpc::encode_nodes(&nodes)).await {
+            eprintln!("failed to save dht nodes to {}: {e}", cache.display());
+        }
+    }
+
+    async fn query(
+        &self,
+        addr: SocketAddrV4,
+        method: &str,
+        mut args: Args,
+    ) -> anyhow::Result<Response> {
+        let transaction = self
+            .inner
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        args.id = ByteBuf::from(self.inner.id.to_vec());
+        let (tx, rx) = oneshot::channel();
+        self.inner.pending().insert(transaction.clone(), tx);
+
+        let message = Message::query(transaction.clone(), method, args);
+        let message = serde_bencode::to_bytes(&message).context("encode dht query")?;
+        if let Err(e) = self.inner.socket.send_to(&message, addr).await {
+            self.inner.pending().remove(&transaction);
+            return Err(e).context("send dht query");
+        }
+
+        let reply = tokio::time::timeout(QUERY_TIMEOUT, rx).await;
+        self.inner.pending().remove(&transaction);
+        match reply {
+            Ok(Ok(Ok(response))) => {
+                if let Some(id) = krpc::node_id(&response.id) {
+                    self.inner.table().insert(Node { id, addr });
+                }
+                Ok(response)
+            }
+            Ok(Ok(Err((code, message)))) => {
+                anyhow::bail!("node {addr} replied with error {code}: {message}")
+            }
+            Ok(Err(_)) | Err(_) => {
+                self.inner.table().failed(addr);
+                anyhow::bail!("node {addr} did not reply to {method}")
+            }
+        }
+    }
+
+    /// Iteratively query nodes ever closer to `target`, until the closest nodes we know of have
+    /// all been asked.
+    async fn lookup(&self, target: NodeId, get_peers: bool) -> Lookup {
+        #[derive(PartialEq)]
+        enum State {
+            New,
+            Asked,
+            Answered(Option<Vec<u8>>),
+        }
+
+        let mut shortlist: BTreeMap<NodeId, (Node, State)> = self
+            .inner
+            .table()
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (distance(&node.id, &target), (node, State::New)))
+            .collect();
+        let mut peers = HashSet::new();
+
+        loop {
+            let batch: Vec<_> = shortlist
+                .values_mut()
+                .filter(|(_, state)| *state != State::Asked)
+                .take(K)
+                .filter(|(_, state)| *state == State::New)
+                .take(ALPHA)
+                .map(|(node, state)| {
+                    *state = State::Asked;
+                    *node
+                })
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let replies =
+                futures_util::future::join_all(batch.into_iter().map(|node| async move {
+                    let args = if get_peers {
+                        Args {
+                            info_hash: Some(ByteBuf::from(target.to_vec())),
+                            ..Default::default()
+                        }
+                    } else {
+                        Args {
+                            target: Some(ByteBuf::from(target.to_vec())),
+                            ..Default::default()
+                        }
+                    };
+                    let method = if get_peers { "get_peers" } else { "find_node" };
+                    (node, self.query(node.addr, method, args).await)
+                }))
+                .await;
+
+            for (node, reply) in replies {
+                let key = distance(&node.id, &target);
+                let Ok(response) = reply else {
+                    shortlist.remove(&key);
+                    continue;
+                };
+                for value in response.values.iter().flatten() {
+                    peers.extend(krpc::decode_peer(value));
+                }
+                for found in response
+                    .nodes
+                    .map(|nodes| krpc::decode_nodes(&nodes))
+                    .unwrap_or_default()
+                {
+                    if found.id != self.inner.id {
+                        shortlist
+                            .entry(distance(&found.id, &target))
+                            .or_insert((found, State::New));
+                    }
+                }
+                if let Some((_, state)) = shortlist.get_mut(&key) {
+                    *state = State::Answered(response.token.map(ByteBuf::into_vec));
+                }
+            }
+        }
+
+        let closest = shortlist
+            .into_values()
+            .filter_map(|(node, state)| match state {
+                State::Answered(token) => Some((node, token)),
+                _ => None,
+            })
+            .take(K)
+            .collect();
+        Lookup { peers, closest }
+    }
+}
+
+impl Inner {
+    fn table(&self) -> std::sync::MutexGuard<'_, RoutingTable> {
+        self.table.lock().expect("lock is never poisoned")
+    }
+
+    fn pending(&self) -> std::sync::MutexGuard<'_, HashMap<Vec<u8>, oneshot::Sender<Reply>>> {
+        self.pending.lock().expect("lock is never poisoned")
+    }
+
+    fn handle(&self, message: Message, from: SocketAddrV4) -> Option<Message> {
+        match message.y.as_str() {
+            "r" | "e" => {
+                let reply = match (message.r, message.e) {
+                    (Some(response), _) => Ok(response),
+                    (None, Some(error)) => Err(error),
+                    (None, None) => Err((krpc::ERROR_PROTOCOL, String::from("empty reply"))),
+                };
+                if let Some(waiting) = self.pending().remove(&message.t) {
+                    let _ = waiting.send(reply);
+                }
+                None
+            }
+            "q" => {
+                let (Some(method), Some(args)) = (message.q, message.a) else {
+                    return Some(Message::error(
+                        message.t,
+                        krpc::ERROR_PROTOCOL,
+                        "missing query",
+                    ));
+                };
+                let Some(id) = krpc::node_id(&args.id) else {
+                    return Some(Message::error(
+                        message.t,
+                        krpc::ERROR_PROTOCOL,
+                        "invalid id",
+                    ));
+                };
+                self.table().insert(Node { id, addr: from });
+                let reply = match self.answer(&method, args, from) {
+                    Ok(response) => Message::response(message.t, response),
+                    Err((code, error)) => Message::error(message.t, code, error),
+                };
+                Some(reply)
+            }
+            _ => Some(Message::error(
+                message.t,
+                krpc::ERROR_PROTOCOL,
+                "unknown message type",
+            )),
+        }
+    }
+
+    fn answer(
+        &self,
+        method: &str,
+        args: Args,
+        from: SocketAddrV4,
+    ) -> Result<Response, (i64, &'static str)> {
+        let mut response = Response {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        };
+        let target = |key: Option<ByteBuf>| {
+            key.as_deref()
+                .and_then(|id| krpc::node_id(id))
+                .ok_or((krpc::ERROR_PROTOCOL, "missing or invalid target"))
+        };
+        match method {
+            "ping" => {}
+            "find_node" => {
+                let target = target(args.target)?;
+                response.nodes = Some(krpc::encode_nodes(&self.table().closest(&target, K)));
+            }
+            "get_peers" => {
+                let info_hash = target(args.info_hash)?;
+                let mut secrets = self.secrets.lock().expect("lock is never poisoned");
+                secrets.rotate_if_due();
+                response.token = Some(ByteBuf::from(Secrets::token(&secrets.current, &from)));
+                drop(secrets);
+
+                let stored: Vec<_> = self
+                    .peers
+                    .lock()
+                    .expect("lock is never poisoned")
+                    .get(&info_hash, Instant::now())
+                    .into_iter()
+                    .map(|peer| ByteBuf::from(krpc::encode_peer(peer).to_vec()))
+                    .collect();
+                if stored.is_empty() {
+                    response.nodes = Some(krpc::encode_nodes(&self.table().closest(&info_hash, K)));
+                } else {
+                    response.values = Some(stored);
+                }
+            }
+            "announce_peer" => {
+                let info_hash = target(args.info_hash)?;
+                let token = args.token.ok_or((krpc::ERROR_PROTOCOL, "missing token"))?;
+                let mut secrets = self.secrets.lock().expect("lock is never poisoned");
+                secrets.rotate_if_due();
+                let valid = [secrets.current, secrets.previous]
+                    .iter()
+                    .any(|secret| Secrets::token(secret, &from) == token.as_slice());
+                drop(secrets);
+                if !valid {
+                    return Err((krpc::ERROR_PROTOCOL, "bad token"));
+                }
+                let port = match (args.implied_port, args.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) => port,
+                    _ => return Err((krpc::ERROR_PROTOCOL, "missing port")),
+                };
+                let stored = self.peers.lock().expect("lock is never poisoned").insert(
+                    info_hash,
+                    SocketAddrV4::new(*from.ip(), port),
+                    Instant::now(),
+                );
+                if !stored {
+                    return Err((krpc::ERROR_SERVER, "storing too many torrents"));
+                }
+            }
+            _ => return Err((krpc::ERROR_METHOD_UNKNOWN, "method unknown")),
+        }
+        Ok(response)
+    }
+}
+
+async fn receive(socket: Arc<UdpSocket>, node: Weak<Inner>) {
+    let mut buf = vec![0; 1 << 16];
+    // how many reads in a row have failed
+    let mut failures = 0;
+    loop {
+        let (n, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => {
+                failures = 0;
+                received
+            }
+            Err(e) => {
+                // on some platforms, an ICMP unreachable for an earlier send shows up here, which
+                // is a one-off. a socket that keeps failing would have us spin, though.
+                if failures == 0 {
+                    eprintln!("dht receive failed: {e}");
+                } else {
+                    let backoff = Duration::from_millis(10) * 2u32.pow(failures.min(10));
+                    tokio::time::sleep(backoff.min(MAX_RECEIVE_BACKOFF)).await;
+                }
+                failures += 1;
+                continue;
+            }
+        };
+        let SocketAddr::V4(from) = from else {
+            continue;
+        };
+        let Some(node) = node.upgrade() else {
+            break;
+        };
+        let reply = match serde_bencode::from_bytes::<Message>(&buf[..n]) {
+            Ok(message) => node.handle(message, from),
+            // we can't even find the transaction id, so we can't send an error back
+            Err(_) => None,
+        };
+        if let Some(reply) = reply {
+            if let Ok(reply) = serde_bencode::to_bytes(&reply) {
+                let _ = socket.send_to(&reply, from).await;
+            }
+        }
+    }
+}
+
+/// Peers for a torrent from the DHT, looked up again every few minutes.
+pub struct DhtSource {
+    dht: Dht,
+    port: Option<u16>,
+}
+
+impl DhtSource {
+    pub fn new(dht: Dht) -> Self {
+        Self { dht, port: None }
+    }
+
+    /// Also announce that we accept connections for the torrent on `port`.
+    pub fn announce(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+}
+
+impl PeerSource for DhtSource {
+    fn peers(
+        self: Box<Self>,
+        info_hash: [u8; 20],
+        progress: watch::Receiver<Progress>,
+    ) -> BoxStream<'static, DiscoveredPeer> {
+        let lookups = stream::unfold((*self, true), move |(source, first)| async move {
+            if first {
+                if source.dht.nodes() == 0 {
+                    if let Err(e) = source.dht.bootstrap().await {
+                        eprintln!("failed to bootstrap dht: {e:?}");
+                        return None;
+                    }
+                }
+            } else {
+                tokio::time::sleep(LOOKUP_INTERVAL).await;
+            }
+            let peers = match source.port {
+                Some(port) => source.dht.announce(info_hash, port).await,
+                None => source.dht.get_peers(info_hash).await,
+            };
+            source.dht.save().await;
+            Some((peers, (source, false)))
+        });
+        lookups
+            .flat_map(|peers| {
+                stream::iter(peers.into_iter().map(|addr| DiscoveredPeer {
+                    addr,
+                    origin: PeerOrigin::Dht,
+                }))
+            })
+            .take_until(discovery::finished(progress))
+            .boxed()
+    }
+}
+
+#[tokio::test]
+async fn dht_localhost_swarm() {
+    let config = |bootstrap: Vec<String>| DhtConfig {
+        bind: "127.0.0.1:0".parse().unwrap(),
+        bootstrap,
+        node_cache: None,
+        id: None,
+    };
+    let a = Dht::bind(config(Vec::new())).await.unwrap();
+    let a_addr = a.local_addr().unwrap().to_string();
+    let b = Dht::bind(config(vec![a_addr.clone()])).await.unwrap();
+    let c = Dht::bind(config(vec![a_addr])).await.unwrap();
+    b.bootstrap().await.unwrap();
+    c.bootstrap().await.unwrap();
+    assert!(c.nodes() >= 2, "c should have learned about b through a");
+
+    let info_hash = [0x42; 20];
+    assert!(c.announce(info_hash, 4242).await.is_empty());
+    let peers = b.get_peers(info_hash).await;
+    assert_eq!(
+        peers,
+        vec!["127.0.0.1:4242".parse::<SocketAddrV4>().unwrap()]
+    );
+
+    // a bogus token is refused
+    let args = Args {
+        info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+        port: Some(1),
+        token: Some(ByteBuf::from(b"nope".to_vec())),
+        ..Default::default()
+    };
+    let a_addr = match a.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    assert!(b.query(a_addr, "announce_peer", args).await.is_err());
+}
+
+#[tokio::test]
+async fn dht_node_cache() {
+    let cache = std::env::temp_dir().join(format!("dht-nodes-{}", rand::random::<u64>()));
+    let config = |bootstrap: Vec<String>| DhtConfig {
+        bind: "127.0.0.1:0".parse().unwrap(),
+        bootstrap,
+        node_cache: Some(cache.clone()),
+        id: None,
+    };
+    let a = Dht::bind(DhtConfig {
+        node_cache: None,
+        ..config(Vec::new())
+    })
+    .await
+    .unwrap();
+    let b = Dht::bind(config(vec![a.local_addr().unwrap().to_string()]))
+        .await
+        .unwrap();
+    b.bootstrap().await.unwrap();
+    drop(b);
+
+    let b = Dht::bind(config(Vec::new())).await.unwrap();
+    assert_eq!(b.nodes(), 1);
+    let _ = std::fs::remove_file(cache);
+}
+
+#[test]
+fn announced_peers_bounded() {
+    let now = Instant::now();
+    let mut announced = Announced::new(now);
+    let peer = |i: usize| SocketAddrV4::new([10, 0, (i >> 8) as u8, i as u8].into(), 6881);
+
+    // a full torrent makes room by dropping the peer that announced longest ago
+    for i in 0..=MAX_PEERS_PER_TORRENT {
+        let at = now + Duration::from_millis(i as u64);
+        assert!(announced.insert([1; 20], peer(i), at));
+    }
+    let later = now + Duration::from_secs(1);
+    let stored = announced.get(&[1; 20], later);
+    assert_eq!(stored.len(), MAX_PEERS_PER_TORRENT);
+    assert!(!stored.contains(&peer(0)));
+
+    // only so many torrents are stored
+    for i in 1..MAX_TORRENTS {
+        let mut info_hash = [2; 20];
+        info_hash[..8].copy_from_slice(&(i as u64).to_be_bytes());
+        assert!(announced.insert(info_hash, peer(0), later));
+    }
+    assert!(!announced.insert([3; 20], peer(0), later));
+
+    // until their peers expire
+    let expired = later + PEER_TTL;
+    assert!(announced.get(&[1; 20], expired).is_empty());
+    assert!(announced.insert([3; 20], peer(0), expired));
+    assert_eq!(announced.torrents.len(), 1);
+}
@@ -0,0 +1,192 @@
+//! KRPC, the simple RPC mechanism the DHT uses: bencoded dictionaries sent over UDP.
+
+use super::routing::{Node, NodeId};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+pub(crate) const ERROR_SERVER: i64 = 202;
+pub(crate) const ERROR_PROTOCOL: i64 = 203;
+pub(crate) const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Message {
+    /// Transaction id, echoed back in the response to a query.
+    #[serde(with = "serde_bytes")]
+    pub t: Vec<u8>,
+
+    /// Message type: `q` for query, `r` for response, `e` for error.
+    pub y: String,
+
+    /// Method name of a query.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+
+    /// Arguments of a query.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub a: Option<Args>,
+
+    /// Return values of a response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r: Option<Response>,
+
+    /// Error code and message of an error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<(i64, String)>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Args {
+    pub id: ByteBuf,
+
+    /// `find_node`: the node id being looked for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+
+    /// `get_peers` and `announce_peer`: the torrent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+
+    /// `announce_peer`: the port the announcing peer accepts connections on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+
+    /// `announce_peer`: the token from an earlier `get_peers` response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+
+    /// `announce_peer`: use the source port of the UDP packet instead of `port`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Response {
+    pub id: ByteBuf,
+
+    /// Compact node info of the nodes closest to the target.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+
+    /// Compact peer info of peers for the torrent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+
+    /// Token needed to later `announce_peer` to the responding node.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+}
+
+impl Message {
+    pub(crate) fn query(t: Vec<u8>, method: &str, args: Args) -> Self {
+        Self {
+            t,
+            y: String::from("q"),
+            q: Some(String::from(method)),
+            a: Some(args),
+            r: None,
+            e: None,
+        }
+    }
+
+    pub(crate) fn response(t: Vec<u8>, response: Response) -> Self {
+        Self {
+            t,
+            y: String::from("r"),
+            q: None,
+            a: None,
+            r: Some(response),
+            e: None,
+        }
+    }
+
+    pub(crate) fn error(t: Vec<u8>, code: i64, message: &str) -> Self {
+        Self {
+            t,
+            y: String::from("e"),
+            q: None,
+            a: None,
+            r: None,
+            e: Some((code, String::from(message))),
+        }
+    }
+}
+
+pub(crate) fn node_id(id: &[u8]) -> Option<NodeId> {
+    id.try_into().ok()
+}
+
+/// Encode nodes in the 26 bytes per node "compact node info" format.
+pub(crate) fn encode_nodes(nodes: &[Node]) -> ByteBuf {
+    let mut compact = Vec::with_capacity(26 * nodes.len());
+    for node in nodes {
+        compact.extend(node.id);
+        compact.extend(encode_peer(node.addr));
+    }
+    ByteBuf::from(compact)
+}
+
+pub(crate) fn decode_nodes(compact: &[u8]) -> Vec<Node> {
+    compact
+        .chunks_exact(26)
+        .filter_map(|slice_26| {
+            Some(Node {
+                id: node_id(&slice_26[..20])?,
+                addr: decode_peer(&slice_26[20..])?,
+            })
+        })
+        .collect()
+}
+
+/// Encode an address in the 6 byte "compact peer info" format.
+pub(crate) fn encode_peer(addr: SocketAddrV4) -> [u8; 6] {
+    let mut compact = [0; 6];
+    compact[..4].copy_from_slice(&addr.ip().octets());
+    compact[4..].copy_from_slice(&addr.port().to_be_bytes());
+    compact
+}
+
+pub(crate) fn decode_peer(compact: &[u8]) -> Option<SocketAddrV4> {
+    let &[a, b, c, d, p1, p2] = compact else {
+        return None;
+    };
+    Some(SocketAddrV4::new(
+        Ipv4Addr::new(a, b, c, d),
+        u16::from_be_bytes([p1, p2]),
+    ))
+}
+
+#[test]
+fn krpc_encoding() {
+    // example from BEP 5
+    let ping = Message::query(
+        b"aa".to_vec(),
+        "ping",
+        Args {
+            id: ByteBuf::from(b"abcdefghij0123456789".to_vec()),
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        serde_bencode::to_bytes(&ping).unwrap(),
+        b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+    );
+
+    let error: Message =
+        serde_bencode::from_bytes(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee").unwrap();
+    assert_eq!(
+        error.e,
+        Some((201, String::from("A Generic Error Ocurred")))
+    );
+
+    let response: Message = serde_bencode::from_bytes(
+        b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re",
+    )
+    .unwrap();
+    let values = response.r.unwrap().values.unwrap();
+    assert_eq!(values.len(), 2);
+    assert_eq!(
+        decode_peer(&values[0]),
+        Some(SocketAddrV4::new(Ipv4Addr::new(97, 120, 106, 101), 11893))
+    );
+}
@@ -0,0 +1,148 @@
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+/// Nodes per bucket.
+pub(crate) const K: usize = 8;
+
+/// After this many unanswered queries in a row a node is considered bad and may be replaced.
+const MAX_FAILURES: u8 = 2;
+
+/// Nodes we haven't heard from in this long are questionable, and may be replaced by new ones.
+const QUESTIONABLE: Duration = Duration::from_secs(15 * 60);
+
+pub type NodeId = [u8; 20];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+/// The XOR metric used to compare node ids and info hashes.
+pub(crate) fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+#[derive(Debug)]
+struct Entry {
+    node: Node,
+    last_seen: Instant,
+    failures: u8,
+}
+
+impl Entry {
+    fn replaceable(&self) -> bool {
+        self.failures >= MAX_FAILURES || self.last_seen.elapsed() > QUESTIONABLE
+    }
+}
+
+/// A Kademlia routing table with one bucket for every bit of distance from our own id.
+///
+/// Bucket `i` holds the nodes whose distance to us has `i` leading zero bits, so the buckets
+/// covering the space close to us are the ones that fill up last.
+#[derive(Debug)]
+pub(crate) struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub(crate) fn new(own: NodeId) -> Self {
+        Self {
+            own,
+            buckets: (0..160).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    fn bucket(&self, id: &NodeId) -> Option<usize> {
+        let d = distance(&self.own, id);
+        let zeros = d
+            .iter()
+            .position(|&b| b != 0)
+            .map(|byte_i| byte_i * 8 + d[byte_i].leading_zeros() as usize)?;
+        Some(zeros)
+    }
+
+    /// Record that we heard from `node`, adding it to the table if there is room.
+    pub(crate) fn insert(&mut self, node: Node) {
+        let Some(bucket_i) = self.bucket(&node.id) else {
+            // that's us!
+            return;
+        };
+        let bucket = &mut self.buckets[bucket_i];
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.node.id == node.id) {
+            entry.node.addr = node.addr;
+            entry.last_seen = Instant::now();
+            entry.failures = 0;
+            return;
+        }
+        let entry = Entry {
+            node,
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+        if bucket.len() < K {
+            bucket.push(entry);
+        } else if let Some(stale) = bucket.iter_mut().find(|entry| entry.replaceable()) {
+            *stale = entry;
+        }
+        // otherwise the bucket is full of good nodes, and we keep those
+    }
+
+    /// Record that the node at `addr` did not answer a query.
+    pub(crate) fn failed(&mut self, addr: SocketAddrV4) {
+        for entry in self.buckets.iter_mut().flatten() {
+            if entry.node.addr == addr {
+                entry.failures = entry.failures.saturating_add(1);
+            }
+        }
+    }
+
+    /// The `n` known nodes closest to `target`, closest first.
+    pub(crate) fn closest(&self, target: &NodeId, n: usize) -> Vec<Node> {
+        let mut nodes: Vec<_> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|entry| entry.failures < MAX_FAILURES)
+            .map(|entry| entry.node)
+            .collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    pub(crate) fn nodes(&self) -> impl Iterator<Item = Node> + '_ {
+        self.buckets.iter().flatten().map(|entry| entry.node)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+}
+
+#[test]
+fn routing_table_buckets() {
+    let own = [0; 20];
+    let mut table = RoutingTable::new(own);
+    let addr: SocketAddrV4 = "127.0.0.1:1".parse().unwrap();
+
+    // ourselves never goes in
+    table.insert(Node { id: own, addr });
+    assert_eq!(table.len(), 0);
+
+    // the far half of the id space is a single bucket that holds at most K nodes
+    for i in 0..2 * K as u8 {
+        let mut id = [0; 20];
+        id[0] = 0x80;
+        id[19] = i;
+        table.insert(Node { id, addr });
+    }
+    assert_eq!(table.len(), K);
+
+    let mut near = [0; 20];
+    near[19] = 1;
+    table.insert(Node { id: near, addr });
+    assert_eq!(table.closest(&own, 1)[0].id, near);
+    assert_eq!(table.closest(&own, 100).len(), K + 1);
+}
@@ -0,0 +1,314 @@
+use crate::torrent::Torrent;
+use crate::tracker::{AnnounceConfig, Announcer, Event, TrackerClient};
+use anyhow::Context;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Where we learned about a peer from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerOrigin {
+    /// An HTTP tracker announce.
+    Tracker,
+    /// A fixed list of peers given up front.
+    Static,
+    /// A Mainline DHT lookup.
+    Dht,
+    /// Peer exchange with a peer we're connected to.
+    Pex,
+    /// Local Service Discovery multicast on the local network.
+    Lsd,
+}
+
+/// A peer address along with where it came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DiscoveredPeer {
+    pub addr: SocketAddrV4,
+    pub origin: PeerOrigin,
+}
+
+/// How far along a download is, in bytes, as trackers want to hear it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub uploaded: usize,
+    pub downloaded: usize,
+    pub left: usize,
+}
+
+/// A mechanism for finding peers in the swarm of a torrent.
+///
+/// The downloader merges the streams of all its sources, so a source may keep yielding peers for
+/// as long as the download runs (and may yield the same peer more than once), or end early if it
+/// has nothing more to offer.
+///
+/// `progress` follows the download, and is closed once it's over. The stream should end soon
+/// after that; the downloader only waits a little while for it.
+pub trait PeerSource: Send {
+    fn peers(
+        self: Box<Self>,
+        info_hash: [u8; 20],
+        progress: watch::Receiver<Progress>,
+    ) -> BoxStream<'static, DiscoveredPeer>;
+}
+
+/// Resolves once the download that `progress` follows is over.
+pub(crate) async fn finished(mut progress: watch::Receiver<Progress>) {
+    while progress.changed().await.is_ok() {}
+}
+
+/// Peers handed out by the torrent's `announce` tracker, re-announcing every tracker interval.
+///
+/// The tracker also hears when the download completes, and when it's over.
+pub struct TrackerSource {
+    announce: String,
+    config: AnnounceConfig,
+    client: TrackerClient,
+}
+
+impl TrackerSource {
+    pub fn new(torrent: &Torrent) -> Self {
+        Self::with_config(torrent, AnnounceConfig::default())
+    }
+
+    pub fn with_config(torrent: &Torrent, config: AnnounceConfig) -> Self {
+        Self {
+            announce: torrent.announce.clone(),
+            config,
+            client: TrackerClient::default(),
+        }
+    }
+
+    /// Talk to the tracker with `client` rather than the shared default client.
+    pub fn with_client(mut self, client: TrackerClient) -> Self {
+        self.client = client;
+        self
+    }
+}
+
+/// How long to wait before retrying a tracker that failed to answer.
+const TRACKER_RETRY: Duration = Duration::from_secs(60);
+
+impl PeerSource for TrackerSource {
+    fn peers(
+        self: Box<Self>,
+        info_hash: [u8; 20],
+        progress: watch::Receiver<Progress>,
+    ) -> BoxStream<'static, DiscoveredPeer> {
+        let announcer =
+            Announcer::new(self.announce, info_hash, self.config).with_client(self.client);
+        // a download that starts out complete has nothing to report on that front
+        let completed = progress.borrow().left == 0;
+        let announces = stream::unfold(
+            (announcer, progress, None, completed),
+            move |(mut announcer, mut progress, wait, mut completed): (
+                Announcer,
+                watch::Receiver<Progress>,
+                Option<Duration>,
+                bool,
+            )| async move {
+                let event = match wait {
+                    Some(wait) => {
+                        let until = tokio::time::Instant::now() + wait;
+                        loop {
+                            tokio::select! {
+                                _ = tokio::time::sleep_until(until) => break None,
+                                changed = progress.changed() => {
+                                    if changed.is_err() {
+                                        break Some(Event::Stopped);
+                                    }
+                                    if !completed && progress.borrow().left == 0 {
+                                        completed = true;
+                                        break Some(Event::Completed);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    None => Some(Event::Started),
+                };
+                let Progress {
+                    uploaded,
+                    downloaded,
+                    left,
+                } = *progress.borrow();
+                let announced = announcer.announce(event, uploaded, downloaded, left).await;
+                if event == Some(Event::Stopped) {
+                    if let Err(e) = announced {
+                        eprintln!(
+                            "failed to tell {} we stopped: {e:?}",
+                            announcer.announce_url()
+                        );
+                    }
+                    return None;
+                }
+                match announced {
+                    Ok(response) => {
+                        let interval = Duration::from_secs(response.interval as u64);
+                        Some((
+                            response.peers.0,
+                            (announcer, progress, Some(interval), completed),
+                        ))
+                    }
+                    Err(e) if wait.is_none() => {
+                        // never got an answer; this tracker is of no use to us
+                        eprintln!(
+                            "failed to query tracker {}: {e:?}",
+                            announcer.announce_url()
+                        );
+                        None
+                    }
+                    Err(e) => {
+                        eprintln!(
+                            "failed to re-announce to {}: {e:?}",
+                            announcer.announce_url()
+                        );
+                        Some((
+                            Vec::new(),
+                            (announcer, progress, Some(TRACKER_RETRY), completed),
+                        ))
+                    }
+                }
+            },
+        );
+        announces
+            .flat_map(|peers| {
+                stream::iter(peers.into_iter().map(|addr| DiscoveredPeer {
+                    addr,
+                    origin: PeerOrigin::Tracker,
+                }))
+            })
+            .boxed()
+    }
+}
+
+/// A fixed list of peers.
+pub struct StaticPeers(pub Vec<SocketAddrV4>);
+
+impl StaticPeers {
+    /// Resolve `host:port` strings, keeping the IPv4 addresses each host resolves to.
+    pub async fn resolve(peers: &[impl AsRef<str>]) -> anyhow::Result<Self> {
+        let mut addrs = Vec::new();
+        for peer in peers {
+            let peer = peer.as_ref();
+            let resolved: Vec<_> = tokio::net::lookup_host(peer)
+                .await
+                .with_context(|| format!("resolve peer {peer}"))?
+                .filter_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(addr),
+                    SocketAddr::V6(_) => None,
+                })
+                .collect();
+            anyhow::ensure!(!resolved.is_empty(), "peer {peer} has no IPv4 address");
+            addrs.extend(resolved);
+        }
+        Ok(Self(addrs))
+    }
+}
+
+impl PeerSource for StaticPeers {
+    fn peers(
+        self: Box<Self>,
+        _info_hash: [u8; 20],
+        _progress: watch::Receiver<Progress>,
+    ) -> BoxStream<'static, DiscoveredPeer> {
+        stream::iter(self.0.into_iter().map(|addr| DiscoveredPeer {
+            addr,
+            origin: PeerOrigin::Static,
+        }))
+        .boxed()
+    }
+}
+
+/// Merge several sources into a single stream that yields every address only once.
+pub fn merge(
+    sources: Vec<Box<dyn PeerSource>>,
+    info_hash: [u8; 20],
+    progress: watch::Receiver<Progress>,
+) -> BoxStream<'static, DiscoveredPeer> {
+    let mut seen = std::collections::HashSet::new();
+    stream::select_all(
+        sources
+            .into_iter()
+            .map(|source| source.peers(info_hash, progress.clone())),
+    )
+    .filter(move |peer| std::future::ready(seen.insert(peer.addr)))
+    .boxed()
+}
+
+#[tokio::test]
+async fn merge_dedups() {
+    let a: SocketAddrV4 = "127.0.0.1:1".parse().unwrap();
+    let b: SocketAddrV4 = "127.0.0.1:2".parse().unwrap();
+    let c: SocketAddrV4 = "127.0.0.1:3".parse().unwrap();
+    let merged: Vec<_> = merge(
+        vec![
+            Box::new(StaticPeers(vec![a, b, a])),
+            Box::new(StaticPeers(vec![b, c])),
+        ],
+        [0; 20],
+        watch::channel(Progress::default()).1,
+    )
+    .map(|peer| peer.addr)
+    .collect()
+    .await;
+    assert_eq!(merged.len(), 3);
+    for addr in [a, b, c] {
+        assert!(merged.contains(&addr));
+    }
+}
+
+#[tokio::test]
+async fn static_peers_resolve() {
+    let StaticPeers(peers) = StaticPeers::resolve(&["127.0.0.1:6881", "localhost:51413"])
+        .await
+        .unwrap();
+    assert_eq!(peers[0], "127.0.0.1:6881".parse().unwrap());
+    assert!(peers[1..].contains(&"127.0.0.1:51413".parse().unwrap()));
+    assert!(StaticPeers::resolve(&["127.0.0.1"]).await.is_err());
+}
+
+#[tokio::test]
+async fn tracker_source_events() {
+    let (url, mut queries) =
+        crate::tracker::fake_tracker(b"d8:intervali900e5:peers6:\x7f\x00\x00\x01\x1a\xe1e").await;
+    let (mut t, _) = crate::download::test_torrent();
+    t.announce = url;
+    let (progress, progress_rx) = watch::channel(Progress {
+        left: t.length(),
+        ..Default::default()
+    });
+    let mut peers = Box::new(TrackerSource::new(&t)).peers(t.info_hash(), progress_rx);
+    let event = |query: String| {
+        query
+            .split('&')
+            .find_map(|param| param.strip_prefix("event="))
+            .map(String::from)
+    };
+
+    assert!(peers.next().await.is_some());
+    assert_eq!(
+        event(queries.recv().await.unwrap()).as_deref(),
+        Some("started")
+    );
+
+    // completing is announced right away, rather than at the next interval
+    progress.send_replace(Progress {
+        uploaded: 0,
+        downloaded: t.length(),
+        left: 0,
+    });
+    assert!(peers.next().await.is_some());
+    assert_eq!(
+        event(queries.recv().await.unwrap()).as_deref(),
+        Some("completed")
+    );
+
+    // and so is the end of the download, after which the source ends
+    drop(progress);
+    assert!(peers.next().await.is_none());
+    assert_eq!(
+        event(queries.recv().await.unwrap()).as_deref(),
+        Some("stopped")
+    );
+}
@@ -1,166 +1,505 @@
-use crate::peer::Peer;
-use crate::piece::Piece;
+use crate::bitfield::Bitfield;
+use crate::choker::{Choker, ChokerConfig};
+use crate::dht::Dht;
+use crate::discovery::{self, DiscoveredPeer, PeerOrigin, PeerSource, Progress};
+use crate::listener::Listener;
+use crate::mse::Encryption;
+use crate::peer::{self, Local, MessageFramer, Peer, Served, Timeouts};
+use crate::piece::{Availability, Blocks, Piece};
+use crate::stats::PeerStats;
 use crate::torrent::{File, Keys, Torrent};
-use crate::tracker::TrackerResponse;
+use crate::utp::UtpSocket;
 use crate::BLOCK_MAX;
-use anyhow::Context;
-use futures_util::stream::StreamExt;
+use futures_util::stream::{BoxStream, FuturesUnordered, StreamExt};
+use futures_util::FutureExt;
 use sha1::{Digest, Sha1};
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, HashSet, VecDeque};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
 
-pub(crate) async fn all(t: &Torrent) -> anyhow::Result<Downloaded> {
-    let info_hash = t.info_hash();
-    let peer_info = TrackerResponse::query(t, info_hash)
-        .await
-        .context("query tracker for peer info")?;
-
-    let mut peer_list = Vec::new();
-    let mut peers = futures_util::stream::iter(peer_info.peers.0.iter())
-        .map(|&peer_addr| async move {
-            let peer = Peer::new(peer_addr, info_hash).await;
-            (peer_addr, peer)
-        })
-        .buffer_unordered(5 /* user config */);
-    while let Some((peer_addr, peer)) = peers.next().await {
-        match peer {
-            Ok(peer) => {
-                peer_list.push(peer);
-                if peer_list.len() >= 5
-                /* TODO: user config */
-                {
-                    break;
-                }
-            }
-            Err(e) => {
-                eprintln!("failed to connect to peer {peer_addr:?}: {e:?}");
-            }
-        }
-    }
-    drop(peers);
-    let mut peers = peer_list;
-
-    let mut need_pieces = BinaryHeap::new();
-    let mut no_peers = Vec::new();
-    for piece_i in 0..t.info.pieces.0.len() {
-        let piece = Piece::new(piece_i, &t, &peers);
-        if piece.peers().is_empty() {
-            no_peers.push(piece);
-        } else {
-            need_pieces.push(piece);
+/// How many peers we try to connect to at the same time.
+const CONCURRENT_CONNECTS: usize = 5;
+
+/// How many connected peers we keep around at most.
+const MAX_PEERS: usize = 20;
+
+/// How long the peer sources get to wind down once the download is over, such as for trackers to
+/// hear that we're stopping.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Settings for downloading a torrent.
+#[derive(Clone)]
+pub struct DownloadConfig {
+    /// The peer id we go by in handshakes.
+    pub peer_id: [u8; 20],
+    /// Also take peers that connect to us through this listener.
+    pub listener: Option<Listener>,
+    /// How to pick the peers we upload to.
+    pub choker: ChokerConfig,
+    /// How many block requests to keep outstanding with each peer. Peers that ask for a smaller
+    /// queue (through `reqq` in their extended handshake) get fewer.
+    pub pipeline: usize,
+    /// When to give up on peers, and when to keep connections alive.
+    pub timeouts: Timeouts,
+    /// The longest message we exchange with peers.
+    pub max_frame: usize,
+    /// Whether we encrypt the connections we open to peers. Incoming connections are up to the
+    /// listener.
+    pub encryption: Encryption,
+    /// The socket to try reaching peers over uTP with, before falling back to TCP.
+    pub utp: Option<UtpSocket>,
+    /// Where to publish a snapshot of the peers we're connected to, every so often.
+    pub peer_stats: Option<watch::Sender<Vec<PeerStats>>>,
+    /// How long to keep uploading to peers once we have the whole torrent.
+    pub seed: Duration,
+    /// The DHT node to tell about the nodes that peers say they run.
+    pub dht: Option<Dht>,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            peer_id: peer::new_peer_id(),
+            listener: None,
+            choker: ChokerConfig::default(),
+            pipeline: 16,
+            timeouts: Timeouts::default(),
+            max_frame: MessageFramer::DEFAULT_MAX_FRAME,
+            encryption: Encryption::default(),
+            utp: None,
+            peer_stats: None,
+            seed: Duration::ZERO,
+            dht: None,
         }
     }
+}
+
+pub(crate) async fn all(
+    t: &Torrent,
+    sources: Vec<Box<dyn PeerSource>>,
+    config: DownloadConfig,
+) -> anyhow::Result<Downloaded> {
+    let npieces = t.info.pieces.0.len();
+    pieces(t, (0..npieces).collect(), sources, config).await
+}
 
-    // TODO
-    assert!(no_peers.is_empty());
+/// Download only piece `piece_i` of the torrent.
+pub(crate) async fn piece(
+    t: &Torrent,
+    piece_i: usize,
+    sources: Vec<Box<dyn PeerSource>>,
+    config: DownloadConfig,
+) -> anyhow::Result<Vec<u8>> {
+    let npieces = t.info.pieces.0.len();
+    anyhow::ensure!(piece_i < npieces, "torrent has only {npieces} pieces");
+    let downloaded = pieces(t, vec![piece_i], sources, config).await?;
+    let start = piece_i * t.info.plength;
+    let piece_size = t.info.plength.min(t.length() - start);
+    Ok(downloaded.bytes[start..][..piece_size].to_vec())
+}
+
+/// Download the pieces in `wanted`, leaving the bytes of the other pieces zeroed.
+async fn pieces(
+    t: &Torrent,
+    wanted: Vec<usize>,
+    sources: Vec<Box<dyn PeerSource>>,
+    config: DownloadConfig,
+) -> anyhow::Result<Downloaded> {
+    let info_hash = t.info_hash();
+    let npieces = t.info.pieces.0.len();
+    let mut have = Bitfield::new(npieces);
+    let (local, local_rx) = watch::channel(Local {
+        info_hash,
+        peer_id: config.peer_id,
+        npieces,
+        have: have.clone(),
+        port: config.listener.as_ref().map(Listener::port),
+        pipeline: config.pipeline,
+        timeouts: config.timeouts,
+        max_frame: config.max_frame,
+        encryption: config.encryption,
+        utp: config.utp,
+    });
+
+    // connect to peers in the background as the sources (and the peers we're connected to) find
+    // them, so that peers keep trickling in for the whole download.
+    let (connected, mut new_peers) = mpsc::channel(MAX_PEERS);
+    let _registered = config
+        .listener
+        .as_ref()
+        .map(|listener| listener.register(local_rx.clone(), connected.clone()));
+    let (pex, exchanged) = mpsc::unbounded_channel();
+    let (exhausted_tx, mut exhausted) = watch::channel(false);
+    let (progress, progress_rx) = watch::channel(Progress {
+        uploaded: 0,
+        downloaded: 0,
+        left: t.length(),
+    });
+    let discovered = discovery::merge(sources, info_hash, progress_rx);
+    let mut connector = AbortOnDrop(tokio::spawn(connect(
+        local_rx,
+        discovered,
+        exchanged,
+        connected,
+        exhausted_tx,
+    )));
+
+    let mut peers: Vec<Peer> = Vec::new();
+    let mut remaining = wanted;
+    let mut availability = Availability::new(npieces);
+    let mut choker = Choker::new(config.choker);
 
     // TODO: this is dumb because all the pieces for a given torrent may not fit in memory!
     // should probably write every piece to disk so that we can also resume downloads, and seed
     // later on.
     let mut all_pieces = vec![0; t.length()];
-    while let Some(piece) = need_pieces.pop() {
-        // the + (BLOCK_MAX - 1) rounds up
-        let piece_size = piece.length();
-        let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
-        let peers: Vec<_> = peers
-            .iter_mut()
-            .enumerate()
-            .filter_map(|(peer_i, peer)| piece.peers().contains(&peer_i).then_some(peer))
+    let mut downloaded = 0;
+    // what we uploaded to peers we're no longer connected to
+    let mut uploaded_before = 0;
+    let mut seed_until = None;
+    loop {
+        // trackers hear about this when they next announce, or right away once we complete
+        let uploaded = uploaded_before + peers.iter().map(Peer::uploaded).sum::<u64>();
+        progress.send_if_modified(|progress| {
+            let now = Progress {
+                uploaded: uploaded as usize,
+                downloaded,
+                left: t.length() - downloaded,
+            };
+            std::mem::replace(progress, now) != now
+        });
+        if remaining.is_empty() {
+            let until = *seed_until.get_or_insert_with(|| Instant::now() + config.seed);
+            if Instant::now() >= until {
+                break;
+            }
+        }
+        while peers.len() < MAX_PEERS {
+            let Ok(peer) = new_peers.try_recv() else {
+                break;
+            };
+            add_peer(
+                &mut peers,
+                &mut availability,
+                &mut uploaded_before,
+                &have,
+                peer,
+            );
+        }
+        let served = Served {
+            data: &all_pieces,
+            have: &have,
+            plength: t.info.plength,
+        };
+        // read what peers sent while we were busy, which includes the bitfields of new peers
+        let mut failed = Vec::new();
+        for (peer_i, peer) in peers.iter_mut().enumerate() {
+            while let Some(read) = peer.wait_for_have(&served).now_or_never() {
+                if let Err(e) = read {
+                    eprintln!("peer failed: {e:?}");
+                    failed.push(peer_i);
+                    break;
+                }
+            }
+        }
+        for peer_i in failed.into_iter().rev() {
+            let mut failed = peers.remove(peer_i);
+            forget(&mut availability, &mut uploaded_before, &mut failed);
+        }
+        // account for the pieces peers announced while we were busy
+        for peer in &mut peers {
+            if let (Some(dht), Some(node)) = (&config.dht, peer.take_dht_node()) {
+                // a node that answers goes into the routing table
+                let dht = dht.clone();
+                tokio::spawn(async move {
+                    let _ = dht.ping(node).await;
+                });
+            }
+            availability.add(peer.take_haves());
+            let interested = peer.bitfield().difference(&have).count() > 0;
+            if let Err(e) = peer.set_interested(interested).await {
+                eprintln!("failed to update interest in {:?}: {e:?}", peer.addr());
+            }
+        }
+        if Instant::now() >= choker.next_round() {
+            rechoke(
+                &mut choker,
+                peers.iter_mut().collect(),
+                remaining.is_empty(),
+            )
+            .await;
+        }
+        if let Some(peer_stats) = &config.peer_stats {
+            peer_stats.send_replace(peers.iter().map(Peer::stats).collect());
+        }
+
+        let mut need_pieces: BinaryHeap<_> = remaining
+            .iter()
+            .filter(|&&piece_i| availability.count(piece_i) > 0)
+            .map(|&piece_i| Piece::new(piece_i, t, &peers, &availability))
             .collect();
+        let Some(piece) = need_pieces.pop() else {
+            // none of the peers we have can give us any of the pieces we're missing (yet), so
+            // wait for either a new peer or for one of the peers to announce a new piece. once
+            // we're seeding, this is where we spend our time.
+            let no_peers = peers.is_empty();
+            let seeding = remaining.is_empty();
+            let announced = async {
+                if no_peers {
+                    return std::future::pending().await;
+                }
+                let waits = peers.iter_mut().enumerate().map(|(peer_i, peer)| {
+                    Box::pin(async move { (peer_i, peer.wait_for_have(&served).await) })
+                });
+                futures_util::future::select_all(waits).await.0
+            };
+            let woken = tokio::select! {
+                biased;
+                peer = new_peers.recv() => Woken::Connected(peer.map(Box::new)),
+                (peer_i, have) = announced => Woken::Announced(peer_i, have),
+                _ = tokio::time::sleep_until(choker.next_round()) => Woken::Rechoke,
+                _ = exhausted.wait_for(|&exhausted| exhausted), if no_peers && !seeding => {
+                    Woken::Connected(None)
+                }
+                _ = tokio::time::sleep_until(seed_until.unwrap_or_else(Instant::now)), if seeding => {
+                    Woken::Seeded
+                }
+            };
+            match woken {
+                Woken::Connected(Some(peer)) => {
+                    add_peer(
+                        &mut peers,
+                        &mut availability,
+                        &mut uploaded_before,
+                        &have,
+                        *peer,
+                    );
+                }
+                Woken::Connected(None) => {
+                    anyhow::bail!("no peers left to get pieces {remaining:?}")
+                }
+                Woken::Announced(_, Ok(())) => {
+                    // picked up at the top of the loop
+                }
+                Woken::Announced(peer_i, Err(e)) => {
+                    eprintln!("peer failed: {e:?}");
+                    let mut failed = peers.remove(peer_i);
+                    forget(&mut availability, &mut uploaded_before, &mut failed);
+                }
+                Woken::Rechoke | Woken::Seeded => {
+                    // done at the top of the loop
+                }
+            }
+            continue;
+        };
+        drop(need_pieces);
 
-        let (submit, tasks) = kanal::bounded_async(nblocks);
-        for block in 0..nblocks {
-            submit
-                .send(block)
-                .await
-                .expect("bound holds all these items");
-        }
-        let (finish, mut done) = tokio::sync::mpsc::channel(nblocks);
-        let mut participants = futures_util::stream::futures_unordered::FuturesUnordered::new();
-        for peer in peers {
-            participants.push(peer.participate(
-                piece.index(),
-                piece_size,
-                nblocks,
-                submit.clone(),
-                tasks.clone(),
-                finish.clone(),
-            ));
-        }
-        drop(submit);
-        drop(finish);
-        drop(tasks);
+        let piece_size = piece.length();
+        let nblocks = piece_size.div_ceil(BLOCK_MAX);
+        let blocks = Blocks::new(nblocks);
 
         eprintln!("start receive loop");
         let mut all_blocks = vec![0u8; piece_size];
         let mut bytes_received = 0;
+        let mut failed = Vec::new();
+        // peers that were too slow for this piece
+        let mut gave_up = HashSet::new();
+        // the peers that sent blocks of this piece, by peer id
+        let mut served_by = HashSet::new();
+        // the download is put on hold whenever it's time to rechoke, and resumed right after
         loop {
-            tokio::select! {
-                joined = participants.next(), if !participants.is_empty() => {
-                    // if a participant ends early, it's either slow or failed
-                    eprintln!("participant finished");
-                    match joined {
-                        None => {
-                            // there are no peers!
-                            // this must mean we are about to get None from done.recv(),
-                            // so we'll handle it there
-                        }
-                        Some(Ok(_)) => {
-                            // the peer gave up because it timed out
-                            // nothing to do, except maybe de-prioritize this peer for later
-                            // TODO
+            let (finish, mut done) = tokio::sync::mpsc::channel(nblocks);
+            let mut participants = FuturesUnordered::new();
+            let mut others = FuturesUnordered::new();
+            for (peer_i, peer) in peers.iter_mut().enumerate() {
+                if failed.contains(&peer_i) {
+                    continue;
+                }
+                if piece.peers().contains(&peer_i) && !gave_up.contains(&peer_i) {
+                    let participation = peer.participate(
+                        piece.index(),
+                        piece_size,
+                        nblocks,
+                        &blocks,
+                        finish.clone(),
+                        &served,
+                    );
+                    participants.push(async move { (peer_i, participation.await) });
+                } else {
+                    // the other peers still get their requests served (and pieces announced)
+                    // meanwhile
+                    let served = &served;
+                    others.push(async move {
+                        loop {
+                            if let Err(e) = peer.wait_for_have(served).await {
+                                return (peer_i, e);
+                            }
                         }
-                        Some(Err(_)) => {
-                            // the peer failed and should be removed
-                            // it already isn't participating in this piece any more, so this is
-                            // more of an indicator that we shouldn't try this peer again, and
-                            // should remove it from the global peer list
-                            // TODO
+                    });
+                }
+            }
+            drop(finish);
+
+            let rechoke_at = choker.next_round();
+            let mut paused = false;
+            loop {
+                tokio::select! {
+                    joined = participants.next(), if !participants.is_empty() => {
+                        // if a participant ends early, it's either slow or failed
+                        eprintln!("participant finished");
+                        match joined {
+                            None => {
+                                // there are no peers!
+                                // this must mean we are about to get None from done.recv(),
+                                // so we'll handle it there
+                            }
+                            Some((peer_i, Ok(_))) => {
+                                // the peer gave up because it timed out, so it sits out the rest
+                                // of this piece
+                                gave_up.insert(peer_i);
+                            }
+                            Some((peer_i, Err(e))) => {
+                                // the peer failed and should be removed
+                                // it already isn't participating in this piece any more, so this
+                                // is more of an indicator that we shouldn't try this peer again,
+                                // and should remove it from the global peer list
+                                eprintln!("peer failed: {e:?}");
+                                failed.push(peer_i);
+                            }
                         }
                     }
-                }
-                piece = done.recv() => {
-                    if let Some(piece) = piece {
-                        eprintln!("got piece");
-                        // keep track of the bytes in message
-                        let piece = crate::peer::Piece::ref_from_bytes(&piece.payload[..])
-                            .expect("always get all Piece response fields from peer");
-                        bytes_received += piece.block().len();
-                        all_blocks[piece.begin() as usize..][..piece.block().len()].copy_from_slice(piece.block());
-                        if bytes_received == piece_size {
-                            // have received every piece
-                            // this must mean that all participations have either exited or are
-                            // waiting for more work -- in either case, it is okay to drop all the
-                            // participant futures.
+                    Some((peer_i, e)) = others.next(), if !others.is_empty() => {
+                        eprintln!("peer failed: {e:?}");
+                        failed.push(peer_i);
+                    }
+                    block = done.recv() => {
+                        if let Some((peer_id, block)) = block {
+                            eprintln!("got piece");
+                            served_by.insert(peer_id);
+                            // keep track of the bytes in message
+                            bytes_received += block.data.len();
+                            all_blocks[block.begin as usize..][..block.data.len()].copy_from_slice(&block.data);
+                            if bytes_received == piece_size {
+                                // have received every piece
+                                // this must mean that all participations have either exited or
+                                // are waiting for more work -- in either case, it is okay to drop
+                                // all the participant futures.
+                                break;
+                            }
+                        } else {
+                            eprintln!("got pieces end");
+                            // there are no peers left, so we can't progress!
                             break;
                         }
-                    } else {
-                        eprintln!("got pieces end");
-                        // there are no peers left, so we can't progress!
+                    }
+                    _ = tokio::time::sleep_until(rechoke_at) => {
+                        // participations keep their requests in flight, and are picked up again
+                        // once we're done
+                        paused = true;
                         break;
                     }
                 }
             }
+            drop(participants);
+            drop(others);
+            // blocks that came in just as we paused would otherwise go down with the channel
+            while let Ok((peer_id, block)) = done.try_recv() {
+                served_by.insert(peer_id);
+                bytes_received += block.data.len();
+                all_blocks[block.begin as usize..][..block.data.len()].copy_from_slice(&block.data);
+            }
+            if !paused || bytes_received == piece_size {
+                break;
+            }
+            let alive: Vec<_> = peers
+                .iter_mut()
+                .enumerate()
+                .filter(|(peer_i, _)| !failed.contains(peer_i))
+                .map(|(_, peer)| peer)
+                .collect();
+            // a slow piece would otherwise leave the snapshot out of date until it's done
+            if let Some(peer_stats) = &config.peer_stats {
+                peer_stats.send_replace(alive.iter().map(|peer| peer.stats()).collect());
+            }
+            rechoke(&mut choker, alive, false).await;
+        }
+
+        // remove from the back so that the remaining indices stay valid
+        failed.sort_unstable();
+        for peer_i in failed.into_iter().rev() {
+            let mut failed = peers.remove(peer_i);
+            forget(&mut availability, &mut uploaded_before, &mut failed);
+        }
+
+        // pass on what peers told us about the swarm, and tell them about ours
+        // peers that connected to us are left out until they tell us where they listen
+        let addrs: HashSet<_> = peers.iter().filter_map(Peer::listen_addr).collect();
+        for peer in &mut peers {
+            for addr in peer.take_pex_found() {
+                let _ = pex.send(DiscoveredPeer {
+                    addr,
+                    origin: PeerOrigin::Pex,
+                });
+            }
+            if let Err(e) = peer.send_pex(&addrs).await {
+                eprintln!("failed to send peer exchange to {:?}: {e:?}", peer.addr());
+            }
         }
-        drop(participants);
 
         if bytes_received == piece_size {
             // great, we got all the bytes
         } else {
-            // we'll need to connect to more peers, and make sure that those additional peers also
-            // have this piece, and then download the pieces we _didn't_ get from them.
-            // probably also stick this back onto the pieces_heap.
-            anyhow::bail!("no peers left to get piece {}", piece.index());
+            // the piece stays in `remaining`, so we'll try again once we have other peers that
+            // have it. the blocks we did get are thrown away, which is wasteful but simple.
+            eprintln!("no peers left to get piece {}", piece.index());
+            continue;
         }
 
         let mut hasher = Sha1::new();
         hasher.update(&all_blocks);
-        let hash: [u8; 20] = hasher
-            .finalize()
-            .try_into()
-            .expect("GenericArray<_, 20> == [_; 20]");
-        assert_eq!(hash, piece.hash());
+        let hash: [u8; 20] = hasher.finalize().into();
+        if hash != piece.hash() {
+            // the piece stays in `remaining`, and we stay away from whoever sent us bad data
+            eprintln!("piece {} failed its hash check", piece.index());
+            for peer_i in (0..peers.len()).rev() {
+                if served_by.contains(&peers[peer_i].peer_id()) {
+                    let mut corrupt = peers.remove(peer_i);
+                    forget(&mut availability, &mut uploaded_before, &mut corrupt);
+                }
+            }
+            continue;
+        }
 
         all_pieces[piece.index() * t.info.plength..][..piece_size].copy_from_slice(&all_blocks);
+        downloaded += piece_size;
+        remaining.retain(|&piece_i| piece_i != piece.index());
+        have.set_piece(piece.index());
+        local.send_modify(|local| local.have.clone_from(&have));
+        for peer in &mut peers {
+            if let Err(e) = peer.send_have(piece.index()).await {
+                // it'll fail again the next time we read from it, and be dropped then
+                eprintln!("failed to send have to {:?}: {e:?}", peer.addr());
+            }
+        }
+    }
+
+    let uploaded: u64 = peers.iter().map(Peer::uploaded).sum();
+    if uploaded > 0 {
+        eprintln!("uploaded {uploaded} bytes to the peers we're still connected to");
+    }
+
+    // the sources see the download is over once the progress is closed, and the connector stops
+    // connecting once peer exchange is
+    progress.send_modify(|progress| progress.uploaded = (uploaded_before + uploaded) as usize);
+    drop(progress);
+    drop(pex);
+    drop(new_peers);
+    if tokio::time::timeout(STOP_TIMEOUT, &mut connector.0)
+        .await
+        .is_err()
+    {
+        eprintln!("peer sources didn't wind down in time");
     }
 
     Ok(Downloaded {
@@ -175,6 +514,165 @@ pub(crate) async fn all(t: &Torrent) -> anyhow::Result<Downloaded> {
     })
 }
 
+/// What woke the downloader up while it was waiting for pieces to become available.
+enum Woken {
+    /// A new peer connected, or `None` if no more peers will.
+    Connected(Option<Box<Peer>>),
+    /// The peer at the given index announced a new piece, or failed.
+    Announced(usize, anyhow::Result<()>),
+    /// It's time to reconsider which peers we upload to.
+    Rechoke,
+    /// We've been seeding for as long as we were asked to.
+    Seeded,
+}
+
+/// Run a round of the choker, and choke or unchoke the peers accordingly.
+async fn rechoke(choker: &mut Choker, mut peers: Vec<&mut Peer>, seeding: bool) {
+    let snub_timeout = choker.config().snub_timeout;
+    let candidates: Vec<_> = peers
+        .iter()
+        .map(|peer| peer.candidate(snub_timeout))
+        .collect();
+    let unchoked = choker.round(&candidates, seeding);
+    for peer in &mut peers {
+        if let Err(e) = peer.set_choking(!unchoked.contains(&peer.addr())).await {
+            // it'll fail again the next time we read from it, and be dropped then
+            eprintln!("failed to choke or unchoke {:?}: {e:?}", peer.addr());
+        }
+    }
+}
+
+/// Start using a newly connected peer, unless we're connected to it already, or have no room
+/// for it.
+fn add_peer(
+    peers: &mut Vec<Peer>,
+    availability: &mut Availability,
+    uploaded_before: &mut u64,
+    have: &Bitfield,
+    mut peer: Peer,
+) {
+    // the same peer may connect to us while we connect to it, or be known by several addresses
+    if peers.iter().any(|other| other.peer_id() == peer.peer_id()) {
+        eprintln!(
+            "dropping duplicate connection to peer {} at {:?}",
+            hex::encode(peer.peer_id()),
+            peer.addr()
+        );
+        return;
+    }
+    if peers.len() >= MAX_PEERS {
+        // make room by dropping a peer that is of no use to us: one that has nothing we need,
+        // and doesn't want anything from us either
+        let useless = peers.iter().position(|other| {
+            !other.interested() && other.bitfield().difference(have).count() == 0
+        });
+        let Some(useless) = useless else {
+            eprintln!("no room for peer {:?}", peer.addr());
+            return;
+        };
+        let mut dropped = peers.remove(useless);
+        forget(availability, uploaded_before, &mut dropped);
+    }
+    peer.take_haves();
+    availability.add(peer.pieces());
+    peers.push(peer);
+}
+
+/// Stop counting the pieces of a peer we're disconnecting from, and add what we uploaded to it to
+/// `uploaded_before`.
+fn forget(availability: &mut Availability, uploaded_before: &mut u64, peer: &mut Peer) {
+    *uploaded_before += peer.uploaded();
+    // the pieces it announced since we last looked were never counted
+    let uncounted = peer.take_haves();
+    availability.remove(peer.pieces().filter(|piece_i| !uncounted.contains(piece_i)));
+}
+
+/// Connect to every peer that is discovered, either by the peer sources or through peer exchange,
+/// and hand the connected peers to the downloader.
+///
+/// `exhausted` is set whenever the sources have run dry and there are no connections in progress,
+/// meaning that only peer exchange can still give us more peers.
+///
+/// Once the download is over, which peer exchange closing tells, this only waits for the sources
+/// to end.
+async fn connect(
+    local: watch::Receiver<Local>,
+    mut discovered: BoxStream<'static, DiscoveredPeer>,
+    mut exchanged: mpsc::UnboundedReceiver<DiscoveredPeer>,
+    connected: mpsc::Sender<Peer>,
+    exhausted: watch::Sender<bool>,
+) {
+    let mut sources_done = false;
+    let mut over = false;
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::new();
+    let mut connects = FuturesUnordered::new();
+    loop {
+        while connects.len() < CONCURRENT_CONNECTS {
+            let Some(found): Option<DiscoveredPeer> = queue.pop_front() else {
+                break;
+            };
+            let local = local.borrow().clone();
+            connects.push(async move { (found, Peer::new(found.addr, &local).await) });
+        }
+        exhausted.send_replace(sources_done && queue.is_empty() && connects.is_empty());
+
+        tokio::select! {
+            found = discovered.next(), if over || !sources_done && queue.len() < CONCURRENT_CONNECTS => {
+                match found {
+                    Some(_) if over => {}
+                    Some(found) => {
+                        if seen.insert(found.addr) {
+                            queue.push_back(found);
+                        }
+                    }
+                    None if over => break,
+                    None => sources_done = true,
+                }
+            }
+            found = exchanged.recv(), if !over => {
+                let Some(found) = found else {
+                    // the download is over
+                    if sources_done {
+                        break;
+                    }
+                    over = true;
+                    queue.clear();
+                    connects.clear();
+                    continue;
+                };
+                if seen.insert(found.addr) {
+                    queue.push_back(found);
+                }
+            }
+            Some((found, peer)) = connects.next(), if !connects.is_empty() => {
+                match peer {
+                    Ok(peer) => {
+                        // if the download is over, the peer is hung up on, and peer exchange
+                        // closing tells us the rest
+                        let _ = connected.send(peer).await;
+                    }
+                    Err(e) => {
+                        eprintln!(
+                            "failed to connect to peer {:?} (from {:?}): {e:?}",
+                            found.addr, found.origin
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Aborts the wrapped task when dropped, so background work doesn't outlive the download.
+pub(crate) struct AbortOnDrop<T>(pub(crate) tokio::task::JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
 pub struct Downloaded {
     bytes: Vec<u8>, // TODO: maybe Bytes?
     files: Vec<File>,
@@ -228,4 +726,226 @@ impl<'d> DownloadedFile<'d> {
     pub fn bytes(&self) -> &'d [u8] {
         self.bytes
     }
-}
\ No newline at end of file
+}
+
+/// A torrent of three pieces, and its contents.
+#[cfg(test)]
+pub(crate) fn test_torrent() -> (Torrent, Vec<u8>) {
+    use crate::hashes::Hashes;
+    use crate::torrent::Info;
+
+    let plength = 3 * BLOCK_MAX;
+    let data: Vec<u8> = (0..2 * plength + 100).map(|i| (i % 251) as u8).collect();
+    let t = Torrent {
+        announce: String::new(),
+        info: Info {
+            name: String::from("test"),
+            plength,
+            pieces: Hashes(
+                data.chunks(plength)
+                    .map(|piece| Sha1::digest(piece).into())
+                    .collect(),
+            ),
+            keys: Keys::SingleFile { length: data.len() },
+        },
+    };
+    (t, data)
+}
+
+/// What we tell peers about ourselves in tests: that we have `have` of torrent `info_hash`, and
+/// that we ask for one block at a time, over TCP, and don't take connections.
+#[cfg(test)]
+pub(crate) fn test_local(info_hash: [u8; 20], have: Bitfield, encryption: Encryption) -> Local {
+    Local {
+        info_hash,
+        peer_id: peer::new_peer_id(),
+        npieces: have.npieces(),
+        have,
+        port: None,
+        pipeline: 1,
+        timeouts: Timeouts::default(),
+        max_frame: MessageFramer::DEFAULT_MAX_FRAME,
+        encryption,
+        utp: None,
+    }
+}
+
+/// The address of a socket that was bound to an IPv4 address.
+#[cfg(test)]
+pub(crate) fn ipv4(addr: std::net::SocketAddr) -> std::net::SocketAddrV4 {
+    let std::net::SocketAddr::V4(addr) = addr else {
+        unreachable!("bound to IPv4");
+    };
+    addr
+}
+
+/// A fake peer that answers plaintext handshakes for any torrent, without extensions, and then
+/// hands each connection to `then` in turn. Connections that don't start with a handshake are
+/// hung up on.
+#[cfg(test)]
+pub(crate) async fn fake_remote<F, Fut>(then: F) -> std::net::SocketAddrV4
+where
+    F: Fn(tokio::net::TcpStream) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    use crate::peer::Handshake;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = ipv4(listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let Ok(mut handshake) = Handshake::read_from(&mut stream).await else {
+                continue;
+            };
+            handshake.reserved = [0; 8];
+            handshake.peer_id = [1; 20];
+            handshake.write_to(&mut stream).await.unwrap();
+            then(stream).await;
+        }
+    });
+    addr
+}
+
+/// A peer that has all of `t`, and uploads it to the first peer that connects.
+#[cfg(test)]
+async fn seeder(t: &Torrent, data: Vec<u8>) -> (std::net::SocketAddrV4, AbortOnDrop<()>) {
+    let npieces = t.info.pieces.0.len();
+    let plength = t.info.plength;
+    let listener = Listener::bind(
+        "127.0.0.1:0".parse().unwrap(),
+        Encryption::Disabled,
+        Timeouts::default().handshake,
+    )
+    .await
+    .unwrap();
+    let addr = ipv4(listener.local_addr());
+    let (seeder_tx, seeder_rx) = watch::channel(test_local(
+        t.info_hash(),
+        Bitfield::all(npieces),
+        Encryption::Disabled,
+    ));
+    let seeding = tokio::spawn(async move {
+        let (incoming_tx, mut incoming) = mpsc::channel(1);
+        let _registered = listener.register(seeder_rx, incoming_tx);
+        let mut seed = incoming.recv().await.unwrap();
+        drop(seeder_tx);
+        seed.set_choking(false).await.unwrap();
+        let served = Served {
+            data: &data,
+            have: &Bitfield::all(npieces),
+            plength,
+        };
+        while seed.wait_for_have(&served).await.is_ok() {}
+    });
+    (addr, AbortOnDrop(seeding))
+}
+
+#[tokio::test]
+async fn download_from_seeder() {
+    use crate::discovery::StaticPeers;
+
+    let (t, data) = test_torrent();
+    let (addr, _seeding) = seeder(&t, data.clone()).await;
+    let config = DownloadConfig {
+        // rechoking this often puts the piece in progress on hold time and again
+        choker: ChokerConfig {
+            interval: Duration::from_millis(2),
+            ..Default::default()
+        },
+        encryption: Encryption::Disabled,
+        ..Default::default()
+    };
+    let sources: Vec<Box<dyn PeerSource>> = vec![Box::new(StaticPeers(vec![addr]))];
+    let downloaded = tokio::time::timeout(Duration::from_secs(10), all(&t, sources, config))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(downloaded.bytes == data);
+}
+
+#[tokio::test]
+async fn download_and_seed() {
+    use crate::discovery::StaticPeers;
+
+    let (t, data) = test_torrent();
+    let (addr, _seeding) = seeder(&t, data.clone()).await;
+
+    // the first leecher passes the torrent on to the second, while and after downloading it
+    let listener = Listener::bind(
+        "127.0.0.1:0".parse().unwrap(),
+        Encryption::Disabled,
+        Timeouts::default().handshake,
+    )
+    .await
+    .unwrap();
+    let relay = ipv4(listener.local_addr());
+    let first = DownloadConfig {
+        listener: Some(listener),
+        choker: ChokerConfig {
+            interval: Duration::from_millis(50),
+            ..Default::default()
+        },
+        encryption: Encryption::Disabled,
+        seed: Duration::from_secs(2),
+        ..Default::default()
+    };
+    let second = DownloadConfig {
+        encryption: Encryption::Disabled,
+        ..Default::default()
+    };
+    let started = Instant::now();
+    let (first, second) = tokio::time::timeout(
+        Duration::from_secs(10),
+        futures_util::future::join(
+            all(&t, vec![Box::new(StaticPeers(vec![addr]))], first),
+            all(&t, vec![Box::new(StaticPeers(vec![relay]))], second),
+        ),
+    )
+    .await
+    .unwrap();
+    assert!(first.unwrap().bytes == data);
+    assert!(second.unwrap().bytes == data);
+    assert!(started.elapsed() >= Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn download_one_piece() {
+    use crate::discovery::StaticPeers;
+
+    let (t, data) = test_torrent();
+    let (addr, _seeding) = seeder(&t, data.clone()).await;
+    // nothing listens here any more, so the seeder has to be tried next
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dead = ipv4(listener.local_addr().unwrap());
+    drop(listener);
+    let config = DownloadConfig {
+        encryption: Encryption::Disabled,
+        ..Default::default()
+    };
+    let sources: Vec<Box<dyn PeerSource>> = vec![Box::new(StaticPeers(vec![dead, addr]))];
+    let piece = tokio::time::timeout(Duration::from_secs(10), piece(&t, 2, sources, config))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(piece == data[2 * t.info.plength..]);
+}
+
+#[tokio::test]
+async fn corrupt_peer() {
+    use crate::discovery::StaticPeers;
+
+    let (t, mut data) = test_torrent();
+    data[t.info.plength + 1] ^= 1;
+    let (addr, _seeding) = seeder(&t, data).await;
+    let config = DownloadConfig {
+        encryption: Encryption::Disabled,
+        ..Default::default()
+    };
+    let sources: Vec<Box<dyn PeerSource>> = vec![Box::new(StaticPeers(vec![addr]))];
+    // the peer is dropped for its bad piece, and then there's no one left to get it from
+    let downloaded = tokio::time::timeout(Duration::from_secs(10), all(&t, sources, config))
+        .await
+        .unwrap();
+    assert!(downloaded.is_err());
+}
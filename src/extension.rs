@@ -0,0 +1,258 @@
+//! The extension protocol (BEP 10), which lets peers negotiate protocol extensions by name.
+
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Bit in the handshake's reserved bytes that signals support for the extension protocol.
+pub(crate) const RESERVED_BYTE: usize = 5;
+pub(crate) const RESERVED_BIT: u8 = 0x10;
+
+/// Extended message id of the extended handshake itself.
+pub(crate) const HANDSHAKE_ID: u8 = 0;
+
+/// Whether the reserved bytes of a handshake signal support for the extension protocol.
+pub fn supported(reserved: &[u8; 8]) -> bool {
+    reserved[RESERVED_BYTE] & RESERVED_BIT != 0
+}
+
+/// The dictionary sent as the first extended message.
+///
+/// Every key is optional, and peers are free to send keys we don't know about.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// Maps the names of the extensions the sender supports to the extended message ids it wants
+    /// to receive them with. An id of 0 means the extension has been disabled.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+
+    /// Client name and version. Meant to be UTF-8, though not every client sticks to that.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub v: Option<Vec<u8>>,
+
+    /// The port the sender accepts incoming connections on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+
+    /// How many outstanding requests the sender is willing to queue up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+
+    /// The receiver's IP address as seen by the sender, in compact form (4 or 16 bytes).
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub yourip: Option<Vec<u8>>,
+
+    /// Size of the info dictionary, for peers that support fetching it (BEP 9).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtendedHandshake {
+    /// The id the sender wants messages of extension `name` sent with, if it supports it.
+    pub fn id(&self, name: &str) -> Option<u8> {
+        self.m.get(name).copied().filter(|&id| id != 0)
+    }
+
+    /// The sender's client name and version, for showing, if it told us.
+    pub fn client(&self) -> Option<String> {
+        self.v
+            .as_deref()
+            .map(|v| String::from_utf8_lossy(v).into_owned())
+    }
+
+    pub fn set_yourip(&mut self, ip: IpAddr) {
+        self.yourip = Some(match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        });
+    }
+
+    /// Our IP address as the sender sees it, if it told us.
+    pub fn yourip(&self) -> Option<IpAddr> {
+        let yourip = self.yourip.as_deref()?;
+        if let Ok(ip) = <[u8; 4]>::try_from(yourip) {
+            Some(IpAddr::V4(Ipv4Addr::from(ip)))
+        } else if let Ok(ip) = <[u8; 16]>::try_from(yourip) {
+            Some(IpAddr::V6(Ipv6Addr::from(ip)))
+        } else {
+            None
+        }
+    }
+}
+
+/// A protocol extension that runs on top of the extension protocol.
+pub trait Extension: Any + Send {
+    /// The name the extension is known by in the `m` dictionary, such as `ut_pex`.
+    fn name(&self) -> &'static str;
+
+    /// Called whenever the peer sends an extended handshake. The peer may send more than one to
+    /// update what it told us before.
+    fn on_handshake(&mut self, handshake: &ExtendedHandshake) {
+        let _ = handshake;
+    }
+
+    /// Called with the payload (after the extended message id) of every message the peer sends
+    /// for this extension.
+    ///
+    /// Extensions are optional, so a message that can't be made sense of should be ignored
+    /// rather than bring down the connection.
+    fn on_message(&mut self, payload: &[u8]);
+}
+
+/// The extensions enabled on a single connection, along with what the peer told us about the
+/// ones it supports.
+pub struct Extensions {
+    /// Our handlers by extension name, along with the extended message id we receive them with.
+    handlers: BTreeMap<&'static str, (u8, Box<dyn Extension>)>,
+    remote: Option<ExtendedHandshake>,
+}
+
+impl Default for Extensions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self {
+            handlers: BTreeMap::new(),
+            remote: None,
+        }
+    }
+
+    /// Enable `extension` on this connection, replacing any earlier handler with the same name.
+    pub fn register(&mut self, extension: impl Extension) {
+        let name = extension.name();
+        let id = match self.handlers.get(name) {
+            Some(&(id, _)) => id,
+            // ids are ours to pick, as long as they're unique and not the handshake's
+            None => self.handlers.len() as u8 + 1,
+        };
+        self.handlers.insert(name, (id, Box::new(extension)));
+    }
+
+    /// The handler registered for extension `T`, if any.
+    pub fn get_mut<T: Extension>(&mut self) -> Option<&mut T> {
+        self.handlers
+            .values_mut()
+            .find_map(|(_, handler)| (handler.as_mut() as &mut dyn Any).downcast_mut())
+    }
+
+    /// Our extended handshake, with `m` filled in from the registered handlers.
+    pub fn handshake(&self) -> ExtendedHandshake {
+        ExtendedHandshake {
+            m: self
+                .handlers
+                .iter()
+                .map(|(&name, &(id, _))| (String::from(name), id))
+                .collect(),
+            v: Some(
+                format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")).into_bytes(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    /// The extended handshake the peer sent, if it sent one yet.
+    pub fn remote(&self) -> Option<&ExtendedHandshake> {
+        self.remote.as_ref()
+    }
+
+    /// The id to send messages of extension `name` with, if both sides support it.
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        if !self.handlers.contains_key(name) {
+            return None;
+        }
+        self.remote.as_ref()?.id(name)
+    }
+
+    /// Route an extended message, sent with extended message id `id`, to the extension it
+    /// belongs to. Messages for extensions we don't have are dropped.
+    pub fn on_message(&mut self, id: u8, payload: &[u8]) {
+        if id == HANDSHAKE_ID {
+            let Ok(handshake) = serde_bencode::from_bytes::<ExtendedHandshake>(payload) else {
+                return;
+            };
+            for (_, handler) in self.handlers.values_mut() {
+                handler.on_handshake(&handshake);
+            }
+            self.remote = Some(handshake);
+            return;
+        }
+        if let Some((_, handler)) = self.handlers.values_mut().find(|(our_id, _)| *our_id == id) {
+            handler.on_message(payload);
+        }
+    }
+}
+
+#[test]
+fn extended_handshake_fields() {
+    let mut handshake = ExtendedHandshake::default();
+    handshake.m.insert(String::from("ut_pex"), 1);
+    handshake.p = Some(6881);
+    handshake.reqq = Some(250);
+    handshake.set_yourip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+    let encoded = serde_bencode::to_bytes(&handshake).unwrap();
+    assert_eq!(
+        encoded,
+        b"d1:md6:ut_pexi1ee1:pi6881e4:reqqi250e6:yourip4:\x0a\x00\x00\x01e"
+    );
+    assert_eq!(
+        serde_bencode::from_bytes::<ExtendedHandshake>(&encoded).unwrap(),
+        handshake
+    );
+    assert_eq!(
+        handshake.yourip(),
+        Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)))
+    );
+
+    // unknown keys are fine, and a disabled extension has id 0
+    let decoded: ExtendedHandshake = serde_bencode::from_bytes(
+        b"d1:md6:ut_pexi0e11:ut_metadatai3ee13:metadata_sizei31235e1:v6:xx 1.01:ei1ee",
+    )
+    .unwrap();
+    assert_eq!(decoded.id("ut_pex"), None);
+    assert_eq!(decoded.id("ut_metadata"), Some(3));
+    assert_eq!(decoded.metadata_size, Some(31235));
+    assert_eq!(decoded.client().as_deref(), Some("xx 1.0"));
+
+    // nor does a client name that isn't UTF-8 stop the rest from being read
+    let decoded: ExtendedHandshake =
+        serde_bencode::from_bytes(b"d1:md6:ut_pexi1ee1:v4:xx \xffe").unwrap();
+    assert_eq!(decoded.id("ut_pex"), Some(1));
+    assert_eq!(decoded.client().as_deref(), Some("xx \u{fffd}"));
+}
+
+#[test]
+fn extension_routing() {
+    struct Echo(Vec<Vec<u8>>, Option<u8>);
+    impl Extension for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+        fn on_handshake(&mut self, handshake: &ExtendedHandshake) {
+            self.1 = handshake.id("echo");
+        }
+        fn on_message(&mut self, payload: &[u8]) {
+            self.0.push(payload.to_vec());
+        }
+    }
+
+    let mut extensions = Extensions::new();
+    extensions.register(Echo(Vec::new(), None));
+    let id = extensions.handshake().id("echo").unwrap();
+    assert_eq!(extensions.remote_id("echo"), None);
+
+    extensions.on_message(HANDSHAKE_ID, b"d1:md4:echoi7e5:otheri8eee");
+    assert_eq!(extensions.remote_id("echo"), Some(7));
+    // the peer supports it, but we don't
+    assert_eq!(extensions.remote_id("other"), None);
+
+    extensions.on_message(id, &[1, 2, 3]);
+    extensions.on_message(id + 1, &[4]);
+    let echo = extensions.get_mut::<Echo>().unwrap();
+    assert_eq!(echo.0, vec![vec![1, 2, 3]]);
+    assert_eq!(echo.1, Some(7));
+}
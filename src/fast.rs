@@ -0,0 +1,11 @@
+//! The Fast Extension (BEP 6), which lets peers skip sending a bitfield, reject requests
+//! explicitly, and hand out pieces that may be requested while choked.
+
+/// Bit in the handshake's reserved bytes that signals support for the Fast Extension.
+pub(crate) const RESERVED_BYTE: usize = 7;
+pub(crate) const RESERVED_BIT: u8 = 0x04;
+
+/// Whether the reserved bytes of a handshake signal support for the Fast Extension.
+pub fn supported(reserved: &[u8; 8]) -> bool {
+    reserved[RESERVED_BYTE] & RESERVED_BIT != 0
+}
@@ -31,7 +31,7 @@ impl<'de> Visitor<'de> for HashesVisitor {
     where
         E: de::Error,
     {
-        if v.len() % 20 != 0 {
+        if !v.len().is_multiple_of(20) {
             return Err(E::custom(format!("length is {}", v.len())));
         }
         // TODO: use array_chunks when stable
@@ -6,4 +6,16 @@ pub mod torrent;
 pub mod tracker;
 pub mod peer;
 pub mod piece;
-pub mod download;
\ No newline at end of file
+pub mod download;
+pub mod choker;
+pub mod discovery;
+pub mod dht;
+pub mod extension;
+pub mod fast;
+pub mod pex;
+pub mod lsd;
+pub mod listener;
+pub mod mse;
+pub mod utp;
+pub mod stats;
+pub mod bitfield;
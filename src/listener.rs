@@ -0,0 +1,333 @@
+//! Accepting connections from peers that found us, rather than the other way around.
+
+use crate::download::AbortOnDrop;
+use crate::mse::{self, Encryption};
+use crate::peer::{Handshake, Local, Peer, Transport};
+use crate::utp::UtpSocket;
+use anyhow::Context;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+
+/// The torrents a listener accepts peers for, by info hash.
+type Torrents = Arc<Mutex<HashMap<[u8; 20], Registration>>>;
+
+struct Registration {
+    local: watch::Receiver<Local>,
+    peers: mpsc::Sender<Peer>,
+}
+
+/// A TCP listener, and optionally a uTP one, that hands incoming peers to the download of the
+/// torrent they ask for.
+///
+/// Cloning is cheap, and clones share the same socket, so one listener can serve many torrents.
+#[derive(Clone)]
+pub struct Listener {
+    local_addr: SocketAddr,
+    torrents: Torrents,
+    encryption: Encryption,
+    handshake: Duration,
+    _accept: Arc<AbortOnDrop<()>>,
+    _accept_utp: Option<Arc<AbortOnDrop<()>>>,
+}
+
+impl Listener {
+    /// Listen on `addr`, taking encrypted connections, plaintext ones, or both, as `encryption`
+    /// says, and hanging up on peers that don't finish their handshake within `handshake`.
+    pub async fn bind(
+        addr: SocketAddr,
+        encryption: Encryption,
+        handshake: Duration,
+    ) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("listen on {addr}"))?;
+        let local_addr = listener.local_addr().context("get listen address")?;
+        let torrents = Torrents::default();
+        let accept = tokio::spawn(accept_all(
+            listener,
+            Arc::clone(&torrents),
+            encryption,
+            handshake,
+        ));
+        Ok(Self {
+            local_addr,
+            torrents,
+            encryption,
+            handshake,
+            _accept: Arc::new(AbortOnDrop(accept)),
+            _accept_utp: None,
+        })
+    }
+
+    /// Also accept peers that connect over uTP on `socket`.
+    pub fn with_utp(mut self, socket: UtpSocket) -> Self {
+        let accept = tokio::spawn(accept_all_utp(
+            socket,
+            Arc::clone(&self.torrents),
+            self.encryption,
+            self.handshake,
+        ));
+        self._accept_utp = Some(Arc::new(AbortOnDrop(accept)));
+        self
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn port(&self) -> u16 {
+        self.local_addr.port()
+    }
+
+    /// Start accepting peers for the torrent described by `local`, handing them to `peers`, until
+    /// the returned guard is dropped.
+    pub(crate) fn register(
+        &self,
+        local: watch::Receiver<Local>,
+        peers: mpsc::Sender<Peer>,
+    ) -> Registered {
+        let info_hash = local.borrow().info_hash;
+        self.torrents
+            .lock()
+            .expect("no panics while holding the lock")
+            .insert(info_hash, Registration { local, peers });
+        Registered {
+            torrents: Arc::clone(&self.torrents),
+            info_hash,
+        }
+    }
+}
+
+/// Stops the listener from accepting peers for a torrent when dropped.
+pub(crate) struct Registered {
+    torrents: Torrents,
+    info_hash: [u8; 20],
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.torrents
+            .lock()
+            .expect("no panics while holding the lock")
+            .remove(&self.info_hash);
+    }
+}
+
+async fn accept_all(
+    listener: TcpListener,
+    torrents: Torrents,
+    encryption: Encryption,
+    handshake: Duration,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // most likely out of file descriptors; the next accept may well work
+                eprintln!("failed to accept connection: {e:?}");
+                continue;
+            }
+        };
+        spawn_accept(Box::new(stream), addr, &torrents, encryption, handshake);
+    }
+}
+
+async fn accept_all_utp(
+    socket: UtpSocket,
+    torrents: Torrents,
+    encryption: Encryption,
+    handshake: Duration,
+) {
+    loop {
+        let (stream, addr) = match socket.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("stopped accepting uTP connections: {e:?}");
+                return;
+            }
+        };
+        spawn_accept(Box::new(stream), addr, &torrents, encryption, handshake);
+    }
+}
+
+fn spawn_accept(
+    stream: Box<dyn Transport>,
+    addr: SocketAddr,
+    torrents: &Torrents,
+    encryption: Encryption,
+    handshake: Duration,
+) {
+    let torrents = Arc::clone(torrents);
+    tokio::spawn(async move {
+        if let Err(e) = accept(stream, addr, torrents, encryption, handshake).await {
+            eprintln!("rejected incoming peer {addr}: {e:?}");
+        }
+    });
+}
+
+async fn accept(
+    stream: Box<dyn Transport>,
+    addr: SocketAddr,
+    torrents: Torrents,
+    encryption: Encryption,
+    handshake: Duration,
+) -> anyhow::Result<()> {
+    let SocketAddr::V4(addr) = addr else {
+        anyhow::bail!("we only speak IPv4");
+    };
+    // the peer gets this long for everything up to our answer, so a peer that stops reading
+    // can't hold us up either
+    let deadline = tokio::time::Instant::now() + handshake;
+    let (stream, handshake) =
+        tokio::time::timeout_at(deadline, read_handshake(stream, &torrents, encryption))
+            .await
+            .context("handshake timed out")??;
+
+    let info_hash = handshake.info_hash;
+    let (local, peers) = {
+        let torrents = torrents.lock().expect("no panics while holding the lock");
+        let registration = torrents
+            .get(&info_hash)
+            .with_context(|| format!("unknown info hash {}", hex::encode(info_hash)))?;
+        let local = registration.local.borrow().clone();
+        (local, registration.peers.clone())
+    };
+    let peer = tokio::time::timeout_at(deadline, Peer::accept(addr, stream, &handshake, &local))
+        .await
+        .context("handshake timed out")??;
+    // the download may have finished in the meantime, in which case we just hang up
+    let _ = peers.send(peer).await;
+    Ok(())
+}
+
+/// Read the handshake of a peer that connected to us, after a key exchange if it starts with
+/// one.
+async fn read_handshake(
+    mut stream: Box<dyn Transport>,
+    torrents: &Torrents,
+    encryption: Encryption,
+) -> anyhow::Result<(mse::Stream<Box<dyn Transport>>, Handshake)> {
+    let mut first = [0; 20];
+    stream
+        .read_exact(&mut first)
+        .await
+        .context("read handshake")?;
+    if mse::is_plaintext(&first) {
+        anyhow::ensure!(
+            encryption != Encryption::Required,
+            "peer didn't encrypt the connection"
+        );
+        let mut stream = mse::Stream::plaintext(stream, &first);
+        let handshake = Handshake::read_from(&mut stream).await?;
+        return Ok((stream, handshake));
+    }
+
+    anyhow::ensure!(
+        encryption != Encryption::Disabled,
+        "peer didn't send a plaintext handshake"
+    );
+    let info_hashes: Vec<[u8; 20]> = torrents
+        .lock()
+        .expect("no panics while holding the lock")
+        .keys()
+        .copied()
+        .collect();
+    let (mut stream, info_hash) = mse::Stream::respond(stream, &first, &info_hashes, encryption)
+        .await
+        .context("key exchange")?;
+    let handshake = Handshake::read_from(&mut stream).await?;
+    handshake.verify(&info_hash)?;
+    Ok((stream, handshake))
+}
+
+#[tokio::test]
+async fn listener_routes_by_info_hash() {
+    use crate::bitfield::Bitfield;
+    use crate::download::test_local;
+    use crate::peer::Timeouts;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    let listener = Listener::bind(
+        "127.0.0.1:0".parse().unwrap(),
+        Encryption::Enabled,
+        Timeouts::default().handshake,
+    )
+    .await
+    .unwrap();
+    let have = Bitfield::from_payload(&[0b00000001], 8).unwrap();
+    let (_local_tx, local) = watch::channel(Local {
+        port: Some(listener.port()),
+        ..test_local([1; 20], have, Encryption::Enabled)
+    });
+    let (peers_tx, mut peers) = mpsc::channel(1);
+    let _registered = listener.register(local, peers_tx);
+
+    // a torrent we don't have is hung up on
+    let mut stream = TcpStream::connect(listener.local_addr()).await.unwrap();
+    let mut handshake = Handshake::new([2; 20], [0; 20]);
+    handshake.reserved = [0; 8];
+    handshake.write_to(&mut stream).await.unwrap();
+    assert_eq!(stream.read(&mut [0; 68]).await.unwrap(), 0);
+
+    let mut stream = TcpStream::connect(listener.local_addr()).await.unwrap();
+    let mut handshake = Handshake::new([1; 20], [0; 20]);
+    handshake.reserved = [0; 8];
+    handshake.write_to(&mut stream).await.unwrap();
+    let reply = Handshake::read_from(&mut stream).await.unwrap();
+    assert_eq!(reply.info_hash, [1; 20]);
+    // followed by our bitfield
+    let mut bitfield = [0; 6];
+    stream.read_exact(&mut bitfield).await.unwrap();
+    assert_eq!(bitfield, [0, 0, 0, 2, 5, 0b00000001]);
+
+    stream
+        .write_all(&[0, 0, 0, 2, 5, 0b10000000])
+        .await
+        .unwrap();
+    let mut peer = peers.recv().await.unwrap();
+    let served = crate::peer::Served {
+        data: &[0],
+        have: &Bitfield::from_payload(&[0b00000001], 8).unwrap(),
+        plength: 1,
+    };
+    peer.wait_for_have(&served).await.unwrap();
+    assert!(peer.has_piece(0));
+    assert!(!peer.has_piece(7));
+
+    // encrypted connections find their torrent too
+    let stream = TcpStream::connect(listener.local_addr()).await.unwrap();
+    let mut stream = mse::Stream::initiate(stream, &[1; 20], Encryption::Required)
+        .await
+        .unwrap();
+    assert!(stream.is_encrypted());
+    handshake.write_to(&mut stream).await.unwrap();
+    let reply = Handshake::read_from(&mut stream).await.unwrap();
+    assert_eq!(reply.info_hash, [1; 20]);
+    assert!(peers.recv().await.is_some());
+}
+
+#[tokio::test]
+async fn listener_handshake_timeout() {
+    use tokio::net::TcpStream;
+
+    let listener = Listener::bind(
+        "127.0.0.1:0".parse().unwrap(),
+        Encryption::Enabled,
+        Duration::from_millis(100),
+    )
+    .await
+    .unwrap();
+    // a peer that connects and never says anything is hung up on
+    let mut stream = TcpStream::connect(listener.local_addr()).await.unwrap();
+    let mut buf = [0; 1];
+    let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+        .await
+        .expect("listener should give up on the handshake");
+    assert!(matches!(read, Ok(0) | Err(_)));
+}
@@ -0,0 +1,360 @@
+//! Local Service Discovery (BEP 14): finding peers on the local network through multicast
+//! announcements.
+
+use crate::discovery::{self, DiscoveredPeer, PeerOrigin, PeerSource, Progress};
+use anyhow::Context;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+pub const MULTICAST_V4: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+pub const MULTICAST_V6: SocketAddrV6 = SocketAddrV6::new(
+    Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f),
+    6771,
+    0,
+    0,
+);
+
+/// How often we announce each torrent. BEP 14 asks for no more than once a minute.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Announcements from the same host are ignored if they come in quicker than this.
+const MIN_HEARD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A `BT-SEARCH` announcement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    /// The port the announcing peer accepts connections on.
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    /// Lets a client recognize (and ignore) its own announcements when they are looped back.
+    pub cookie: Option<String>,
+}
+
+impl Announce {
+    pub fn to_bytes(&self, host: &str) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {host}\r\nPort: {}\r\n",
+            self.port
+        );
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {cookie}\r\n"));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    pub fn parse(message: &[u8]) -> Option<Self> {
+        let message = std::str::from_utf8(message).ok()?;
+        let mut lines = message.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            // header names are case-insensitive, as in HTTP
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse().ok(),
+                "infohash" => {
+                    if let Some(info_hash) = hex::decode(value)
+                        .ok()
+                        .and_then(|info_hash| info_hash.try_into().ok())
+                    {
+                        info_hashes.push(info_hash);
+                    }
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Some(Self {
+            port: port.filter(|&port| port != 0)?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+/// The peers we heard announcing lately, so that one that announces over and over is only
+/// reported once in a while.
+#[derive(Default)]
+struct Heard(HashMap<SocketAddr, Instant>);
+
+impl Heard {
+    /// Whether `peer`, announcing at `now`, is worth reporting.
+    fn fresh(&mut self, peer: SocketAddr, now: Instant) -> bool {
+        // those we'd report again anyway needn't be remembered, which keeps this to the
+        // announcements of the last interval
+        self.0
+            .retain(|_, at| now.duration_since(*at) < MIN_HEARD_INTERVAL);
+        if self.0.contains_key(&peer) {
+            return false;
+        }
+        self.0.insert(peer, now);
+        true
+    }
+}
+
+/// Multicast sockets for sending and receiving local announcements.
+pub struct Lsd {
+    v4: UdpSocket,
+    /// IPv6 multicast is best effort, as many hosts don't have IPv6 set up.
+    v6: Option<UdpSocket>,
+    port: u16,
+    cookie: String,
+}
+
+impl Lsd {
+    /// Join the LSD multicast groups, announcing that we accept connections on `port`.
+    pub fn bind(port: u16) -> anyhow::Result<Self> {
+        let v4 = multicast_socket(SocketAddr::V4(MULTICAST_V4)).context("join IPv4 LSD group")?;
+        let v6 = multicast_socket(SocketAddr::V6(MULTICAST_V6)).ok();
+        Ok(Self {
+            v4,
+            v6,
+            port,
+            cookie: hex::encode(rand::random::<[u8; 8]>()),
+        })
+    }
+
+    pub async fn announce(&self, info_hashes: &[[u8; 20]]) -> anyhow::Result<()> {
+        let announce = Announce {
+            port: self.port,
+            info_hashes: info_hashes.to_vec(),
+            cookie: Some(self.cookie.clone()),
+        };
+        self.v4
+            .send_to(&announce.to_bytes(&MULTICAST_V4.to_string()), MULTICAST_V4)
+            .await
+            .context("send IPv4 LSD announce")?;
+        if let Some(v6) = &self.v6 {
+            let _ = v6
+                .send_to(&announce.to_bytes(&MULTICAST_V6.to_string()), MULTICAST_V6)
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Wait for an announcement from another client.
+    pub async fn recv(&self) -> anyhow::Result<(SocketAddr, Announce)> {
+        let mut buf = [0; 1500];
+        let mut buf6 = [0; 1500];
+        loop {
+            let (message, from) = match &self.v6 {
+                Some(v6) => tokio::select! {
+                    received = self.v4.recv_from(&mut buf) => {
+                        received.map(|(n, from)| (&buf[..n], from))
+                    }
+                    received = v6.recv_from(&mut buf6) => {
+                        received.map(|(n, from)| (&buf6[..n], from))
+                    }
+                },
+                None => self
+                    .v4
+                    .recv_from(&mut buf)
+                    .await
+                    .map(|(n, from)| (&buf[..n], from)),
+            }
+            .context("receive LSD announce")?;
+            let Some(announce) = Announce::parse(message) else {
+                continue;
+            };
+            if announce.cookie.as_deref() == Some(self.cookie.as_str()) {
+                // our own announcement, looped back to us
+                continue;
+            }
+            return Ok((from, announce));
+        }
+    }
+}
+
+fn multicast_socket(group: SocketAddr) -> anyhow::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let domain = if group.is_ipv4() {
+        Domain::IPV4
+    } else {
+        Domain::IPV6
+    };
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    // every client on the host listens on the same port
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    match group {
+        SocketAddr::V4(group) => {
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+            socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+            socket.set_multicast_loop_v4(true)?;
+        }
+        SocketAddr::V6(group) => {
+            socket.set_only_v6(true)?;
+            socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, group.port())).into())?;
+            socket.join_multicast_v6(group.ip(), 0)?;
+            socket.set_multicast_loop_v6(true)?;
+        }
+    }
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Peers on the local network that announce the torrent.
+pub struct LsdSource {
+    port: u16,
+}
+
+impl LsdSource {
+    /// `port` is the port we accept connections on, which is what we announce.
+    pub fn new(port: u16) -> Self {
+        Self { port }
+    }
+}
+
+impl PeerSource for LsdSource {
+    fn peers(
+        self: Box<Self>,
+        info_hash: [u8; 20],
+        progress: watch::Receiver<Progress>,
+    ) -> BoxStream<'static, DiscoveredPeer> {
+        let lsd = match Lsd::bind(self.port) {
+            Ok(lsd) => lsd,
+            Err(e) => {
+                eprintln!("local service discovery unavailable: {e:?}");
+                return stream::empty().boxed();
+            }
+        };
+        let state = (lsd, Instant::now(), Heard::default());
+        stream::unfold(
+            state,
+            move |(lsd, mut next_announce, mut heard)| async move {
+                loop {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(next_announce) => {
+                            if let Err(e) = lsd.announce(&[info_hash]).await {
+                                eprintln!("failed to announce on local network: {e:?}");
+                            }
+                            next_announce = Instant::now() + ANNOUNCE_INTERVAL;
+                        }
+                        received = lsd.recv() => {
+                            let (from, announce) = match received {
+                                Ok(received) => received,
+                                Err(e) => {
+                                    eprintln!("local service discovery failed: {e:?}");
+                                    return None;
+                                }
+                            };
+                            if !announce.info_hashes.contains(&info_hash) {
+                                continue;
+                            }
+                            let peer = SocketAddr::new(from.ip(), announce.port);
+                            if !heard.fresh(peer, Instant::now()) {
+                                continue;
+                            }
+                            let SocketAddr::V4(addr) = peer else {
+                                // we only speak IPv4
+                                continue;
+                            };
+                            let found = DiscoveredPeer {
+                                addr,
+                                origin: PeerOrigin::Lsd,
+                            };
+                            return Some((found, (lsd, next_announce, heard)));
+                        }
+                    }
+                }
+            },
+        )
+        .take_until(discovery::finished(progress))
+        .boxed()
+    }
+}
+
+#[test]
+fn lsd_announce_format() {
+    let announce = Announce {
+        port: 51413,
+        info_hashes: vec![[0xab; 20], [0x01; 20]],
+        cookie: Some(String::from("xyz")),
+    };
+    let bytes = announce.to_bytes("239.192.152.143:6771");
+    assert_eq!(
+        std::str::from_utf8(&bytes).unwrap(),
+        "BT-SEARCH * HTTP/1.1\r\n\
+         Host: 239.192.152.143:6771\r\n\
+         Port: 51413\r\n\
+         Infohash: abababababababababababababababababababab\r\n\
+         Infohash: 0101010101010101010101010101010101010101\r\n\
+         cookie: xyz\r\n\
+         \r\n\
+         \r\n"
+    );
+    assert_eq!(Announce::parse(&bytes), Some(announce));
+
+    // headers are case-insensitive and the cookie is optional
+    let parsed = Announce::parse(
+        b"BT-SEARCH * HTTP/1.1\r\nhost: x\r\nport: 1\r\nINFOHASH: 0101010101010101010101010101010101010101\r\n\r\n\r\n",
+    )
+    .unwrap();
+    assert_eq!(parsed.port, 1);
+    assert_eq!(parsed.info_hashes, vec![[0x01; 20]]);
+    assert_eq!(parsed.cookie, None);
+
+    assert_eq!(
+        Announce::parse(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n\r\n"),
+        None
+    );
+}
+
+#[test]
+fn lsd_rate_limit() {
+    let mut heard = Heard::default();
+    let now = Instant::now();
+    let a: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let b: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+    assert!(heard.fresh(a, now));
+    assert!(!heard.fresh(a, now + Duration::from_secs(30)));
+    // other peers have their own limit
+    assert!(heard.fresh(b, now + Duration::from_secs(30)));
+    // and once the interval is up, the peer is reported again, and the others forgotten
+    assert!(heard.fresh(a, now + MIN_HEARD_INTERVAL));
+    assert!(heard.fresh(b, now + MIN_HEARD_INTERVAL + Duration::from_secs(30)));
+    assert!(heard.fresh(a, now + 3 * MIN_HEARD_INTERVAL));
+    assert_eq!(heard.0.len(), 1);
+}
+
+#[tokio::test]
+async fn lsd_ignores_own_announcements() {
+    // plain sockets stand in for the multicast group
+    let lsd = Lsd {
+        v4: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        v6: None,
+        port: 6881,
+        cookie: String::from("ours"),
+    };
+    let to = lsd.v4.local_addr().unwrap();
+    let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let announce = |port, cookie: &str| {
+        Announce {
+            port,
+            info_hashes: vec![[1; 20]],
+            cookie: Some(String::from(cookie)),
+        }
+        .to_bytes(&to.to_string())
+    };
+    other.send_to(&announce(6881, "ours"), to).await.unwrap();
+    other.send_to(&announce(6882, "theirs"), to).await.unwrap();
+    let (from, received) = lsd.recv().await.unwrap();
+    assert_eq!(from, other.local_addr().unwrap());
+    assert_eq!(received.port, 6882);
+}
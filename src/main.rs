@@ -1,13 +1,25 @@
-use std::{net::SocketAddrV4, path::PathBuf};
 use anyhow::{Context, Ok};
-use bittorrent::{parse, peer::*, torrent::Keys, tracker::{TrackerRequest, TrackerResponse}, BLOCK_MAX};
+use bittorrent::torrent::Torrent;
+use bittorrent::{
+    choker::ChokerConfig,
+    dht::{Dht, DhtConfig, DhtSource},
+    discovery::{PeerSource, StaticPeers, TrackerSource},
+    download::DownloadConfig,
+    listener::Listener,
+    lsd::LsdSource,
+    mse::Encryption,
+    parse,
+    peer::*,
+    stats,
+    torrent::Keys,
+    tracker::{
+        server::Tracker, AnnounceConfig, ScrapeResponse, TrackerClient, TrackerClientConfig,
+        TrackerResponse,
+    },
+    utp::UtpSocket,
+};
 use clap::{Parser, Subcommand};
-use serde_bencode;
-use bittorrent::torrent::{Torrent};
-use futures_util::{SinkExt, StreamExt};
-use sha1::{Sha1,Digest};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
+use std::{net::SocketAddrV4, path::PathBuf, time::Duration};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -27,6 +39,8 @@ enum Command {
     },
     Peers {
         torrent: PathBuf,
+        #[command(flatten)]
+        http: TrackerHttpArgs,
     },
     Handshake {
         torrent: PathBuf,
@@ -37,14 +51,144 @@ enum Command {
         output: PathBuf,
         torrent: PathBuf,
         piece: usize,
+        #[command(flatten)]
+        http: TrackerHttpArgs,
+        #[command(flatten)]
+        announce: AnnounceArgs,
+        #[command(flatten)]
+        peers: PeerArgs,
     },
     Download {
         #[arg(short)]
         output: PathBuf,
         torrent: PathBuf,
+        #[command(flatten)]
+        http: TrackerHttpArgs,
+        #[command(flatten)]
+        announce: AnnounceArgs,
+        #[command(flatten)]
+        peers: PeerArgs,
+        /// Also find peers through the Mainline DHT.
+        #[arg(long)]
+        dht: bool,
+        /// File to keep known DHT nodes in between runs.
+        #[arg(long, requires = "dht")]
+        dht_cache: Option<PathBuf>,
+        /// UDP port for the DHT node. Defaults to the one after `--port`, which uTP uses.
+        #[arg(long, requires = "dht")]
+        dht_port: Option<u16>,
+        /// Also find peers on the local network through multicast announcements.
+        #[arg(long)]
+        lsd: bool,
+        /// Port to accept connections from peers on.
+        #[arg(long, default_value_t = 6881)]
+        port: u16,
+        /// How many peers to upload to at the same time.
+        #[arg(long, default_value_t = 4)]
+        upload_slots: usize,
+        /// How many block requests to keep outstanding with each peer.
+        #[arg(long, default_value_t = 16)]
+        pipeline: usize,
+        /// Whether to encrypt connections to peers: disabled, enabled or required.
+        #[arg(long, default_value = "enabled")]
+        encryption: Encryption,
+        /// Also connect to peers over uTP, on the same port number as TCP.
+        #[arg(long)]
+        utp: bool,
+        /// Print a table of the connected peers every few seconds.
+        #[arg(long)]
+        verbose: bool,
+        /// Seconds to keep uploading to peers once the download is complete.
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+    },
+    Scrape {
+        #[arg(required = true)]
+        torrents: Vec<PathBuf>,
+        #[command(flatten)]
+        http: TrackerHttpArgs,
+    },
+    Tracker {
+        #[arg(long, default_value = "0.0.0.0:6969")]
+        listen: std::net::SocketAddr,
+        /// Seconds between announces that peers are asked to honor.
+        #[arg(long, default_value_t = 1800)]
+        interval: u64,
+        /// Only track these torrents (hex info hash or .torrent file); may be repeated.
+        #[arg(long)]
+        allow: Vec<String>,
     },
 }
 
+// How to reach trackers over HTTP.
+#[derive(clap::Args, Debug)]
+struct TrackerHttpArgs {
+    /// Seconds to wait for a tracker to respond.
+    #[arg(long, default_value_t = 30)]
+    tracker_timeout: u64,
+    /// User-Agent header to send to trackers.
+    #[arg(long)]
+    user_agent: Option<String>,
+    /// HTTP or SOCKS5 proxy for tracker requests, e.g. socks5://127.0.0.1:9050.
+    #[arg(long)]
+    proxy: Option<String>,
+}
+
+impl TrackerHttpArgs {
+    fn client(self) -> anyhow::Result<TrackerClient> {
+        let mut config = TrackerClientConfig {
+            timeout: Duration::from_secs(self.tracker_timeout),
+            proxy: self.proxy,
+            ..Default::default()
+        };
+        if let Some(user_agent) = self.user_agent {
+            config.user_agent = user_agent;
+        }
+        TrackerClient::new(config)
+    }
+}
+
+// What to tell trackers about ourselves when announcing.
+#[derive(clap::Args, Debug)]
+struct AnnounceArgs {
+    /// How many peers to ask trackers for.
+    #[arg(long)]
+    numwant: Option<usize>,
+    /// IP address (or DNS name) to tell trackers we are reachable at.
+    #[arg(long)]
+    ip: Option<String>,
+    /// Key that proves to trackers it's us when our IP address changes. Random if not given.
+    #[arg(long)]
+    key: Option<String>,
+}
+
+impl AnnounceArgs {
+    fn config(self, peer_id: [u8; 20]) -> AnnounceConfig {
+        // the same key for the whole session
+        let key = self
+            .key
+            .unwrap_or_else(|| format!("{:08X}", rand::random::<u32>()));
+        AnnounceConfig {
+            peer_id: String::from_utf8(peer_id.to_vec()).expect("peer ids are ascii"),
+            ip: self.ip,
+            numwant: self.numwant,
+            key: Some(key),
+            ..Default::default()
+        }
+    }
+}
+
+// Peers to use besides (or instead of) the ones the tracker hands out.
+#[derive(clap::Args, Debug)]
+struct PeerArgs {
+    /// Connect to this peer (host:port); may be repeated.
+    #[arg(long = "peer", value_name = "HOST:PORT")]
+    peers: Vec<String>,
+    /// Don't ask the tracker for peers.
+    #[arg(long)]
+    no_tracker: bool,
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()>{
     let args = Args::parse();
@@ -60,16 +204,15 @@ async fn main() -> anyhow::Result<()>{
 
             println!("Announce: {}", t.announce);
             
-            let length = if let Keys::SingleFile { length } = t.info.keys {
+            if let Keys::SingleFile { length } = t.info.keys {
                 println!("Length: {length}");
-                length
             } else {
                 todo!();
             };
 
             let info_hash = t.info_hash();
 
-            println!("Info Hash: {}", hex::encode(&info_hash));
+            println!("Info Hash: {}", hex::encode(info_hash));
             println!("Piece Length: {}", t.info.plength);
 
 
@@ -80,38 +223,14 @@ async fn main() -> anyhow::Result<()>{
                 println!("{}", piece_hash)
             }
         }
-        Command::Peers { torrent } => {
+        Command::Peers { torrent, http } => {
             let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
             let t: Torrent =
                 serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
-            let length = if let Keys::SingleFile { length } = t.info.keys {
-                length
-            } else {
-                todo!();
-            };
-
             let info_hash = t.info_hash();
-            let request = TrackerRequest {
-                peer_id: String::from("00112233445566778899"),
-                port: 6881,
-                uploaded: 0,
-                downloaded: 0,
-                left: length,
-                compact: 1,
-            };
-
-            let url_params =
-                serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                t.announce,
-                url_params,
-                &urlencode(&info_hash)
-            );
-            let response = reqwest::get(tracker_url).await.context("query tracker")?;
-            let response = response.bytes().await.context("fetch tracker response")?;
-            let response: TrackerResponse =
-                serde_bencode::from_bytes(&response).context("parse tracker response")?;
+            let response = TrackerResponse::query_with(&http.client()?, &t, info_hash)
+                .await
+                .context("query tracker")?;
             for peer in &response.peers.0 {
                 println!("{}:{}", peer.ip(), peer.port());
             }
@@ -126,191 +245,216 @@ async fn main() -> anyhow::Result<()>{
             let mut peer = tokio::net::TcpStream::connect(peer)
                 .await
                 .context("connect to peer")?;
-            let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
-            {
-                let handshake_bytes =
-                    &mut handshake as *mut Handshake as *mut [u8; std::mem::size_of::<Handshake>()];
-                // Safety: Handshake is a POD with repr(c) and repr(packed)
-                let handshake_bytes: &mut [u8; std::mem::size_of::<Handshake>()] =
-                    unsafe { &mut *handshake_bytes };
-                peer.write_all(handshake_bytes)
-                    .await
-                    .context("write handshake")?;
-                peer.read_exact(handshake_bytes)
-                    .await
-                    .context("read handshake")?;
-            }
-            assert_eq!(handshake.length, 19);
-            assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
-            println!("Peer ID: {}", hex::encode(&handshake.peer_id));
+            let peer_id = new_peer_id();
+            Handshake::new(info_hash, peer_id)
+                .write_to(&mut peer)
+                .await?;
+            let handshake = Handshake::read_from(&mut peer).await?;
+            handshake.verify(&info_hash)?;
+            anyhow::ensure!(handshake.peer_id != peer_id, "connected to ourselves");
+            println!("Peer ID: {}", hex::encode(handshake.peer_id));
+            println!("Reserved: {}", hex::encode(handshake.reserved));
+            println!(
+                "Extension protocol: {}",
+                bittorrent::extension::supported(&handshake.reserved)
+            );
+            println!(
+                "Fast extension: {}",
+                bittorrent::fast::supported(&handshake.reserved)
+            );
         }
         Command::DownloadPiece {
             output,
             torrent,
             piece: piece_i,
+            http,
+            announce,
+            peers,
         } => {
-            let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent =
-                serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
-            let length = if let Keys::SingleFile { length } = t.info.keys {
-                length
-            } else {
-                todo!();
-            };
-            assert!(piece_i < t.info.pieces.0.len());
-
-            let info_hash = t.info_hash();
-            let request = TrackerRequest {
-                peer_id: String::from("00112233445566778899"),
-                port: 6881,
-                uploaded: 0,
-                downloaded: 0,
-                left: length,
-                compact: 1,
-            };
-
-            let url_params =
-                serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                t.announce,
-                url_params,
-                &urlencode(&info_hash)
-            );
-            let response = reqwest::get(tracker_url).await.context("query tracker")?;
-            let response = response.bytes().await.context("fetch tracker response")?;
-            let tracker_info: TrackerResponse =
-                serde_bencode::from_bytes(&response).context("parse tracker response")?;
-
-            let peer = &tracker_info.peers.0[0];
-            let mut peer = tokio::net::TcpStream::connect(peer)
-                .await
-                .context("connect to peer")?;
-            let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
-            {
-                let handshake_bytes = handshake.as_bytes_mut();
-                peer.write_all(handshake_bytes)
-                    .await
-                    .context("write handshake")?;
-                peer.read_exact(handshake_bytes)
-                    .await
-                    .context("read handshake")?;
+            let t = Torrent::read(torrent).await?;
+            let peer_id = new_peer_id();
+            let mut sources: Vec<Box<dyn PeerSource>> = Vec::new();
+            if !peers.peers.is_empty() {
+                sources.push(Box::new(StaticPeers::resolve(&peers.peers).await?));
             }
-            assert_eq!(handshake.length, 19);
-            assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
-
-            let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer);
-            let bitfield = peer
-                .next()
-                .await
-                .expect("peer always sends a bitfields")
-                .context("peer message was invalid")?;
-            assert_eq!(bitfield.tag, MessageTag::Bitfield);
-            // NOTE: we assume that the bitfield covers all pieces
-
-            peer.send(Message {
-                tag: MessageTag::Interested,
-                payload: Vec::new(),
-            })
-            .await
-            .context("send interested message")?;
-
-            let unchoke = peer
-                .next()
-                .await
-                .expect("peer always sends an unchoke")
-                .context("peer message was invalid")?;
-            assert_eq!(unchoke.tag, MessageTag::Unchoke);
-            assert!(unchoke.payload.is_empty());
-
-            let piece_hash = &t.info.pieces.0[piece_i];
-            let piece_size = if piece_i == t.info.pieces.0.len() - 1 {
-                let md = length % t.info.plength;
-                if md == 0 {
-                    t.info.plength
-                } else {
-                    md
-                }
-            } else {
-                t.info.plength
-            };
-            // the + (BLOCK_MAX - 1) rounds up
-            let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
-            let mut all_blocks = Vec::with_capacity(piece_size);
-            for block in 0..nblocks {
-                let block_size = if block == nblocks - 1 {
-                    let md = piece_size % BLOCK_MAX;
-                    if md == 0 {
-                        BLOCK_MAX
-                    } else {
-                        md
-                    }
-                } else {
-                    BLOCK_MAX
-                };
-                let mut request = Request::new(
-                    piece_i as u32,
-                    (block * BLOCK_MAX) as u32,
-                    block_size as u32,
-                );
-                let request_bytes = Vec::from(request.as_bytes_mut());
-                peer.send(Message {
-                    tag: MessageTag::Request,
-                    payload: request_bytes,
-                })
-                .await
-                .with_context(|| format!("send request for block {block}"))?;
-
-                let piece = peer
-                    .next()
-                    .await
-                    .expect("peer always sends a piece")
-                    .context("peer message was invalid")?;
-                assert_eq!(piece.tag, MessageTag::Piece);
-                assert!(!piece.payload.is_empty());
-
-                let piece = Piece::ref_from_bytes(&piece.payload[..])
-                    .expect("always get all Piece response fields from peer");
-                assert_eq!(piece.index() as usize, piece_i);
-                assert_eq!(piece.begin() as usize, block * BLOCK_MAX);
-                assert_eq!(piece.block().len(), block_size);
-                all_blocks.extend(piece.block());
+            if !peers.no_tracker {
+                // a tracker that doesn't answer is only logged, so explicit peers still get tried
+                let announce = announce.config(peer_id);
+                let tracker = TrackerSource::with_config(&t, announce).with_client(http.client()?);
+                sources.push(Box::new(tracker));
             }
-            assert_eq!(all_blocks.len(), piece_size);
-
-            let mut hasher = Sha1::new();
-            hasher.update(&all_blocks);
-            let hash: [u8; 20] = hasher
-                .finalize()
-                .try_into()
-                .expect("GenericArray<_, 20> == [_; 20]");
-            assert_eq!(&hash, piece_hash);
+            anyhow::ensure!(!sources.is_empty(), "no way to find peers");
+            let config = DownloadConfig {
+                peer_id,
+                ..Default::default()
+            };
+            let piece = t.download_piece_with(piece_i, sources, config).await?;
 
-            tokio::fs::write(&output, all_blocks)
+            tokio::fs::write(&output, piece)
                 .await
                 .context("write out downloaded piece")?;
             println!("Piece {piece_i} downloaded to {}.", output.display());
         },
-        Command::Download { output, torrent } => {
+        Command::Download {
+            output,
+            torrent,
+            http,
+            announce,
+            peers,
+            dht,
+            dht_cache,
+            dht_port,
+            lsd,
+            port,
+            upload_slots,
+            pipeline,
+            encryption,
+            utp,
+            verbose,
+            seed,
+        } => {
             let torrent = Torrent::read(torrent).await?;
             torrent.print_tree();
             // torrent.download_all_to_file(output).await?;
-            let files = torrent.download_all().await?;
+            let timeouts = Timeouts::default();
+            let mut listener = Listener::bind(
+                (std::net::Ipv4Addr::UNSPECIFIED, port).into(),
+                encryption,
+                timeouts.handshake,
+            )
+            .await?;
+            let port = listener.port();
+            let utp = if utp {
+                let socket =
+                    UtpSocket::bind((std::net::Ipv4Addr::UNSPECIFIED, port).into()).await?;
+                listener = listener.with_utp(socket.clone());
+                Some(socket)
+            } else {
+                None
+            };
+            let peer_id = new_peer_id();
+            let mut sources: Vec<Box<dyn PeerSource>> = Vec::new();
+            if !peers.peers.is_empty() {
+                sources.push(Box::new(StaticPeers::resolve(&peers.peers).await?));
+            }
+            if !peers.no_tracker {
+                let announce = AnnounceConfig {
+                    port,
+                    ..announce.config(peer_id)
+                };
+                let tracker =
+                    TrackerSource::with_config(&torrent, announce).with_client(http.client()?);
+                sources.push(Box::new(tracker));
+            }
+            let dht = if dht {
+                let dht_port = dht_port.unwrap_or_else(|| port.checked_add(1).unwrap_or(0));
+                let dht = Dht::bind(DhtConfig {
+                    bind: (std::net::Ipv4Addr::UNSPECIFIED, dht_port).into(),
+                    node_cache: dht_cache,
+                    ..Default::default()
+                })
+                .await?;
+                sources.push(Box::new(DhtSource::new(dht.clone()).announce(port)));
+                Some(dht)
+            } else {
+                None
+            };
+            if lsd {
+                sources.push(Box::new(LsdSource::new(port)));
+            }
+            anyhow::ensure!(!sources.is_empty(), "no way to find peers");
+            let peer_stats = if verbose {
+                let (peer_stats, mut watched) = tokio::sync::watch::channel(Vec::new());
+                tokio::spawn(async move {
+                    let mut every = tokio::time::interval(Duration::from_secs(5));
+                    // ends along with the download
+                    while watched.has_changed().is_ok() {
+                        every.tick().await;
+                        eprint!("{}", stats::table(&watched.borrow_and_update()));
+                    }
+                });
+                Some(peer_stats)
+            } else {
+                None
+            };
+            let config = DownloadConfig {
+                peer_id,
+                listener: Some(listener),
+                choker: ChokerConfig {
+                    slots: upload_slots,
+                    ..Default::default()
+                },
+                pipeline,
+                timeouts,
+                max_frame: MessageFramer::DEFAULT_MAX_FRAME,
+                encryption,
+                utp,
+                peer_stats,
+                seed: Duration::from_secs(seed),
+                dht,
+            };
+            let files = torrent.download_with(sources, config).await?;
             tokio::fs::write(
                 output,
                 files.into_iter().next().expect("always one file").bytes(),
             )
             .await?;
         }
+        Command::Scrape { torrents, http } => {
+            let client = http.client()?;
+            // trackers can answer for many torrents at once, so batch them by announce url
+            let mut by_tracker = std::collections::BTreeMap::<_, Vec<_>>::new();
+            for path in torrents {
+                let t = Torrent::read(&path).await?;
+                let info_hash = t.info_hash();
+                by_tracker
+                    .entry(t.announce)
+                    .or_default()
+                    .push((path, info_hash));
+            }
+
+            for (announce, batch) in by_tracker {
+                let info_hashes: Vec<_> = batch.iter().map(|&(_, info_hash)| info_hash).collect();
+                let response = ScrapeResponse::query_with(&client, &announce, &info_hashes)
+                    .await
+                    .with_context(|| format!("scrape {announce}"))?;
+                for (path, info_hash) in batch {
+                    match response.files.0.get(&info_hash) {
+                        Some(stats) => println!(
+                            "{}: {} seeders, {} leechers, {} completed",
+                            path.display(),
+                            stats.complete,
+                            stats.incomplete,
+                            stats.downloaded
+                        ),
+                        None => println!("{}: not tracked by {announce}", path.display()),
+                    }
+                }
+            }
+        }
+        Command::Tracker {
+            listen,
+            interval,
+            allow,
+        } => {
+            let mut tracker = Tracker::new(interval);
+            if !allow.is_empty() {
+                let mut info_hashes = Vec::with_capacity(allow.len());
+                for allowed in allow {
+                    let hex_hash = hex::decode(&allowed)
+                        .ok()
+                        .and_then(|info_hash| <[u8; 20]>::try_from(info_hash).ok());
+                    let info_hash = match hex_hash {
+                        Some(info_hash) => info_hash,
+                        None => Torrent::read(&allowed).await?.info_hash(),
+                    };
+                    info_hashes.push(info_hash);
+                }
+                tracker = tracker.allow(info_hashes);
+            }
+            eprintln!("tracker listening on {listen}");
+            tracker.run(listen).await?;
+        }
     }
     Ok(())
 }
-
-fn urlencode(t: &[u8; 20]) -> String {
-    let mut encoded = String::with_capacity(3 * t.len());
-    for &byte in t {
-        encoded.push('%');
-        encoded.push_str(&hex::encode(&[byte]));
-    }
-    encoded
-}
\ No newline at end of file
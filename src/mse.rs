@@ -0,0 +1,586 @@
+//! Message Stream Encryption, also known as Protocol Encryption: a Diffie-Hellman key exchange
+//! at the start of a peer connection, after which the connection is obfuscated with RC4 or, if
+//! both sides agree, carries on in plaintext.
+//!
+//! This is about getting past traffic shaping that recognizes BitTorrent, not about keeping
+//! secrets: anyone who knows the info hash can decrypt the connection.
+
+use crate::peer::PROTOCOL;
+use anyhow::Context;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use num_bigint::BigUint;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context as TaskContext, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// The prime the key exchange is done modulo, with 2 as the generator.
+const PRIME: &str =
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22\
+                     514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7E\
+                     C6F44C42E9A63A36210000000000090563";
+
+/// The length of public keys, and of the shared secret.
+const KEY_LENGTH: usize = 96;
+
+/// The most random padding either side may put after its public key, and in its encrypted
+/// handshake.
+const MAX_PADDING: usize = 512;
+
+/// The verification constant both sides send encrypted, which tells them the keys match.
+const VC: [u8; 8] = [0; 8];
+
+/// How much of the RC4 keystream is thrown away before it's used, since its start is weak.
+const RC4_DISCARD: usize = 1024;
+
+/// The most payload an initiator may send along with its handshake that we'll take.
+const MAX_INITIAL_PAYLOAD: usize = 1 << 14;
+
+/// `crypto_provide` and `crypto_select` bits.
+const PLAINTEXT: u32 = 0x01;
+const RC4: u32 = 0x02;
+
+/// Whether we encrypt connections to peers, and whether we insist on it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encryption {
+    /// Only ever speak plaintext, and hang up on peers that start with a key exchange.
+    Disabled,
+    /// Try encrypting outgoing connections, but fall back to plaintext for peers that don't
+    /// support it, and accept incoming connections either way.
+    #[default]
+    Enabled,
+    /// Only ever speak RC4, and hang up on peers that won't.
+    Required,
+}
+
+impl Encryption {
+    /// The `crypto_provide` bits we offer when we start a key exchange.
+    fn provide(self) -> u32 {
+        match self {
+            Encryption::Disabled => PLAINTEXT,
+            Encryption::Enabled => PLAINTEXT | RC4,
+            Encryption::Required => RC4,
+        }
+    }
+
+    /// Which of the methods a peer offered we go with, if any.
+    fn select(self, provided: u32) -> Option<u32> {
+        // RC4 whenever we can, since getting past throttling is the point of all this
+        [RC4, PLAINTEXT]
+            .into_iter()
+            .find(|&method| provided & method != 0 && self.provide() & method != 0)
+    }
+}
+
+impl FromStr for Encryption {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disabled" => Ok(Encryption::Disabled),
+            "enabled" => Ok(Encryption::Enabled),
+            "required" => Ok(Encryption::Required),
+            _ => anyhow::bail!("expected disabled, enabled or required, got {s:?}"),
+        }
+    }
+}
+
+/// Whether the first bytes of a connection are the start of a plaintext handshake, rather than
+/// of a public key.
+pub fn is_plaintext(first: &[u8; 20]) -> bool {
+    first[0] as usize == PROTOCOL.len() && first[1..] == PROTOCOL[..]
+}
+
+/// A peer connection, encrypted or not.
+///
+/// Anything that arrived along with the key exchange is handed out before reading on from the
+/// underlying stream.
+#[derive(Debug)]
+pub struct Stream<S> {
+    inner: S,
+    /// Plaintext that has already been read from `inner`.
+    read_buffer: Bytes,
+    decrypt: Option<Rc4>,
+    encrypt: Option<Rc4>,
+    /// Encrypted bytes that `inner` has yet to take.
+    write_buffer: BytesMut,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream<S> {
+    /// A plaintext connection, where `read` has already been read from `inner`.
+    pub fn plaintext(inner: S, read: &[u8]) -> Self {
+        Self {
+            inner,
+            read_buffer: Bytes::copy_from_slice(read),
+            decrypt: None,
+            encrypt: None,
+            write_buffer: BytesMut::new(),
+        }
+    }
+
+    /// Whether the connection is encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt.is_some()
+    }
+
+    /// Start the key exchange on a connection we opened to a peer of the torrent `info_hash`,
+    /// offering the methods that `policy` allows.
+    pub async fn initiate(
+        mut inner: S,
+        info_hash: &[u8; 20],
+        policy: Encryption,
+    ) -> anyhow::Result<Self> {
+        let key = PrivateKey::new();
+        let mut ours = key.public().to_vec();
+        ours.extend(padding());
+        inner.write_all(&ours).await.context("send public key")?;
+
+        let mut buf = BytesMut::new();
+        fill(&mut inner, &mut buf, KEY_LENGTH)
+            .await
+            .context("read public key")?;
+        let secret = key.shared_secret(&buf.split_to(KEY_LENGTH))?;
+        let mut encrypt = Rc4::for_mse(b"keyA", &secret, info_hash);
+        let mut decrypt = Rc4::for_mse(b"keyB", &secret, info_hash);
+
+        let mut handshake = BytesMut::new();
+        handshake.put_slice(&hash(&[b"req1", &secret]));
+        let req2 = hash(&[b"req2", info_hash]);
+        let req3 = hash(&[b"req3", &secret]);
+        handshake.extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+        let mut encrypted = BytesMut::new();
+        encrypted.put_slice(&VC);
+        encrypted.put_u32(policy.provide());
+        // no padding of our own, and no initial payload: the handshake follows once we know
+        // whether to encrypt it
+        encrypted.put_u16(0);
+        encrypted.put_u16(0);
+        encrypt.apply(&mut encrypted);
+        handshake.put(encrypted);
+        inner
+            .write_all(&handshake)
+            .await
+            .context("send encrypted handshake")?;
+
+        // the peer's padding comes before the encrypted verification constant
+        let mut vc = VC;
+        decrypt.apply(&mut vc);
+        sync(&mut inner, &mut buf, &vc)
+            .await
+            .context("find verification constant")?;
+        fill(&mut inner, &mut buf, 6).await?;
+        let mut header = buf.split_to(6);
+        decrypt.apply(&mut header);
+        let selected = header.get_u32();
+        let padding = header.get_u16() as usize;
+        anyhow::ensure!(padding <= MAX_PADDING, "padding too long ({padding} bytes)");
+        fill(&mut inner, &mut buf, padding).await?;
+        decrypt.apply(&mut buf.split_to(padding));
+
+        anyhow::ensure!(
+            [PLAINTEXT, RC4].contains(&selected) && policy.provide() & selected != 0,
+            "peer selected an encryption method we didn't offer: {selected:#x}"
+        );
+        Ok(Self::negotiated(
+            inner,
+            Bytes::new(),
+            buf,
+            selected,
+            encrypt,
+            decrypt,
+        ))
+    }
+
+    /// Take part in the key exchange on a connection a peer opened to us, having read `read`
+    /// from it so far.
+    ///
+    /// The peer only tells us which torrent it's after in a roundabout way, so we need to know
+    /// which ones we could be serving. Returns the one it picked along with the connection.
+    pub async fn respond(
+        mut inner: S,
+        read: &[u8],
+        info_hashes: &[[u8; 20]],
+        policy: Encryption,
+    ) -> anyhow::Result<(Self, [u8; 20])> {
+        let mut buf = BytesMut::from(read);
+        fill(&mut inner, &mut buf, KEY_LENGTH)
+            .await
+            .context("read public key")?;
+        let key = PrivateKey::new();
+        let secret = key.shared_secret(&buf.split_to(KEY_LENGTH))?;
+        let mut ours = key.public().to_vec();
+        ours.extend(padding());
+        inner.write_all(&ours).await.context("send public key")?;
+
+        // the peer's padding comes before the hash of the shared secret
+        sync(&mut inner, &mut buf, &hash(&[b"req1", &secret]))
+            .await
+            .context("find secret hash")?;
+        fill(&mut inner, &mut buf, 20).await?;
+        let req3 = hash(&[b"req3", &secret]);
+        let req2: Vec<u8> = buf
+            .split_to(20)
+            .iter()
+            .zip(req3)
+            .map(|(a, b)| a ^ b)
+            .collect();
+        let info_hash = *info_hashes
+            .iter()
+            .find(|info_hash| hash(&[b"req2", *info_hash]) == req2[..])
+            .context("peer asked for a torrent we don't have")?;
+        let mut decrypt = Rc4::for_mse(b"keyA", &secret, &info_hash);
+        let mut encrypt = Rc4::for_mse(b"keyB", &secret, &info_hash);
+
+        fill(&mut inner, &mut buf, 14).await?;
+        let mut header = buf.split_to(14);
+        decrypt.apply(&mut header);
+        anyhow::ensure!(header[..8] == VC, "keys don't match");
+        header.advance(8);
+        let provided = header.get_u32();
+        let padding = header.get_u16() as usize;
+        anyhow::ensure!(padding <= MAX_PADDING, "padding too long ({padding} bytes)");
+        fill(&mut inner, &mut buf, padding + 2).await?;
+        let mut rest = buf.split_to(padding + 2);
+        decrypt.apply(&mut rest);
+        rest.advance(padding);
+        let initial = rest.get_u16() as usize;
+        anyhow::ensure!(
+            initial <= MAX_INITIAL_PAYLOAD,
+            "initial payload too long ({initial} bytes)"
+        );
+        fill(&mut inner, &mut buf, initial).await?;
+        let mut initial = buf.split_to(initial);
+        decrypt.apply(&mut initial);
+
+        let selected = policy.select(provided).with_context(|| {
+            format!("no encryption method in common, peer offered {provided:#x}")
+        })?;
+        let mut reply = BytesMut::new();
+        reply.put_slice(&VC);
+        reply.put_u32(selected);
+        reply.put_u16(0);
+        encrypt.apply(&mut reply);
+        inner
+            .write_all(&reply)
+            .await
+            .context("send encrypted handshake")?;
+
+        let stream = Self::negotiated(inner, initial.freeze(), buf, selected, encrypt, decrypt);
+        Ok((stream, info_hash))
+    }
+
+    /// The connection once the key exchange is over, where `initial` is plaintext that came
+    /// with the handshake and `rest` is what was read after it.
+    fn negotiated(
+        inner: S,
+        initial: Bytes,
+        mut rest: BytesMut,
+        selected: u32,
+        encrypt: Rc4,
+        mut decrypt: Rc4,
+    ) -> Self {
+        let (encrypt, decrypt) = if selected == RC4 {
+            decrypt.apply(&mut rest);
+            (Some(encrypt), Some(decrypt))
+        } else {
+            (None, None)
+        };
+        let read_buffer = if initial.is_empty() {
+            rest.freeze()
+        } else {
+            let mut read = BytesMut::from(&initial[..]);
+            read.put(rest);
+            read.freeze()
+        };
+        Self {
+            inner,
+            read_buffer,
+            decrypt,
+            encrypt,
+            write_buffer: BytesMut::new(),
+        }
+    }
+
+    /// Hand whatever we have encrypted to the underlying stream.
+    fn poll_write_buffer(&mut self, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        while !self.write_buffer.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buffer))?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buffer.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Stream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.read_buffer.is_empty() {
+            let n = this.read_buffer.len().min(buf.remaining());
+            buf.put_slice(&this.read_buffer.split_to(n));
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(decrypt) = &mut this.decrypt {
+            decrypt.apply(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Stream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.encrypt.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        // the keystream can't be rewound, so once encrypted, bytes are ours to get written out
+        ready!(this.poll_write_buffer(cx))?;
+        this.write_buffer.extend_from_slice(buf);
+        if let Some(encrypt) = &mut this.encrypt {
+            encrypt.apply(&mut this.write_buffer);
+        }
+        // whatever doesn't go out now goes out on the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_write_buffer(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffer(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffer(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Read from `stream` until `buf` holds at least `n` bytes.
+async fn fill(
+    stream: &mut (impl AsyncRead + Unpin),
+    buf: &mut BytesMut,
+    n: usize,
+) -> anyhow::Result<()> {
+    while buf.len() < n {
+        buf.reserve(n - buf.len());
+        if stream.read_buf(buf).await.context("read from peer")? == 0 {
+            anyhow::bail!("peer hung up during the key exchange");
+        }
+    }
+    Ok(())
+}
+
+/// Read from `stream` until `buf` holds `pattern` after at most `MAX_PADDING` bytes, and
+/// consume everything up to and including it.
+async fn sync(
+    stream: &mut (impl AsyncRead + Unpin),
+    buf: &mut BytesMut,
+    pattern: &[u8],
+) -> anyhow::Result<()> {
+    loop {
+        if let Some(at) = buf
+            .windows(pattern.len())
+            .position(|window| window == pattern)
+        {
+            buf.advance(at + pattern.len());
+            return Ok(());
+        }
+        anyhow::ensure!(
+            buf.len() < MAX_PADDING + pattern.len(),
+            "not found in {} bytes",
+            buf.len()
+        );
+        // the pattern may be at most this far off
+        let n = MAX_PADDING + pattern.len() - buf.len();
+        buf.reserve(n);
+        if (&mut *stream)
+            .take(n as u64)
+            .read_buf(buf)
+            .await
+            .context("read from peer")?
+            == 0
+        {
+            anyhow::bail!("peer hung up during the key exchange");
+        }
+    }
+}
+
+/// Random padding of random length.
+fn padding() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let length = rng.gen_range(0..=MAX_PADDING);
+    (0..length).map(|_| rng.gen()).collect()
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// Our half of a Diffie-Hellman key exchange.
+struct PrivateKey {
+    prime: BigUint,
+    exponent: BigUint,
+}
+
+impl PrivateKey {
+    fn new() -> Self {
+        // 160 bits is what the spec asks for at the least
+        let exponent: [u8; 20] = rand::thread_rng().gen();
+        Self {
+            prime: BigUint::parse_bytes(PRIME.as_bytes(), 16).expect("the prime is valid hex"),
+            exponent: BigUint::from_bytes_be(&exponent),
+        }
+    }
+
+    fn public(&self) -> [u8; KEY_LENGTH] {
+        to_key_bytes(&BigUint::from(2u8).modpow(&self.exponent, &self.prime))
+    }
+
+    /// The secret we share with the peer that sent us `public`.
+    fn shared_secret(&self, public: &[u8]) -> anyhow::Result<[u8; KEY_LENGTH]> {
+        let public = BigUint::from_bytes_be(public);
+        // anything else makes for a secret that's easy to guess
+        anyhow::ensure!(
+            public > BigUint::from(1u8) && public < &self.prime - 1u8,
+            "peer sent a weak public key"
+        );
+        Ok(to_key_bytes(&public.modpow(&self.exponent, &self.prime)))
+    }
+}
+
+/// A number modulo the prime as the big-endian bytes the key exchange sends, zero-padded.
+fn to_key_bytes(n: &BigUint) -> [u8; KEY_LENGTH] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0; KEY_LENGTH];
+    key[KEY_LENGTH - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+/// The RC4 stream cipher.
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut state = [0; 256];
+        for (i, byte) in state.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// The cipher for one direction of a connection, `name` being `keyA` for what the side
+    /// that opened the connection sends, and `keyB` for what the other side sends.
+    fn for_mse(name: &[u8], secret: &[u8; KEY_LENGTH], info_hash: &[u8; 20]) -> Self {
+        let mut rc4 = Self::new(&hash(&[name, secret, info_hash]));
+        rc4.apply(&mut [0; RC4_DISCARD]);
+        rc4
+    }
+
+    /// Encrypt or decrypt `data` in place.
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[k as usize];
+        }
+    }
+}
+
+impl std::fmt::Debug for Rc4 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // the state is as good as the key
+        f.write_str("Rc4")
+    }
+}
+
+#[test]
+fn rc4_known_answer() {
+    let mut data = *b"Plaintext";
+    Rc4::new(b"Key").apply(&mut data);
+    assert_eq!(hex::encode(data), "bbf316e8d940af0ad3");
+}
+
+#[tokio::test]
+async fn key_exchange() {
+    async fn exchange(
+        initiator: Encryption,
+        responder: Encryption,
+    ) -> anyhow::Result<Option<bool>> {
+        let (a, b) = tokio::io::duplex(4096);
+        let info_hash = [7; 20];
+        let ours = [[1; 20], info_hash];
+        let initiate = Stream::initiate(a, &info_hash, initiator);
+        let respond = Stream::respond(b, &[], &ours, responder);
+        let (Ok(mut a), Ok((mut b, found))) = tokio::join!(initiate, respond) else {
+            return Ok(None);
+        };
+        assert_eq!(found, info_hash);
+        assert_eq!(a.is_encrypted(), b.is_encrypted());
+
+        // payload makes it through both ways
+        a.write_all(b"hello").await?;
+        a.flush().await?;
+        let mut hello = [0; 5];
+        b.read_exact(&mut hello).await?;
+        assert_eq!(&hello, b"hello");
+        b.write_all(b"there").await?;
+        b.flush().await?;
+        let mut there = [0; 5];
+        a.read_exact(&mut there).await?;
+        assert_eq!(&there, b"there");
+        Ok(Some(a.is_encrypted()))
+    }
+
+    use Encryption::*;
+    assert_eq!(exchange(Enabled, Enabled).await.unwrap(), Some(true));
+    assert_eq!(exchange(Required, Enabled).await.unwrap(), Some(true));
+    assert_eq!(exchange(Enabled, Required).await.unwrap(), Some(true));
+    assert_eq!(exchange(Disabled, Enabled).await.unwrap(), Some(false));
+    assert_eq!(exchange(Disabled, Required).await.unwrap(), None);
+    assert_eq!(exchange(Required, Disabled).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn key_exchange_unknown_torrent() {
+    let (a, b) = tokio::io::duplex(4096);
+    let initiate = Stream::initiate(a, &[7; 20], Encryption::Enabled);
+    let respond = Stream::respond(b, &[], &[[1; 20]], Encryption::Enabled);
+    let (initiated, responded) = tokio::join!(initiate, respond);
+    let error = responded.unwrap_err();
+    assert!(format!("{error:#}").contains("don't have"), "{error:#}");
+    // with the responder gone, the initiator finds out too
+    assert!(initiated.is_err());
+}
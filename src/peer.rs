@@ -1,120 +1,800 @@
+use crate::bitfield::Bitfield;
+use crate::choker::Candidate;
+use crate::extension::{self, Extensions};
+use crate::fast;
+use crate::mse::{self, Encryption};
+use crate::pex::{self, Pex};
+use crate::piece::Blocks;
+use crate::stats::{self, PeerStats, Rate};
+use crate::utp::UtpSocket;
 use crate::BLOCK_MAX;
 use anyhow::Context;
-use bytes::{Buf, BufMut, BytesMut};
-use futures_util::{SinkExt, StreamExt};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::{FutureExt, SinkExt, StreamExt};
+use std::collections::{HashSet, VecDeque};
 use std::net::SocketAddrV4;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
 use tokio_util::codec::Decoder;
 use tokio_util::codec::Encoder;
 use tokio_util::codec::Framed;
 
-// TODO: ideally, Peer should keep track of what pieces we have downloaded (and references to them)
-// so that we can respond to Requests from the other side. also, choking/unchoking the other side.
 pub(crate) struct Peer {
     addr: SocketAddrV4,
-    stream: Framed<TcpStream, MessageFramer>,
+    /// Whether the peer connected to us, in which case `addr` is its ephemeral source address.
+    incoming: bool,
+    /// The peer id the peer sent in its handshake.
+    peer_id: [u8; 20],
+    /// The reserved bytes of the peer's handshake, which say what extensions it supports.
+    reserved: [u8; 8],
+    stream: Framed<mse::Stream<Box<dyn Transport>>, MessageFramer>,
     bitfield: Bitfield,
+    /// Whether the peer may still send its bitfield. It's optional, but has to come first if
+    /// it's sent at all.
+    bitfield_expected: bool,
+    npieces: usize,
+    /// Pieces the peer announced, with its bitfield or with `Have`, that we haven't accounted
+    /// for yet.
+    haves: Vec<usize>,
     choked: bool,
+    /// Whether the peer wants to download from us.
+    interested: bool,
+    /// Whether we are choking the peer.
+    choking: bool,
+    /// Whether we told the peer we want to download from it.
+    interesting: bool,
+    /// Requests from the peer that we have yet to serve, oldest first.
+    uploads: VecDeque<Request>,
+    uploaded: u64,
+    downloaded: u64,
+    blocks_uploaded: u64,
+    blocks_downloaded: u64,
+    upload_rate: Rate,
+    download_rate: Rate,
+    /// How long the peer takes to answer a request that doesn't wait behind our others, smoothed,
+    /// once it has answered one.
+    rtt: Option<Duration>,
+    /// When the handshakes were done.
+    connected: Instant,
+    pipeline: usize,
+    /// The blocks of the piece we're downloading that we requested and the peer has yet to
+    /// send, with when we asked for them.
+    requested: Vec<(usize, Instant)>,
+    /// The requested block we measure the round trip with, if any: one we asked for while no
+    /// other request was outstanding, so that its answer didn't wait behind others.
+    timed: Option<usize>,
+    /// When we sent the oldest of our requests that the peer hasn't answered yet.
+    unanswered_since: Option<Instant>,
+    extensions: Extensions,
+    /// Pieces the peer lets us request even while we're choked.
+    allowed_fast: HashSet<usize>,
+    /// Pieces the peer suggested we download, typically because it has them in its cache.
+    suggested: HashSet<usize>,
+    /// The port the peer runs a DHT node on, until it's taken.
+    dht_port: Option<u16>,
+    timeouts: Timeouts,
+    /// When we last sent the peer anything, to know when it's time for a keep-alive.
+    last_sent: Instant,
+    /// When the peer last sent us anything, to know when it has gone away without saying so.
+    last_received: Instant,
+}
+
+/// What we tell peers about ourselves when a connection starts.
+#[derive(Debug, Clone)]
+pub(crate) struct Local {
+    pub(crate) info_hash: [u8; 20],
+    /// The peer id we go by, which also lets us recognize connections to ourselves.
+    pub(crate) peer_id: [u8; 20],
+    pub(crate) npieces: usize,
+    /// The pieces we have.
+    pub(crate) have: Bitfield,
+    /// The port we accept connections on, if we do.
+    pub(crate) port: Option<u16>,
+    /// How many requests we keep outstanding with a peer, unless it asks for fewer.
+    pub(crate) pipeline: usize,
+    pub(crate) timeouts: Timeouts,
+    /// The longest message we accept from a peer, or send to it. Raised as needed to fit the
+    /// torrent's bitfield.
+    pub(crate) max_frame: usize,
+    /// Whether we encrypt the connections we open.
+    pub(crate) encryption: Encryption,
+    /// The socket to reach peers over uTP with, before falling back to TCP.
+    pub(crate) utp: Option<UtpSocket>,
+}
+
+/// What a connection to a peer runs over: TCP, or uTP.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+/// How long we wait on peers before giving up on them.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// For the TCP connection to be established.
+    pub connect: Duration,
+
+    /// For the peer's handshake, and for the key exchange before it on encrypted connections.
+    pub handshake: Duration,
+
+    /// For a requested block to arrive. Once the oldest outstanding request has been waiting
+    /// this long, the peer's blocks are handed to other peers.
+    pub request: Duration,
+
+    /// How long we stay quiet before sending a keep-alive, so that the peer doesn't hang up on us.
+    pub keep_alive: Duration,
+
+    /// How long a peer may stay silent, keep-alives included, before we hang up on it.
+    pub idle: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            handshake: Duration::from_secs(20),
+            request: Duration::from_secs(30),
+            keep_alive: Duration::from_secs(90),
+            idle: Duration::from_secs(2 * 60),
+        }
+    }
+}
+
+async fn connect(peer_addr: SocketAddrV4, local: &Local) -> anyhow::Result<Box<dyn Transport>> {
+    if let Some(utp) = &local.utp {
+        // not every peer speaks uTP, so this is worth a try, but no more
+        let connect = tokio::time::timeout(local.timeouts.connect, utp.connect(peer_addr.into()));
+        if let Ok(Ok(stream)) = connect.await {
+            return Ok(Box::new(stream));
+        }
+    }
+    let stream = tokio::time::timeout(
+        local.timeouts.connect,
+        tokio::net::TcpStream::connect(peer_addr),
+    )
+    .await
+    .context("connect to peer timed out")?
+    .context("connect to peer")?;
+    Ok(Box::new(stream))
+}
+
+/// Identifies our client at the start of its peer ids, in the style most clients use: a dash,
+/// two letters for the client, four for its version, and another dash.
+const PEER_ID_PREFIX: &[u8; 8] = b"-RB0001-";
+
+/// A new, random, peer id for this client.
+///
+/// The random part is alphanumeric, so that the id can also be sent to trackers as is.
+pub fn new_peer_id() -> [u8; 20] {
+    use rand::Rng;
+
+    let mut peer_id = [0; 20];
+    peer_id[..PEER_ID_PREFIX.len()].copy_from_slice(PEER_ID_PREFIX);
+    let mut rng = rand::thread_rng();
+    for byte in &mut peer_id[PEER_ID_PREFIX.len()..] {
+        *byte = rng.sample(rand::distributions::Alphanumeric);
+    }
+    peer_id
 }
 
 impl Peer {
-    pub async fn new(peer_addr: SocketAddrV4, info_hash: [u8; 20]) -> anyhow::Result<Self> {
-        let mut peer = tokio::net::TcpStream::connect(peer_addr)
-            .await
-            .context("connect to peer")?;
-        let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
-        {
-            let handshake_bytes = handshake.as_bytes_mut();
-            peer.write_all(handshake_bytes)
-                .await
-                .context("write handshake")?;
-            peer.read_exact(handshake_bytes)
+    pub async fn new(peer_addr: SocketAddrV4, local: &Local) -> anyhow::Result<Self> {
+        let mut peer = match local.encryption {
+            Encryption::Disabled => mse::Stream::plaintext(connect(peer_addr, local).await?, &[]),
+            policy => {
+                let stream = connect(peer_addr, local).await?;
+                let encrypted = tokio::time::timeout(
+                    local.timeouts.handshake,
+                    mse::Stream::initiate(stream, &local.info_hash, policy),
+                )
                 .await
-                .context("read handshake")?;
-        }
-        anyhow::ensure!(handshake.length == 19);
-        anyhow::ensure!(&handshake.bittorrent == b"BitTorrent protocol");
-        let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer);
-        let bitfield = peer
-            .next()
-            .await
-            .expect("peer always sends a bitfields")
-            .context("peer message was invalid")?;
-        anyhow::ensure!(bitfield.tag == MessageTag::Bitfield);
+                .context("key exchange timed out")
+                .and_then(|encrypted| encrypted);
+                match encrypted {
+                    Ok(stream) => stream,
+                    // peers that don't do encryption hang up on what looks like garbage to them,
+                    // so we start over without it
+                    Err(_) if policy == Encryption::Enabled => {
+                        mse::Stream::plaintext(connect(peer_addr, local).await?, &[])
+                    }
+                    Err(e) => return Err(e.context("key exchange")),
+                }
+            }
+        };
+        let handshake = tokio::time::timeout(local.timeouts.handshake, async {
+            Handshake::new(local.info_hash, local.peer_id)
+                .write_to(&mut peer)
+                .await?;
+            Handshake::read_from(&mut peer).await
+        })
+        .await
+        .context("handshake timed out")??;
+        handshake.verify(&local.info_hash)?;
+        Self::start(peer_addr, false, peer, &handshake, local).await
+    }
 
-        Ok(Self {
+    /// Take over a connection from a peer that connected to us and sent us `handshake`.
+    pub(crate) async fn accept(
+        peer_addr: SocketAddrV4,
+        mut stream: mse::Stream<Box<dyn Transport>>,
+        handshake: &Handshake,
+        local: &Local,
+    ) -> anyhow::Result<Self> {
+        Handshake::new(local.info_hash, local.peer_id)
+            .write_to(&mut stream)
+            .await?;
+        Self::start(peer_addr, true, stream, handshake, local).await
+    }
+
+    /// Set up the connection once handshakes have been exchanged.
+    ///
+    /// This doesn't wait for the peer's bitfield, since peers without any pieces need not send
+    /// one. It is picked up along with the peer's other messages instead.
+    async fn start(
+        peer_addr: SocketAddrV4,
+        incoming: bool,
+        stream: mse::Stream<Box<dyn Transport>>,
+        handshake: &Handshake,
+        local: &Local,
+    ) -> anyhow::Result<Self> {
+        // we may well be in the peer lists we get from trackers and other peers
+        anyhow::ensure!(handshake.peer_id != local.peer_id, "connected to ourselves");
+        let extended = extension::supported(&handshake.reserved);
+        let mut peer = Self {
             addr: peer_addr,
-            stream: peer,
-            bitfield: Bitfield::from_payload(bitfield.payload),
+            incoming,
+            peer_id: handshake.peer_id,
+            reserved: handshake.reserved,
+            stream: tokio_util::codec::Framed::new(
+                stream,
+                MessageFramer::new(local.max_frame.max(1 + local.npieces.div_ceil(8))),
+            ),
+            bitfield: Bitfield::new(local.npieces),
+            bitfield_expected: true,
+            npieces: local.npieces,
+            haves: Vec::new(),
             choked: true,
+            interested: false,
+            choking: true,
+            interesting: false,
+            uploads: VecDeque::new(),
+            uploaded: 0,
+            downloaded: 0,
+            blocks_uploaded: 0,
+            blocks_downloaded: 0,
+            upload_rate: Rate::new(Instant::now()),
+            download_rate: Rate::new(Instant::now()),
+            rtt: None,
+            connected: Instant::now(),
+            pipeline: local.pipeline,
+            requested: Vec::new(),
+            timed: None,
+            unanswered_since: None,
+            extensions: Extensions::new(),
+            allowed_fast: HashSet::new(),
+            suggested: HashSet::new(),
+            dht_port: None,
+            timeouts: local.timeouts,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+        };
+
+        if local.have.count() > 0 {
+            peer.send(local.have.to_message()).await?;
+        } else if peer.fast() {
+            // the bitfield is optional when it's empty, but with the Fast Extension we must say so
+            peer.send(Message::HaveNone).await?;
+        }
+
+        if extended {
+            peer.extensions.register(Pex::default());
+            let mut ours = peer.extensions.handshake();
+            ours.set_yourip((*peer_addr.ip()).into());
+            ours.p = local.port;
+            ours.reqq = Some(MAX_UPLOADS_QUEUED as u32);
+            peer.send_extended(extension::HANDSHAKE_ID, &ours)
+                .await
+                .context("send extended handshake")?;
+        }
+
+        Ok(peer)
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+
+    /// The address the peer accepts connections on, if we know it: the one we connected to, or,
+    /// for peers that connected to us, the port they told us in their extended handshake.
+    pub(crate) fn listen_addr(&self) -> Option<SocketAddrV4> {
+        if !self.incoming {
+            return Some(self.addr);
+        }
+        let port = self.extensions.remote()?.p?;
+        Some(SocketAddrV4::new(*self.addr.ip(), port))
+    }
+
+    pub(crate) fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    /// Whether both sides support the Fast Extension. We always do.
+    fn fast(&self) -> bool {
+        fast::supported(&self.reserved)
+    }
+
+    async fn send_extended<T: serde::Serialize>(
+        &mut self,
+        id: u8,
+        message: &T,
+    ) -> anyhow::Result<()> {
+        let payload = serde_bencode::to_bytes(message).context("bencode extended message")?;
+        self.send(Message::Extended {
+            id,
+            payload: payload.into(),
         })
+        .await
+    }
+
+    /// The DHT node the peer told us it runs, if it did since we last asked.
+    pub(crate) fn take_dht_node(&mut self) -> Option<SocketAddrV4> {
+        let port = self.dht_port.take()?;
+        Some(SocketAddrV4::new(*self.addr.ip(), port))
+    }
+
+    /// Take the peers this peer has told us about through peer exchange since the last call.
+    pub(crate) fn take_pex_found(&mut self) -> Vec<SocketAddrV4> {
+        self.extensions
+            .get_mut::<Pex>()
+            .map(|pex| std::mem::take(&mut pex.found))
+            .unwrap_or_default()
+    }
+
+    /// Tell the peer about changes to the set of peers we are connected to, if it supports peer
+    /// exchange and it's been long enough since we last did.
+    pub(crate) async fn send_pex(
+        &mut self,
+        connected: &HashSet<SocketAddrV4>,
+    ) -> anyhow::Result<()> {
+        let Some(id) = self.extensions.remote_id(pex::NAME) else {
+            return Ok(());
+        };
+        let mut others = connected.clone();
+        if let Some(addr) = self.listen_addr() {
+            others.remove(&addr);
+        }
+        let Some(pex) = self.extensions.get_mut::<Pex>() else {
+            return Ok(());
+        };
+        let Some(message) = pex.state.delta(&others) else {
+            return Ok(());
+        };
+        self.send_extended(id, &message).await
     }
 
     pub(crate) fn has_piece(&self, piece_i: usize) -> bool {
         self.bitfield.has_piece(piece_i)
     }
 
+    /// The pieces the peer has, including the ones it has announced since it connected.
+    pub(crate) fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
+        self.bitfield.pieces()
+    }
+
+    pub(crate) fn bitfield(&self) -> &Bitfield {
+        &self.bitfield
+    }
+
+    /// Take the pieces the peer announced since the last call.
+    pub(crate) fn take_haves(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.haves)
+    }
+
+    fn on_have(&mut self, index: u32) -> anyhow::Result<()> {
+        let piece_i = index as usize;
+        anyhow::ensure!(
+            piece_i < self.npieces,
+            "peer has non-existent piece {piece_i}"
+        );
+        if self.bitfield.set_piece(piece_i) {
+            self.haves.push(piece_i);
+        }
+        Ok(())
+    }
+
+    /// Take in the peer's bitfield, or the Fast Extension's shorthands for it.
+    fn on_bitfield(&mut self, msg: &Message) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            std::mem::take(&mut self.bitfield_expected),
+            "peer sent {:?} after other messages",
+            msg.tag()
+        );
+        self.bitfield = match msg {
+            Message::Bitfield(payload) => Bitfield::from_payload(payload, self.npieces)
+                .context("peer sent invalid bitfield")?,
+            Message::HaveAll if self.fast() => Bitfield::all(self.npieces),
+            Message::HaveNone if self.fast() => Bitfield::new(self.npieces),
+            _ => anyhow::bail!(
+                "peer sent {:?} without negotiating the Fast Extension",
+                msg.tag()
+            ),
+        };
+        // the pieces are accounted for the same way as ones announced later on
+        let pieces: Vec<_> = self.pieces().collect();
+        self.haves.extend(pieces);
+        Ok(())
+    }
+
+    /// Read messages from the peer until it announces a new piece, or sends its bitfield.
+    ///
+    /// This keeps the peer's state current (and serves its requests) while it isn't
+    /// participating in any piece.
+    pub(crate) async fn wait_for_have(&mut self, served: &Served<'_>) -> anyhow::Result<()> {
+        loop {
+            let msg = self.recv(served).await?;
+            let announced = matches!(
+                msg,
+                Message::Have(_) | Message::Bitfield(_) | Message::HaveAll
+            );
+            if announced && !self.haves.is_empty() {
+                return Ok(());
+            }
+            // otherwise, we're not waiting for any blocks, nor for an unchoke
+        }
+    }
+
+    /// Read messages until one arrives that the caller may have to act on: `Choke`, `Unchoke`,
+    /// `Piece`, `RejectRequest`, `Have`, or the bitfield. Everything else is dealt with here,
+    /// including serving the blocks the peer requests.
+    async fn recv(&mut self, served: &Served<'_>) -> anyhow::Result<Message> {
+        loop {
+            // a send we stopped waiting for may have left its message queued up on the
+            // connection, and the peer can't answer what it hasn't been sent
+            self.stream.flush().await.context("send queued messages")?;
+            let msg = if self.uploads.is_empty() {
+                let keep_alive = self.last_sent + self.timeouts.keep_alive;
+                let idle = self.last_received + self.timeouts.idle;
+                tokio::select! {
+                    // whatever the peer sent while we weren't reading goes first, so that it
+                    // doesn't look idle just because we weren't listening
+                    biased;
+                    msg = self.stream.next() => msg,
+                    _ = tokio::time::sleep_until(keep_alive) => {
+                        self.send(Message::KeepAlive).await?;
+                        continue;
+                    }
+                    _ = tokio::time::sleep_until(idle) => {
+                        anyhow::bail!("peer has been silent for {:?}", self.timeouts.idle);
+                    }
+                }
+            } else {
+                // only serve a request once we've read everything the peer has sent so far, so
+                // that a cancel that is already here takes effect
+                match self.stream.next().now_or_never() {
+                    Some(msg) => msg,
+                    None => {
+                        self.serve(served).await?;
+                        continue;
+                    }
+                }
+            };
+            let msg = msg
+                .context("peer disconnected")?
+                .context("peer message was invalid")?;
+            self.last_received = Instant::now();
+            // the extended handshake and the allowed fast set may come before the bitfield
+            if !matches!(
+                msg,
+                Message::KeepAlive
+                    | Message::Unknown { .. }
+                    | Message::Port(_)
+                    | Message::Bitfield(_)
+                    | Message::HaveAll
+                    | Message::HaveNone
+                    | Message::Extended { .. }
+                    | Message::AllowedFast(_)
+                    | Message::SuggestPiece(_)
+            ) {
+                self.bitfield_expected = false;
+            }
+            match msg {
+                // ids we don't know are for extensions we didn't negotiate
+                Message::KeepAlive | Message::Unknown { .. } => {}
+                Message::Port(port) => {
+                    self.dht_port = Some(port);
+                }
+                Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => {
+                    self.on_bitfield(&msg)?;
+                    return Ok(msg);
+                }
+                Message::Have(index) => {
+                    self.on_have(index)?;
+                    return Ok(msg);
+                }
+                Message::Choke => {
+                    self.choked = true;
+                    if !self.fast() {
+                        // the choke implicitly dropped our requests
+                        self.unanswered_since = None;
+                    }
+                    return Ok(msg);
+                }
+                Message::Unchoke => {
+                    self.choked = false;
+                    return Ok(msg);
+                }
+                Message::Piece(ref block) => {
+                    self.downloaded += block.data.len() as u64;
+                    self.blocks_downloaded += 1;
+                    self.download_rate
+                        .add(block.data.len() as u64, Instant::now());
+                    self.unanswered_since = None;
+                    return Ok(msg);
+                }
+                Message::RejectRequest(_) => {
+                    anyhow::ensure!(self.fast(), "peer rejected without the Fast Extension");
+                    self.unanswered_since = None;
+                    return Ok(msg);
+                }
+                Message::Extended { id, payload } => self.extensions.on_message(id, &payload),
+                Message::AllowedFast(_) | Message::SuggestPiece(_) => self.on_fast(&msg)?,
+                // whether the peer gets to download is up to the choker
+                Message::Interested => self.interested = true,
+                Message::NotInterested => self.interested = false,
+                Message::Request(request) => self.on_request(request, served).await?,
+                Message::Cancel(cancelled) => {
+                    self.uploads.retain(|request| request != &cancelled);
+                }
+            }
+        }
+    }
+
+    async fn send(&mut self, msg: Message) -> anyhow::Result<()> {
+        let tag = msg.tag();
+        self.stream.send(msg).await.with_context(|| match tag {
+            Some(tag) => format!("send {tag:?} message"),
+            None => String::from("send message"),
+        })?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Queue up a block the peer requested, if it's one we can give it.
+    async fn on_request(&mut self, request: Request, served: &Served<'_>) -> anyhow::Result<()> {
+        let (index, begin, length) = (
+            request.index as usize,
+            request.begin as usize,
+            request.length as usize,
+        );
+        anyhow::ensure!(
+            index < self.npieces,
+            "peer requested non-existent piece {index}"
+        );
+        anyhow::ensure!(
+            length > 0 && length <= BLOCK_MAX,
+            "peer requested a block of {length} bytes"
+        );
+        anyhow::ensure!(
+            begin + length <= served.piece_length(index),
+            "peer requested a block past the end of piece {index}"
+        );
+        if self.choking || !served.has_piece(index) || self.uploads.len() >= MAX_UPLOADS_QUEUED {
+            // choked peers aren't supposed to ask, and we can't be expected to give what we
+            // don't have
+            if self.fast() {
+                self.send(Message::RejectRequest(request)).await?;
+            }
+            return Ok(());
+        }
+        self.uploads.push_back(request);
+        Ok(())
+    }
+
+    /// Send the block of the oldest outstanding request.
+    ///
+    /// The request stays queued until the block is on its way, so that it isn't lost if we stop
+    /// waiting halfway through.
+    async fn serve(&mut self, served: &Served<'_>) -> anyhow::Result<()> {
+        let Some(&request) = self.uploads.front() else {
+            return Ok(());
+        };
+        let data = served.block(
+            request.index as usize,
+            request.begin as usize,
+            request.length as usize,
+        );
+        // once the connection has taken the block, it goes out with the next flush whatever
+        // becomes of us
+        self.stream
+            .feed(Message::Piece(Block {
+                index: request.index,
+                begin: request.begin,
+                data: Bytes::copy_from_slice(data),
+            }))
+            .await
+            .context("send Piece message")?;
+        self.uploads.pop_front();
+        self.uploaded += data.len() as u64;
+        self.blocks_uploaded += 1;
+        self.upload_rate.add(data.len() as u64, Instant::now());
+        self.stream.flush().await.context("send Piece message")?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Whether the peer wants to download from us.
+    pub(crate) fn interested(&self) -> bool {
+        self.interested
+    }
+
+    /// Bytes of piece data we have sent the peer.
+    pub(crate) fn uploaded(&self) -> u64 {
+        self.uploaded
+    }
+
+    /// A snapshot of the connection, for keeping an eye on it.
+    pub(crate) fn stats(&self) -> PeerStats {
+        let now = Instant::now();
+        PeerStats {
+            addr: self.addr,
+            client: stats::client_name(&self.peer_id),
+            age: now.duration_since(self.connected),
+            downloaded: self.downloaded,
+            uploaded: self.uploaded,
+            blocks_downloaded: self.blocks_downloaded,
+            blocks_uploaded: self.blocks_uploaded,
+            download_rate: self.download_rate.get(now),
+            upload_rate: self.upload_rate.get(now),
+            rtt: self.rtt,
+            choked: self.choked,
+            interesting: self.interesting,
+            choking: self.choking,
+            interested: self.interested,
+        }
+    }
+
+    /// The peer answered a request `rtt` after we sent it.
+    fn on_rtt(&mut self, rtt: Duration) {
+        self.rtt = Some(match self.rtt {
+            None => rtt,
+            Some(srtt) => srtt * 7 / 8 + rtt / 8,
+        });
+    }
+
+    /// What the choker needs to know about this peer.
+    pub(crate) fn candidate(&self, snub_timeout: Duration) -> Candidate {
+        Candidate {
+            addr: self.addr,
+            interested: self.interested,
+            snubbed: self
+                .unanswered_since
+                .is_some_and(|since| since.elapsed() >= snub_timeout),
+            downloaded: self.downloaded,
+            uploaded: self.uploaded,
+        }
+    }
+
+    /// Choke or unchoke the peer, if that changes anything.
+    pub(crate) async fn set_choking(&mut self, choking: bool) -> anyhow::Result<()> {
+        if self.choking == choking {
+            return Ok(());
+        }
+        self.choking = choking;
+        if !choking {
+            return self.send(Message::Unchoke).await;
+        }
+        self.send(Message::Choke).await?;
+        // choking drops whatever the peer asked for; with the Fast Extension, explicitly
+        for request in std::mem::take(&mut self.uploads) {
+            if self.fast() {
+                self.send(Message::RejectRequest(request)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tell the peer whether we want to download from it, if that changes anything.
+    pub(crate) async fn set_interested(&mut self, interested: bool) -> anyhow::Result<()> {
+        if self.interesting == interested {
+            return Ok(());
+        }
+        self.interesting = interested;
+        self.send(if interested {
+            Message::Interested
+        } else {
+            Message::NotInterested
+        })
+        .await
+    }
+
+    /// Tell the peer we now have `piece_i`, unless it has it already and doesn't care.
+    pub(crate) async fn send_have(&mut self, piece_i: usize) -> anyhow::Result<()> {
+        if self.has_piece(piece_i) {
+            return Ok(());
+        }
+        self.send(Message::Have(piece_i as u32)).await
+    }
+
+    /// Whether the peer suggested we download `piece_i`.
+    pub(crate) fn suggests(&self, piece_i: usize) -> bool {
+        self.suggested.contains(&piece_i)
+    }
+
+    /// Handle the Fast Extension messages that only carry information.
+    fn on_fast(&mut self, msg: &Message) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.fast(),
+            "peer sent {:?} without negotiating the Fast Extension",
+            msg.tag()
+        );
+        match *msg {
+            Message::AllowedFast(index) => {
+                self.allowed_fast.insert(index as usize);
+            }
+            Message::SuggestPiece(index) => {
+                self.suggested.insert(index as usize);
+            }
+            _ => unreachable!("only called for fast information messages"),
+        }
+        Ok(())
+    }
+
+    /// How many requests we keep outstanding with this peer at most.
+    fn window(&self) -> usize {
+        let reqq = self
+            .extensions
+            .remote()
+            .and_then(|handshake| handshake.reqq)
+            .map_or(usize::MAX, |reqq| reqq as usize);
+        self.pipeline.min(reqq).max(1)
+    }
+
+    /// Whether we may request blocks of `piece_i` from the peer: pieces in the allowed fast set
+    /// may be requested even while choked.
+    fn may_request(&self, piece_i: usize) -> bool {
+        !self.choked || self.allowed_fast.contains(&piece_i)
+    }
+
+    /// Download blocks of `piece_i` from the peer, taking them from `blocks` and passing the
+    /// ones that arrive on to `finish` along with the peer's id, until the peer turns out to be too slow or fails.
+    ///
+    /// This may be dropped at any point, and called again to pick up where it left off: the
+    /// requests in flight are kept until then.
     pub(crate) async fn participate(
         &mut self,
         piece_i: usize,
         piece_size: usize,
         nblocks: usize,
-        submit: kanal::AsyncSender<usize>,
-        tasks: kanal::AsyncReceiver<usize>,
-        finish: tokio::sync::mpsc::Sender<Message>,
+        blocks: &Blocks,
+        finish: tokio::sync::mpsc::Sender<([u8; 20], Block)>,
+        served: &Served<'_>,
     ) -> anyhow::Result<()> {
         anyhow::ensure!(self.bitfield.has_piece(piece_i));
 
-        self.stream
-            .send(Message {
-                tag: MessageTag::Interested,
-                payload: Vec::new(),
-            })
-            .await
-            .context("send interested message")?;
-
-        // TODO: timeout, error, and return block to submit if .next() timed out
-        'task: loop {
-            while self.choked {
-                let unchoke = self
-                    .stream
-                    .next()
-                    .await
-                    .expect("peer always sends an unchoke")
-                    .context("peer message was invalid")?;
-                match unchoke.tag {
-                    MessageTag::Unchoke => {
-                        self.choked = false;
-                        assert!(unchoke.payload.is_empty());
-                        break;
-                    }
-                    MessageTag::Have => {
-                        // TODO: update bitfield
-                        // TODO: add to list of peers for relevant piece
-                    }
-                    MessageTag::Interested
-                    | MessageTag::NotInterested
-                    | MessageTag::Request
-                    | MessageTag::Cancel => {
-                        // not allowing requests for now
-                    }
-                    MessageTag::Piece => {
-                        // piece that we no longer need/are responsible for
-                    }
-                    MessageTag::Choke => {
-                        anyhow::bail!("peer sent unchoke while unchoked");
-                    }
-                    MessageTag::Bitfield => {
-                        anyhow::bail!("peer sent bitfield after handshake has been completed");
-                    }
-                }
-            }
-            let Ok(block) = tasks.recv().await else {
-                break;
-            };
+        let result = self
+            .pipeline_blocks(piece_i, piece_size, nblocks, blocks, &finish, served)
+            .await;
+        // whatever we were still waiting for when we gave up (or failed) is up for grabs again
+        self.timed = None;
+        for (block, _) in self.requested.drain(..) {
+            blocks.put(block);
+        }
+        result
+    }
 
-            let block_size = if block == nblocks - 1 {
+    async fn pipeline_blocks(
+        &mut self,
+        piece_i: usize,
+        piece_size: usize,
+        nblocks: usize,
+        blocks: &Blocks,
+        finish: &tokio::sync::mpsc::Sender<([u8; 20], Block)>,
+        served: &Served<'_>,
+    ) -> anyhow::Result<()> {
+        self.set_interested(true).await?;
+
+        let block_size = |block: usize| {
+            if block == nblocks - 1 {
                 let md = piece_size % BLOCK_MAX;
                 if md == 0 {
                     BLOCK_MAX
@@ -123,241 +803,299 @@ impl Peer {
                 }
             } else {
                 BLOCK_MAX
-            };
-
-            let mut request = Request::new(
-                piece_i as u32,
-                (block * BLOCK_MAX) as u32,
-                block_size as u32,
-            );
-            let request_bytes = Vec::from(request.as_bytes_mut());
-            self.stream
-                .send(Message {
-                    tag: MessageTag::Request,
-                    payload: request_bytes,
-                })
-                .await
-                .with_context(|| format!("send request for block {block}"))?;
+            }
+        };
 
-            let mut msg;
-            loop {
-                msg = self
-                    .stream
-                    .next()
+        loop {
+            // keep the window full
+            while self.may_request(piece_i) && self.requested.len() < self.window() {
+                let Some(block) = blocks.take() else {
+                    break;
+                };
+                let request = Request::new(
+                    piece_i as u32,
+                    (block * BLOCK_MAX) as u32,
+                    block_size(block) as u32,
+                );
+                if self.requested.is_empty() {
+                    self.timed = Some(block);
+                }
+                // counted as requested before it's sent, so that the block isn't lost if we stop
+                // halfway through sending
+                self.requested.push((block, Instant::now()));
+                self.unanswered_since.get_or_insert_with(Instant::now);
+                self.send(Message::Request(request))
                     .await
-                    .expect("peer always sends a piece")
-                    .context("peer message was invalid")?;
-
-                match msg.tag {
-                    MessageTag::Choke => {
-                        assert!(msg.payload.is_empty());
-                        self.choked = true;
-                        submit.send(block).await.expect("we still have a receiver");
-                        continue 'task;
-                    }
-                    MessageTag::Piece => {
-                        let piece = Piece::ref_from_bytes(&msg.payload[..])
-                            .expect("always get all Piece response fields from peer");
-
-                        if piece.index() as usize != piece_i
-                            || piece.begin() as usize != block * BLOCK_MAX
-                        {
-                            // piece that we no longer need/are responsible for
-                        } else {
-                            assert_eq!(piece.block().len(), block_size);
-                            break;
+                    .with_context(|| format!("send request for block {block}"))?;
+            }
+
+            // anything else is an unchoke, a new piece, or a block or rejection of an earlier
+            // piece that we no longer care about
+            let oldest = self.requested.iter().map(|&(_, requested)| requested).min();
+            let msg = match oldest {
+                Some(oldest) => {
+                    let deadline = oldest + self.timeouts.request;
+                    match tokio::time::timeout_at(deadline, self.recv(served)).await {
+                        Ok(msg) => msg?,
+                        Err(_) => {
+                            // the peer is too slow for this piece, so give up on it and let the
+                            // caller hand the blocks in flight to someone else
+                            let cancels: Vec<_> = self
+                                .requested
+                                .iter()
+                                .map(|&(block, _)| {
+                                    Request::new(
+                                        piece_i as u32,
+                                        (block * BLOCK_MAX) as u32,
+                                        block_size(block) as u32,
+                                    )
+                                })
+                                .collect();
+                            for cancel in cancels {
+                                self.send(Message::Cancel(cancel)).await?;
+                            }
+                            return Ok(());
                         }
                     }
-                    MessageTag::Have => {
-                        // TODO: update bitfield
-                        // TODO: add to list of peers for relevant piece
+                }
+                None if self.may_request(piece_i) => {
+                    // every block is taken, so wait for one to be handed back, and keep the
+                    // connection alive (and the peer served) meanwhile
+                    tokio::select! {
+                        _ = blocks.available() => continue,
+                        msg = self.recv(served) => msg?,
+                    }
+                }
+                None => self.recv(served).await?,
+            };
+            match msg {
+                Message::Choke if !self.fast() => {
+                    // the choke implicitly dropped our requests
+                    self.timed = None;
+                    for (block, _) in self.requested.drain(..) {
+                        blocks.put(block);
+                    }
+                }
+                Message::RejectRequest(rejected) => {
+                    if rejected.index as usize != piece_i {
+                        continue;
                     }
-                    MessageTag::Interested
-                    | MessageTag::NotInterested
-                    | MessageTag::Request
-                    | MessageTag::Cancel => {
-                        // not allowing requests for now
+                    let rejected_block = rejected.begin as usize / BLOCK_MAX;
+                    if let Some(i) = self
+                        .requested
+                        .iter()
+                        .position(|&(b, _)| b == rejected_block)
+                    {
+                        // hand the block straight to someone else rather than wait for it
+                        self.requested.swap_remove(i);
+                        if self.timed == Some(rejected_block) {
+                            self.timed = None;
+                        }
+                        blocks.put(rejected_block);
                     }
-                    MessageTag::Unchoke => {
-                        anyhow::bail!("peer sent unchoke while unchoked");
+                }
+                Message::Piece(piece) => {
+                    if piece.index as usize != piece_i {
+                        continue;
                     }
-                    MessageTag::Bitfield => {
-                        anyhow::bail!("peer sent bitfield after handshake has been completed");
+                    // blocks may arrive in any order
+                    let begin = piece.begin as usize;
+                    let Some(i) = self
+                        .requested
+                        .iter()
+                        .position(|&(block, _)| block * BLOCK_MAX == begin)
+                    else {
+                        continue;
+                    };
+                    let (block, requested) = self.requested.swap_remove(i);
+                    if self.timed == Some(block) {
+                        self.timed = None;
+                        self.on_rtt(requested.elapsed());
                     }
+                    anyhow::ensure!(
+                        piece.data.len() == block_size(block),
+                        "peer sent {} bytes for block {block} of piece {piece_i}",
+                        piece.data.len()
+                    );
+                    finish.send((self.peer_id, piece)).await.expect("receiver should not go away while there are active peers (us) and missing blocks (this one)");
                 }
+                _ => {}
             }
-
-            finish.send(msg).await.expect("receiver should not go away while there are active peers (us) and missing blocks (this one)");
         }
-
-        Ok(())
     }
 }
 
-pub struct Bitfield {
-    payload: Vec<u8>,
+/// Never queue up more than this many requests from a single peer.
+const MAX_UPLOADS_QUEUED: usize = 250;
+
+/// The verified pieces we can serve requests from.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Served<'a> {
+    /// The contents of the whole torrent, of which only the pieces in `have` are valid.
+    pub(crate) data: &'a [u8],
+    /// The pieces we have.
+    pub(crate) have: &'a Bitfield,
+    pub(crate) plength: usize,
 }
 
-impl Bitfield {
-    pub(crate) fn has_piece(&self, piece_i: usize) -> bool {
-        let byte_i = piece_i / (u8::BITS as usize);
-        let bit_i = (piece_i % (u8::BITS as usize)) as u32;
-        let Some(&byte) = self.payload.get(byte_i) else {
-            return false;
-        };
-        byte & 1u8.rotate_right(bit_i + 1) != 0
+impl Served<'_> {
+    fn has_piece(&self, piece_i: usize) -> bool {
+        self.have.has_piece(piece_i)
     }
 
-    pub(crate) fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
-        self.payload.iter().enumerate().flat_map(|(byte_i, byte)| {
-            (0..u8::BITS).filter_map(move |bit_i| {
-                let piece_i = byte_i * (u8::BITS as usize) + (bit_i as usize);
-                let mask = 1u8.rotate_right(bit_i + 1);
-                (byte & mask != 0).then_some(piece_i)
-            })
-        })
+    fn piece_length(&self, piece_i: usize) -> usize {
+        let start = piece_i * self.plength;
+        self.data.len().saturating_sub(start).min(self.plength)
     }
 
-    fn from_payload(payload: Vec<u8>) -> Bitfield {
-        Self { payload }
+    fn block(&self, piece_i: usize, begin: usize, length: usize) -> &[u8] {
+        &self.data[piece_i * self.plength + begin..][..length]
     }
 }
 
-#[test]
-fn bitfield_has() {
-    let bf = Bitfield {
-        payload: vec![0b10101010, 0b01010101],
-    };
-    assert!(bf.has_piece(0));
-    assert!(!bf.has_piece(1));
-    assert!(!bf.has_piece(7));
-    assert!(!bf.has_piece(8));
-    assert!(bf.has_piece(15));
-}
+/// The protocol string every handshake starts with, after its length.
+pub(crate) const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
 
-#[test]
-fn bitfield_iter() {
-    let bf = Bitfield {
-        payload: vec![0b10101010, 0b01010101],
-    };
-    let mut pieces = bf.pieces();
-    assert_eq!(pieces.next(), Some(0));
-    assert_eq!(pieces.next(), Some(2));
-    assert_eq!(pieces.next(), Some(4));
-    assert_eq!(pieces.next(), Some(6));
-    assert_eq!(pieces.next(), Some(9));
-    assert_eq!(pieces.next(), Some(11));
-    assert_eq!(pieces.next(), Some(13));
-    assert_eq!(pieces.next(), Some(15));
-    assert_eq!(pieces.next(), None);
-}
-
-#[repr(C)]
-#[repr(packed)]
+/// The first thing either side of a connection sends.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Handshake {
-    pub length: u8,
-    pub bittorrent: [u8; 19],
     pub reserved: [u8; 8],
     pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
 }
 
 impl Handshake {
+    /// The length of a handshake on the wire.
+    pub const LENGTH: usize = 1 + PROTOCOL.len() + 8 + 20 + 20;
+
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
         Self {
-            length: 19,
-            bittorrent: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            reserved: {
+                let mut reserved = [0; 8];
+                reserved[extension::RESERVED_BYTE] |= extension::RESERVED_BIT;
+                reserved[fast::RESERVED_BYTE] |= fast::RESERVED_BIT;
+                reserved
+            },
             info_hash,
             peer_id,
         }
     }
 
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
-        // Safety: Self is a POD with repr(c) and repr(packed)
-        let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes };
+    pub fn to_bytes(&self) -> [u8; Self::LENGTH] {
+        let mut bytes = [0; Self::LENGTH];
+        let mut rest = &mut bytes[..];
+        rest.put_u8(PROTOCOL.len() as u8);
+        rest.put_slice(PROTOCOL);
+        rest.put_slice(&self.reserved);
+        rest.put_slice(&self.info_hash);
+        rest.put_slice(&self.peer_id);
         bytes
     }
-}
-
-#[repr(C)]
-#[repr(packed)]
-pub struct Request {
-    index: [u8; 4],
-    begin: [u8; 4],
-    length: [u8; 4],
-}
 
-impl Request {
-    pub fn new(index: u32, begin: u32, length: u32) -> Self {
-        Self {
-            index: index.to_be_bytes(),
-            begin: begin.to_be_bytes(),
-            length: length.to_be_bytes(),
-        }
+    /// Parse a handshake, checking that it's one for the BitTorrent protocol.
+    pub fn from_bytes(bytes: &[u8; Self::LENGTH]) -> anyhow::Result<Self> {
+        let (&length, rest) = bytes.split_first().expect("handshakes aren't empty");
+        let (protocol, rest) = rest.split_at(PROTOCOL.len());
+        anyhow::ensure!(
+            length as usize == PROTOCOL.len() && protocol == PROTOCOL,
+            "peer doesn't speak the BitTorrent protocol"
+        );
+        let (reserved, rest) = rest
+            .split_first_chunk()
+            .expect("handshakes are long enough");
+        let (info_hash, peer_id) = rest
+            .split_first_chunk()
+            .expect("handshakes are long enough");
+        Ok(Self {
+            reserved: *reserved,
+            info_hash: *info_hash,
+            peer_id: peer_id.try_into().expect("what's left is the peer id"),
+        })
     }
 
-    pub fn index(&self) -> u32 {
-        u32::from_be_bytes(self.index)
+    pub async fn read_from(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Self> {
+        let mut bytes = [0; Self::LENGTH];
+        stream
+            .read_exact(&mut bytes)
+            .await
+            .context("read handshake")?;
+        Self::from_bytes(&bytes)
     }
 
-    pub fn begin(&self) -> u32 {
-        u32::from_be_bytes(self.begin)
+    pub async fn write_to(&self, stream: &mut (impl AsyncWrite + Unpin)) -> anyhow::Result<()> {
+        stream
+            .write_all(&self.to_bytes())
+            .await
+            .context("write handshake")
     }
 
-    pub fn length(&self) -> u32 {
-        u32::from_be_bytes(self.length)
+    /// Check that this is a handshake for the torrent with `info_hash`.
+    pub fn verify(&self, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            &self.info_hash == info_hash,
+            "peer is serving a different torrent ({})",
+            hex::encode(self.info_hash)
+        );
+        Ok(())
     }
+}
 
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
-        // Safety: Self is a POD with repr(c) and repr(packed)
-        let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes };
-        bytes
-    }
+#[test]
+fn handshake_bytes() {
+    let peer_id = new_peer_id();
+    assert!(peer_id.starts_with(PEER_ID_PREFIX));
+    assert!(peer_id.is_ascii());
+    assert_ne!(peer_id, new_peer_id());
+
+    let handshake = Handshake::new([1; 20], peer_id);
+    let mut bytes = handshake.to_bytes();
+    assert_eq!(&bytes[..20], b"\x13BitTorrent protocol");
+    assert_eq!(bytes[25], extension::RESERVED_BIT);
+    assert_eq!(&bytes[48..], &peer_id);
+    let parsed = Handshake::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed, handshake);
+    assert!(parsed.verify(&[1; 20]).is_ok());
+    assert!(parsed.verify(&[2; 20]).is_err());
+
+    bytes[1] = b'b';
+    assert!(Handshake::from_bytes(&bytes).is_err());
 }
 
-#[repr(C)]
-// NOTE: needs to be (and is)
-// #[repr(packed)]
-// but can't be marked as such because of the T: ?Sized part
-pub struct Piece<T: ?Sized = [u8]> {
-    index: [u8; 4],
-    begin: [u8; 4],
-    block: T,
+/// A block of a piece, as asked for with `Request` (and cancelled with `Cancel`, or rejected
+/// with `RejectRequest`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
 }
 
-impl Piece {
-    pub fn index(&self) -> u32 {
-        u32::from_be_bytes(self.index)
+impl Request {
+    pub fn new(index: u32, begin: u32, length: u32) -> Self {
+        Self {
+            index,
+            begin,
+            length,
+        }
     }
 
-    pub fn begin(&self) -> u32 {
-        u32::from_be_bytes(self.begin)
+    fn decode(payload: &mut Bytes) -> Self {
+        Self::new(payload.get_u32(), payload.get_u32(), payload.get_u32())
     }
 
-    pub fn block(&self) -> &[u8] {
-        &self.block
+    fn encode(&self, dst: &mut BytesMut) {
+        dst.put_u32(self.index);
+        dst.put_u32(self.begin);
+        dst.put_u32(self.length);
     }
+}
 
-    const PIECE_LEAD: usize = std::mem::size_of::<Piece<()>>();
-    pub fn ref_from_bytes(data: &[u8]) -> Option<&Self> {
-        if data.len() < Self::PIECE_LEAD {
-            return None;
-        }
-        let n = data.len();
-        // NOTE: The slicing here looks really weird. The reason we do it is because we need the
-        // length part of the fat pointer to Piece to hold the length of _just_ the `block` field.
-        // And the only way we can change the length of the fat pointer to Piece is by changing the
-        // length of the fat pointer to the slice, which we do by slicing it. We can't slice it at
-        // the front (as it would invalidate the ptr part of the fat pointer), so we slice it at
-        // the back!
-        let piece = &data[..n - Self::PIECE_LEAD] as *const [u8] as *const Piece;
-        // Safety: Piece is a POD with repr(c) and repr(packed), _and_ the fat pointer data length
-        // is the length of the trailing DST field (thanks to the PIECE_LEAD offset).
-        Some(unsafe { &*piece })
-    }
+/// The data of a block of a piece, as sent in a `Piece` message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub index: u32,
+    pub begin: u32,
+    pub data: Bytes,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -372,49 +1110,226 @@ pub enum MessageTag {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Port = 9,
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    Extended = 20,
 }
 
-#[derive(Debug, Clone)]
-pub struct Message {
-    pub tag: MessageTag,
-    pub payload: Vec<u8>,
+impl MessageTag {
+    fn from_id(id: u8) -> Option<Self> {
+        Some(match id {
+            0 => MessageTag::Choke,
+            1 => MessageTag::Unchoke,
+            2 => MessageTag::Interested,
+            3 => MessageTag::NotInterested,
+            4 => MessageTag::Have,
+            5 => MessageTag::Bitfield,
+            6 => MessageTag::Request,
+            7 => MessageTag::Piece,
+            8 => MessageTag::Cancel,
+            9 => MessageTag::Port,
+            13 => MessageTag::SuggestPiece,
+            14 => MessageTag::HaveAll,
+            15 => MessageTag::HaveNone,
+            16 => MessageTag::RejectRequest,
+            17 => MessageTag::AllowedFast,
+            20 => MessageTag::Extended,
+            _ => return None,
+        })
+    }
 }
 
-pub struct MessageFramer;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Has neither tag nor payload, and is only sent to keep an otherwise idle connection open.
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Bytes),
+    Request(Request),
+    Piece(Block),
+    Cancel(Request),
+    /// The port the peer's DHT node listens on (BEP 5).
+    Port(u16),
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest(Request),
+    AllowedFast(u32),
+    /// A message of the extension protocol, along with the extended message id it was sent with.
+    Extended {
+        id: u8,
+        payload: Bytes,
+    },
+    /// A message with an id we don't know, which is best ignored: peers may use ids from
+    /// extensions we don't support.
+    Unknown {
+        id: u8,
+        payload: Bytes,
+    },
+}
+
+impl Message {
+    /// The tag the message is sent with, or `None` for a keep-alive or a message we don't know.
+    pub fn tag(&self) -> Option<MessageTag> {
+        Some(match self {
+            Message::KeepAlive | Message::Unknown { .. } => return None,
+            Message::Choke => MessageTag::Choke,
+            Message::Unchoke => MessageTag::Unchoke,
+            Message::Interested => MessageTag::Interested,
+            Message::NotInterested => MessageTag::NotInterested,
+            Message::Have(_) => MessageTag::Have,
+            Message::Bitfield(_) => MessageTag::Bitfield,
+            Message::Request(_) => MessageTag::Request,
+            Message::Piece(_) => MessageTag::Piece,
+            Message::Cancel(_) => MessageTag::Cancel,
+            Message::Port(_) => MessageTag::Port,
+            Message::SuggestPiece(_) => MessageTag::SuggestPiece,
+            Message::HaveAll => MessageTag::HaveAll,
+            Message::HaveNone => MessageTag::HaveNone,
+            Message::RejectRequest(_) => MessageTag::RejectRequest,
+            Message::AllowedFast(_) => MessageTag::AllowedFast,
+            Message::Extended { .. } => MessageTag::Extended,
+        })
+    }
+
+    /// The id the message is sent with, or `None` for a keep-alive.
+    fn id(&self) -> Option<u8> {
+        match self {
+            Message::Unknown { id, .. } => Some(*id),
+            _ => self.tag().map(|tag| tag as u8),
+        }
+    }
+
+    /// Make sense of the payload of a message with tag `tag`, without copying it.
+    fn decode(tag: MessageTag, mut payload: Bytes) -> std::io::Result<Self> {
+        let expected = match tag {
+            MessageTag::Choke
+            | MessageTag::Unchoke
+            | MessageTag::Interested
+            | MessageTag::NotInterested
+            | MessageTag::HaveAll
+            | MessageTag::HaveNone => 0..=0,
+            MessageTag::Port => 2..=2,
+            MessageTag::Have | MessageTag::SuggestPiece | MessageTag::AllowedFast => 4..=4,
+            MessageTag::Request | MessageTag::Cancel | MessageTag::RejectRequest => 12..=12,
+            MessageTag::Piece => 8..=usize::MAX,
+            MessageTag::Extended => 1..=usize::MAX,
+            MessageTag::Bitfield => 0..=usize::MAX,
+        };
+        if !expected.contains(&payload.len()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{tag:?} message with a payload of {} bytes", payload.len()),
+            ));
+        }
+        Ok(match tag {
+            MessageTag::Choke => Message::Choke,
+            MessageTag::Unchoke => Message::Unchoke,
+            MessageTag::Interested => Message::Interested,
+            MessageTag::NotInterested => Message::NotInterested,
+            MessageTag::Have => Message::Have(payload.get_u32()),
+            MessageTag::Bitfield => Message::Bitfield(payload),
+            MessageTag::Request => Message::Request(Request::decode(&mut payload)),
+            MessageTag::Piece => Message::Piece(Block {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                data: payload,
+            }),
+            MessageTag::Cancel => Message::Cancel(Request::decode(&mut payload)),
+            MessageTag::Port => Message::Port(payload.get_u16()),
+            MessageTag::SuggestPiece => Message::SuggestPiece(payload.get_u32()),
+            MessageTag::HaveAll => Message::HaveAll,
+            MessageTag::HaveNone => Message::HaveNone,
+            MessageTag::RejectRequest => Message::RejectRequest(Request::decode(&mut payload)),
+            MessageTag::AllowedFast => Message::AllowedFast(payload.get_u32()),
+            MessageTag::Extended => Message::Extended {
+                id: payload.get_u8(),
+                payload,
+            },
+        })
+    }
+
+    /// Write the payload of the message, which goes after its tag.
+    fn encode_payload(&self, dst: &mut BytesMut) {
+        match self {
+            Message::Have(index) | Message::SuggestPiece(index) | Message::AllowedFast(index) => {
+                dst.put_u32(*index);
+            }
+            Message::Bitfield(payload) => dst.extend_from_slice(payload),
+            Message::Request(request)
+            | Message::Cancel(request)
+            | Message::RejectRequest(request) => request.encode(dst),
+            Message::Piece(block) => {
+                dst.put_u32(block.index);
+                dst.put_u32(block.begin);
+                dst.extend_from_slice(&block.data);
+            }
+            Message::Port(port) => dst.put_u16(*port),
+            Message::Extended { id, payload } => {
+                dst.put_u8(*id);
+                dst.extend_from_slice(payload);
+            }
+            Message::Unknown { payload, .. } => dst.extend_from_slice(payload),
+            Message::KeepAlive
+            | Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested
+            | Message::HaveAll
+            | Message::HaveNone => {}
+        }
+    }
+}
+
+pub struct MessageFramer {
+    /// Longer frames are refused both ways, so that peers can't make us buffer arbitrary
+    /// amounts of data.
+    max_frame: usize,
+}
+
+impl MessageFramer {
+    /// Enough for a block of the usual size, and the bitfields of all but the largest torrents.
+    pub const DEFAULT_MAX_FRAME: usize = 1 << 16;
+
+    pub fn new(max_frame: usize) -> Self {
+        Self { max_frame }
+    }
+}
 
-const MAX: usize = 1 << 16;
+impl Default for MessageFramer {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_MAX_FRAME)
+    }
+}
 
 impl Decoder for MessageFramer {
     type Item = Message;
     type Error = std::io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < 4 {
+        let Some(length_bytes) = src.first_chunk::<4>() else {
             // Not enough data to read length marker.
             return Ok(None);
-        }
-
-        // Read length marker.
-        let mut length_bytes = [0u8; 4];
-        length_bytes.copy_from_slice(&src[..4]);
-        let length = u32::from_be_bytes(length_bytes) as usize;
+        };
+        let length = u32::from_be_bytes(*length_bytes) as usize;
 
         if length == 0 {
             // this is a heartbeat message.
-            // discard it.
             src.advance(4);
-            // and then try again in case the buffer has more messages
-            return self.decode(src);
-        }
-
-        if src.len() < 5 {
-            // Not enough data to read tag marker.
-            return Ok(None);
+            return Ok(Some(Message::KeepAlive));
         }
 
         // Check that the length is not too large to avoid a denial of
         // service attack where the server runs out of memory.
-        if length > MAX {
+        if length > self.max_frame {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 format!("Frame of length {} is too large.", length),
@@ -422,7 +1337,7 @@ impl Decoder for MessageFramer {
         }
 
         if src.len() < 4 + length {
-            // The full string has not yet arrived.
+            // The full frame has not yet arrived.
             //
             // We reserve more space in the buffer. This is not strictly
             // necessary, but is a good idea performance-wise.
@@ -433,33 +1348,14 @@ impl Decoder for MessageFramer {
             return Ok(None);
         }
 
-        // Use advance to modify src such that it no longer contains
-        // this frame.
-        let tag = match src[4] {
-            0 => MessageTag::Choke,
-            1 => MessageTag::Unchoke,
-            2 => MessageTag::Interested,
-            3 => MessageTag::NotInterested,
-            4 => MessageTag::Have,
-            5 => MessageTag::Bitfield,
-            6 => MessageTag::Request,
-            7 => MessageTag::Piece,
-            8 => MessageTag::Cancel,
-            tag => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("Unknown message type {}.", tag),
-                ))
-            }
-        };
-        let data = if src.len() > 5 {
-            src[5..4 + length].to_vec()
-        } else {
-            Vec::new()
+        // Split the frame off of src, so that the payload can be handed out without copying it.
+        src.advance(4);
+        let mut frame = src.split_to(length).freeze();
+        let id = frame.get_u8();
+        let Some(tag) = MessageTag::from_id(id) else {
+            return Ok(Some(Message::Unknown { id, payload: frame }));
         };
-        src.advance(4 + length);
-
-        Ok(Some(Message { tag, payload: data }))
+        Message::decode(tag, frame).map(Some)
     }
 }
 
@@ -467,25 +1363,434 @@ impl Encoder<Message> for MessageFramer {
     type Error = std::io::Error;
 
     fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let Some(id) = item.id() else {
+            dst.put_u32(0);
+            return Ok(());
+        };
+
+        // Write the frame with a placeholder length, which is filled in once we know it.
+        let start = dst.len();
+        dst.put_u32(0);
+        dst.put_u8(id);
+        item.encode_payload(dst);
+        let length = dst.len() - start - 4;
+
         // Don't send a message if it is longer than the other end will
         // accept.
-        if item.payload.len() + 1 > MAX {
+        if length > self.max_frame {
+            dst.truncate(start);
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", item.payload.len()),
+                format!("Frame of length {} is too large.", length),
             ));
         }
+        dst[start..][..4].copy_from_slice(&(length as u32).to_be_bytes());
+        Ok(())
+    }
+}
 
-        // Convert the length into a byte array.
-        let len_slice = u32::to_be_bytes(item.payload.len() as u32 + 1);
+#[test]
+fn message_roundtrip() {
+    let messages = [
+        Message::KeepAlive,
+        Message::Unchoke,
+        Message::Have(7),
+        Message::Bitfield(Bytes::from_static(&[0b10100000])),
+        Message::Request(Request::new(1, 2, 3)),
+        Message::Piece(Block {
+            index: 1,
+            begin: BLOCK_MAX as u32,
+            data: Bytes::from_static(b"block"),
+        }),
+        Message::RejectRequest(Request::new(4, 5, 6)),
+        Message::HaveNone,
+        Message::Port(6881),
+        Message::Extended {
+            id: 0,
+            payload: Bytes::from_static(b"de"),
+        },
+        // from some extension we don't know
+        Message::Unknown {
+            id: 99,
+            payload: Bytes::from_static(&[1, 2]),
+        },
+    ];
+    let mut buf = BytesMut::new();
+    for message in &messages {
+        MessageFramer::default()
+            .encode(message.clone(), &mut buf)
+            .unwrap();
+    }
+    assert_eq!(&buf[..4], &[0, 0, 0, 0]);
+    assert_eq!(&buf[4..9], &[0, 0, 0, 1, 1]);
+    assert_eq!(&buf[9..18], &[0, 0, 0, 5, 4, 0, 0, 0, 7]);
+    for message in messages {
+        assert_eq!(
+            MessageFramer::default().decode(&mut buf).unwrap(),
+            Some(message)
+        );
+    }
+    assert_eq!(MessageFramer::default().decode(&mut buf).unwrap(), None);
 
-        // Reserve space in the buffer.
-        dst.reserve(4 /* length */ + 1 /* tag */ + item.payload.len());
+    // a frame that hasn't fully arrived yet
+    let mut buf = BytesMut::from(&[0, 0, 0, 5, 4, 0, 0][..]);
+    assert_eq!(MessageFramer::default().decode(&mut buf).unwrap(), None);
+    buf.extend_from_slice(&[0, 9]);
+    assert_eq!(
+        MessageFramer::default().decode(&mut buf).unwrap(),
+        Some(Message::Have(9))
+    );
 
-        // Write the length and string to the buffer.
-        dst.extend_from_slice(&len_slice);
-        dst.put_u8(item.tag as u8);
-        dst.extend_from_slice(&item.payload);
-        Ok(())
+    // payloads that don't fit their message are errors, not panics
+    for frame in [
+        &[0, 0, 0, 4, 4, 0, 0, 0][..],
+        &[0, 0, 0, 2, 1, 0],
+        &[0, 0, 0, 12, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        &[0, 0, 0, 8, 7, 0, 0, 0, 0, 0, 0, 0],
+        &[0, 0, 0, 1, 20],
+        &[0, 0, 0, 2, 9, 0],
+    ] {
+        let mut buf = BytesMut::from(frame);
+        assert!(
+            MessageFramer::default().decode(&mut buf).is_err(),
+            "{frame:?}"
+        );
+    }
+}
+
+#[test]
+fn message_frame_limit() {
+    let block = Message::Piece(Block {
+        index: 0,
+        begin: 0,
+        data: Bytes::from(vec![0; 100]),
+    });
+    let mut buf = BytesMut::new();
+    let mut framer = MessageFramer::new(100);
+    assert!(framer.encode(block.clone(), &mut buf).is_err());
+    assert!(buf.is_empty());
+    framer.encode(Message::Have(1), &mut buf).unwrap();
+
+    // a larger limit takes larger frames
+    let mut framer = MessageFramer::new(200);
+    framer.encode(block.clone(), &mut buf).unwrap();
+    assert_eq!(framer.decode(&mut buf).unwrap(), Some(Message::Have(1)));
+    assert_eq!(framer.decode(&mut buf).unwrap(), Some(block.clone()));
+
+    // the limit is known from the length alone, before the frame arrives
+    MessageFramer::new(200).encode(block, &mut buf).unwrap();
+    buf.truncate(10);
+    assert!(MessageFramer::new(100).decode(&mut buf).is_err());
+}
+
+#[tokio::test]
+async fn upload_roundtrip() {
+    use crate::download::{ipv4, test_local};
+    use crate::listener::Listener;
+    use tokio::sync::{mpsc, watch};
+
+    let plength = 2 * BLOCK_MAX + 100;
+    let data: Vec<u8> = (0..plength + 10).map(|i| (i % 251) as u8).collect();
+    let seeder = test_local([7; 20], Bitfield::all(2), Encryption::Required);
+    let listener = Listener::bind(
+        "127.0.0.1:0".parse().unwrap(),
+        Encryption::Required,
+        Timeouts::default().handshake,
+    )
+    .await
+    .unwrap();
+    let (_seeder_tx, seeder_rx) = watch::channel(seeder);
+    let (incoming_tx, mut incoming) = mpsc::channel(1);
+    let _registered = listener.register(seeder_rx, incoming_tx);
+
+    let leecher = Local {
+        port: Some(6889),
+        pipeline: 2,
+        ..test_local([7; 20], Bitfield::new(2), Encryption::Required)
+    };
+    let addr = ipv4(listener.local_addr());
+    let served = Served {
+        data: &data,
+        have: &Bitfield::all(2),
+        plength,
+    };
+    let nothing = Served {
+        data: &[],
+        have: &Bitfield::new(2),
+        plength,
+    };
+    let mut leech = Peer::new(addr, &leecher).await.unwrap();
+    let mut seed = incoming.recv().await.unwrap();
+    // the seed only learns where the leech listens from its extended handshake
+    assert_eq!(leech.listen_addr(), Some(addr));
+    assert_eq!(seed.listen_addr(), None);
+    leech.wait_for_have(&nothing).await.unwrap();
+    assert!(leech.has_piece(0) && leech.has_piece(1));
+    assert_eq!(leech.take_haves(), vec![0, 1]);
+    seed.set_choking(false).await.unwrap();
+    let nblocks = plength.div_ceil(BLOCK_MAX);
+    let blocks = Blocks::new(nblocks);
+    let (finish, mut done) = mpsc::channel(nblocks);
+    let mut piece = vec![0; plength];
+    {
+        let seeding = seed.wait_for_have(&served);
+        let leeching = leech.participate(0, plength, nblocks, &blocks, finish, &nothing);
+        tokio::pin!(seeding, leeching);
+        let mut received = 0;
+        while received < plength {
+            tokio::select! {
+                result = &mut seeding => panic!("seeder stopped: {result:?}"),
+                result = &mut leeching => panic!("leecher stopped: {result:?}"),
+                Some((_, block)) = done.recv() => {
+                    piece[block.begin as usize..][..block.data.len()].copy_from_slice(&block.data);
+                    received += block.data.len();
+                }
+            }
+        }
+    }
+    assert_eq!(piece, data[..plength]);
+    assert_eq!(
+        seed.listen_addr(),
+        Some(SocketAddrV4::new([127, 0, 0, 1].into(), 6889))
+    );
+    assert_eq!(seed.uploaded(), plength as u64);
+    let (seed_stats, leech_stats) = (seed.stats(), leech.stats());
+    assert_eq!(seed_stats.blocks_uploaded, nblocks as u64);
+    assert_eq!(leech_stats.blocks_downloaded, nblocks as u64);
+    assert_eq!(leech_stats.downloaded, plength as u64);
+    assert!(seed_stats.upload_rate > 0.0 && leech_stats.download_rate > 0.0);
+    assert!(leech_stats.rtt.is_some() && seed_stats.rtt.is_none());
+    assert!(!leech_stats.choked && leech_stats.interesting);
+    assert!(!seed_stats.choking && seed_stats.interested);
+    assert_eq!(leech_stats.client, "bittorrent 0.0.0.1");
+
+    // requests past the end of a piece are a protocol violation
+    leech
+        .send(Message::Request(Request::new(1, 0, 11)))
+        .await
+        .unwrap();
+    assert!(seed.wait_for_have(&served).await.is_err());
+
+    // blocks that were in flight when the peer went away are handed back
+    drop(seed);
+    let blocks = Blocks::new(1);
+    let (finish, _done) = mpsc::channel(1);
+    let participation = leech.participate(1, 10, 1, &blocks, finish, &nothing).await;
+    assert!(participation.is_err());
+    assert_eq!(blocks.take(), Some(0));
+}
+
+#[tokio::test]
+async fn timeouts_and_keep_alives() {
+    use crate::download::{fake_remote, test_local};
+
+    // a peer that unchokes us, and then never says anything again
+    let (silent_tx, mut silent) = tokio::sync::mpsc::unbounded_channel();
+    let addr = fake_remote(move |mut stream| {
+        let silent_tx = silent_tx.clone();
+        async move {
+            stream
+                .write_all(&[0, 0, 0, 2, 5, 0b10000000, 0, 0, 0, 1, 1])
+                .await
+                .unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            silent_tx.send(received).unwrap();
+        }
+    })
+    .await;
+
+    let local = Local {
+        timeouts: Timeouts {
+            request: Duration::from_millis(100),
+            keep_alive: Duration::from_millis(50),
+            idle: Duration::from_millis(300),
+            ..Default::default()
+        },
+        ..test_local([7; 20], Bitfield::new(1), Encryption::Disabled)
+    };
+    let mut peer = Peer::new(addr, &local).await.unwrap();
+    let nothing = Served {
+        data: &[],
+        have: &Bitfield::new(1),
+        plength: 10,
+    };
+    peer.wait_for_have(&nothing).await.unwrap();
+
+    // the unanswered request times out, and its block is handed back
+    let blocks = Blocks::new(1);
+    let (finish, _done) = tokio::sync::mpsc::channel(1);
+    peer.participate(0, 10, 1, &blocks, finish, &nothing)
+        .await
+        .unwrap();
+    assert_eq!(blocks.take(), Some(0));
+
+    // and eventually we stop waiting for the peer altogether
+    let error = peer.wait_for_have(&nothing).await.unwrap_err();
+    assert!(format!("{error:#}").contains("silent"), "{error:#}");
+    drop(peer);
+
+    let received = silent.recv().await.unwrap();
+    let mut frames = &received[..];
+    let mut tags = Vec::new();
+    while let Some((length, rest)) = frames.split_first_chunk::<4>() {
+        let length = u32::from_be_bytes(*length) as usize;
+        tags.push(rest.first().copied().filter(|_| length > 0));
+        frames = &rest[length..];
     }
-}
\ No newline at end of file
+    assert!(tags.contains(&Some(MessageTag::Request as u8)));
+    assert!(tags.contains(&Some(MessageTag::Cancel as u8)));
+    // a keep-alive has no tag
+    assert!(tags.contains(&None));
+}
+
+#[tokio::test]
+async fn self_connection() {
+    use crate::download::{ipv4, test_local};
+    use crate::listener::Listener;
+    use tokio::sync::{mpsc, watch};
+
+    let listener = Listener::bind(
+        "127.0.0.1:0".parse().unwrap(),
+        Encryption::Enabled,
+        Timeouts::default().handshake,
+    )
+    .await
+    .unwrap();
+    let local = Local {
+        port: Some(listener.port()),
+        ..test_local([7; 20], Bitfield::new(1), Encryption::Enabled)
+    };
+    let (_local_tx, local_rx) = watch::channel(local.clone());
+    let (incoming_tx, _incoming) = mpsc::channel(1);
+    let _registered = listener.register(local_rx, incoming_tx);
+
+    let addr = ipv4(listener.local_addr());
+    let error = Peer::new(addr, &local).await.err().unwrap();
+    assert!(format!("{error:#}").contains("ourselves"), "{error:#}");
+}
+
+#[tokio::test]
+async fn utp_transport() {
+    use crate::download::{ipv4, test_local};
+    use crate::listener::Listener;
+    use tokio::sync::{mpsc, watch};
+
+    let have = Bitfield::from_payload(&[0b10100000], 8).unwrap();
+    let seeder = test_local([7; 20], have, Encryption::Required);
+    // nothing takes TCP connections on the uTP socket's port, so only uTP gets through
+    let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let addr = ipv4(server.local_addr().unwrap());
+    let listener = Listener::bind(
+        "127.0.0.1:0".parse().unwrap(),
+        Encryption::Required,
+        Timeouts::default().handshake,
+    )
+    .await
+    .unwrap()
+    .with_utp(server);
+    let (_seeder_tx, seeder_rx) = watch::channel(seeder);
+    let (incoming_tx, mut incoming) = mpsc::channel(1);
+    let _registered = listener.register(seeder_rx, incoming_tx);
+
+    let leecher = Local {
+        utp: Some(
+            UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap(),
+        ),
+        ..test_local([7; 20], Bitfield::new(8), Encryption::Required)
+    };
+    let mut leech = Peer::new(addr, &leecher).await.unwrap();
+    let _seed = incoming.recv().await.unwrap();
+    let nothing = Served {
+        data: &[],
+        have: &Bitfield::new(8),
+        plength: 10,
+    };
+    leech.wait_for_have(&nothing).await.unwrap();
+    assert_eq!(leech.take_haves(), vec![0, 2]);
+}
+
+#[tokio::test]
+async fn encryption_fallback() {
+    use crate::download::{fake_remote, test_local};
+
+    // a peer that only speaks plaintext, and hangs up on anything else
+    let addr = fake_remote(|mut stream| async move {
+        let _ = stream.read_to_end(&mut Vec::new()).await;
+    })
+    .await;
+
+    let mut local = test_local([7; 20], Bitfield::new(1), Encryption::Enabled);
+    let peer = Peer::new(addr, &local).await.unwrap();
+    assert!(!peer.stream.get_ref().is_encrypted());
+    drop(peer);
+
+    local.encryption = Encryption::Required;
+    assert!(Peer::new(addr, &local).await.is_err());
+}
+
+#[tokio::test]
+async fn optional_bitfield() {
+    use crate::download::{fake_remote, test_local};
+
+    // a peer that handshakes, sends `messages`, and hangs up
+    async fn remote(messages: &'static [u8]) -> SocketAddrV4 {
+        fake_remote(move |mut stream| async move {
+            stream.write_all(messages).await.unwrap();
+        })
+        .await
+    }
+
+    let local = test_local([7; 20], Bitfield::new(16), Encryption::Disabled);
+    let nothing = Served {
+        data: &[],
+        have: &Bitfield::new(16),
+        plength: 10,
+    };
+
+    // no bitfield, just an unchoke and a have
+    let addr = remote(&[0, 0, 0, 1, 1, 0, 0, 0, 5, 4, 0, 0, 0, 9]).await;
+    let mut peer = Peer::new(addr, &local).await.unwrap();
+    peer.wait_for_have(&nothing).await.unwrap();
+    assert!(!peer.choked);
+    assert_eq!(peer.take_haves(), vec![9]);
+    assert_eq!(peer.pieces().collect::<Vec<_>>(), vec![9]);
+    // and the stream ending is an error, not a panic
+    assert!(peer.wait_for_have(&nothing).await.is_err());
+
+    // a bitfield is only allowed first
+    let addr = remote(&[0, 0, 0, 1, 1, 0, 0, 0, 3, 5, 0xff, 0xff]).await;
+    let mut peer = Peer::new(addr, &local).await.unwrap();
+    assert!(peer.wait_for_have(&nothing).await.is_err());
+
+    // bitfields must fit the torrent exactly, down to the spare bits at the end
+    let addr = remote(&[0, 0, 0, 2, 5, 0xff]).await;
+    let mut peer = Peer::new(addr, &local).await.unwrap();
+    assert!(peer.wait_for_have(&nothing).await.is_err());
+    let addr = remote(&[0, 0, 0, 4, 5, 0xff, 0xff, 0x80]).await;
+    let mut peer = Peer::new(addr, &local).await.unwrap();
+    assert!(peer.wait_for_have(&nothing).await.is_err());
+    let addr = remote(&[0, 0, 0, 3, 5, 0xff, 0xff]).await;
+    let mut peer = Peer::new(addr, &local).await.unwrap();
+    peer.wait_for_have(&nothing).await.unwrap();
+    assert_eq!(peer.bitfield().count(), 16);
+
+    // a peer that hangs up straight away
+    let addr = remote(&[]).await;
+    let mut peer = Peer::new(addr, &local).await.unwrap();
+    assert!(peer.wait_for_have(&nothing).await.is_err());
+
+    // the DHT port may come before the bitfield too, and is kept for our DHT node
+    let addr = remote(&[0, 0, 0, 3, 9, 0x1a, 0xe1, 0, 0, 0, 5, 4, 0, 0, 0, 9]).await;
+    let mut peer = Peer::new(addr, &local).await.unwrap();
+    peer.wait_for_have(&nothing).await.unwrap();
+    assert_eq!(
+        peer.take_dht_node(),
+        Some(SocketAddrV4::new(*addr.ip(), 6881))
+    );
+    assert_eq!(peer.take_dht_node(), None);
+}
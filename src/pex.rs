@@ -0,0 +1,256 @@
+//! Peer Exchange (`ut_pex`, BEP 11): learning about other peers in the swarm from the peers we're
+//! connected to.
+
+use crate::extension::Extension;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::time::{Duration, Instant};
+
+/// Name of the extension in the extended handshake.
+pub const NAME: &str = "ut_pex";
+
+/// Never send peer exchange messages more often than this.
+pub const INTERVAL: Duration = Duration::from_secs(60);
+
+/// Maximum number of `added` (and of `dropped`) peers in a single message.
+pub const MAX_PEERS: usize = 50;
+
+/// Flags in `added.f`.
+pub mod flags {
+    pub const PREFERS_ENCRYPTION: u8 = 0x01;
+    pub const SEED: u8 = 0x02;
+    pub const SUPPORTS_UTP: u8 = 0x04;
+    pub const SUPPORTS_HOLEPUNCH: u8 = 0x08;
+    pub const REACHABLE: u8 = 0x10;
+}
+
+/// A peer exchange message, listing the changes to the sender's set of connected peers since its
+/// previous message.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PexMessage {
+    /// Compact IPv4 peers (6 bytes each) that were connected to.
+    #[serde(default, with = "serde_bytes")]
+    pub added: Vec<u8>,
+
+    /// One byte of flags for each peer in `added`.
+    #[serde(rename = "added.f", default, with = "serde_bytes")]
+    pub added_f: Vec<u8>,
+
+    /// Compact IPv6 peers (18 bytes each) that were connected to.
+    #[serde(default, with = "serde_bytes")]
+    pub added6: Vec<u8>,
+
+    /// One byte of flags for each peer in `added6`.
+    #[serde(rename = "added6.f", default, with = "serde_bytes")]
+    pub added6_f: Vec<u8>,
+
+    /// Compact IPv4 peers that were disconnected from.
+    #[serde(default, with = "serde_bytes")]
+    pub dropped: Vec<u8>,
+
+    /// Compact IPv6 peers that were disconnected from.
+    #[serde(default, with = "serde_bytes")]
+    pub dropped6: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PexPeer {
+    pub addr: SocketAddr,
+    pub flags: u8,
+}
+
+impl PexMessage {
+    pub fn new(added: &[SocketAddrV4], dropped: &[SocketAddrV4]) -> Self {
+        Self {
+            added: added.iter().flat_map(|&peer| compact_v4(peer)).collect(),
+            added_f: vec![0; added.len()],
+            dropped: dropped.iter().flat_map(|&peer| compact_v4(peer)).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// All peers the sender connected to, IPv4 first.
+    pub fn added(&self) -> Vec<PexPeer> {
+        let v4 = self.added.chunks_exact(6).map(parse_v4).map(SocketAddr::V4);
+        let v6 = self
+            .added6
+            .chunks_exact(18)
+            .map(parse_v6)
+            .map(SocketAddr::V6);
+        let flags = self
+            .added_f
+            .iter()
+            .copied()
+            .chain(std::iter::repeat(0))
+            .take(self.added.len() / 6)
+            .chain(self.added6_f.iter().copied().chain(std::iter::repeat(0)));
+        v4.chain(v6)
+            .zip(flags)
+            .map(|(addr, flags)| PexPeer { addr, flags })
+            .collect()
+    }
+
+    /// All peers the sender disconnected from.
+    pub fn dropped(&self) -> Vec<SocketAddr> {
+        let v4 = self
+            .dropped
+            .chunks_exact(6)
+            .map(parse_v4)
+            .map(SocketAddr::V4);
+        let v6 = self
+            .dropped6
+            .chunks_exact(18)
+            .map(parse_v6)
+            .map(SocketAddr::V6);
+        v4.chain(v6).collect()
+    }
+}
+
+fn compact_v4(peer: SocketAddrV4) -> [u8; 6] {
+    let mut compact = [0; 6];
+    compact[..4].copy_from_slice(&peer.ip().octets());
+    compact[4..].copy_from_slice(&peer.port().to_be_bytes());
+    compact
+}
+
+fn parse_v4(slice_6: &[u8]) -> SocketAddrV4 {
+    SocketAddrV4::new(
+        Ipv4Addr::new(slice_6[0], slice_6[1], slice_6[2], slice_6[3]),
+        u16::from_be_bytes([slice_6[4], slice_6[5]]),
+    )
+}
+
+fn parse_v6(slice_18: &[u8]) -> SocketAddrV6 {
+    let ip: [u8; 16] = slice_18[..16]
+        .try_into()
+        .expect("guaranteed to be length 16");
+    SocketAddrV6::new(
+        Ipv6Addr::from(ip),
+        u16::from_be_bytes([slice_18[16], slice_18[17]]),
+        0,
+        0,
+    )
+}
+
+/// What we have told a single peer about our connected peers so far.
+#[derive(Debug, Default)]
+pub(crate) struct PexState {
+    sent: HashSet<SocketAddrV4>,
+    last: Option<Instant>,
+}
+
+impl PexState {
+    /// The message to send to bring the peer up to date with `connected`, if it is time to send
+    /// one and there is anything to tell.
+    pub(crate) fn delta(&mut self, connected: &HashSet<SocketAddrV4>) -> Option<PexMessage> {
+        if self.last.is_some_and(|last| last.elapsed() < INTERVAL) {
+            return None;
+        }
+        let added: Vec<_> = connected
+            .difference(&self.sent)
+            .copied()
+            .take(MAX_PEERS)
+            .collect();
+        let dropped: Vec<_> = self
+            .sent
+            .difference(connected)
+            .copied()
+            .take(MAX_PEERS)
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        // anything that didn't fit will go out in the next message
+        self.sent.extend(&added);
+        for peer in &dropped {
+            self.sent.remove(peer);
+        }
+        self.last = Some(Instant::now());
+        Some(PexMessage::new(&added, &dropped))
+    }
+}
+
+/// Peer exchange on a single connection.
+#[derive(Debug, Default)]
+pub(crate) struct Pex {
+    pub(crate) state: PexState,
+    /// Peers the peer told us about that we haven't passed on yet.
+    pub(crate) found: Vec<SocketAddrV4>,
+}
+
+impl Extension for Pex {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn on_message(&mut self, payload: &[u8]) {
+        let Ok(message) = serde_bencode::from_bytes::<PexMessage>(payload) else {
+            return;
+        };
+        let added = message
+            .added()
+            .into_iter()
+            .filter_map(|peer| match peer.addr {
+                SocketAddr::V4(addr) => Some(addr),
+                // we only speak IPv4
+                SocketAddr::V6(_) => None,
+            });
+        // don't let a single message flood us with peers
+        self.found.extend(added.take(MAX_PEERS));
+    }
+}
+
+#[test]
+fn pex_roundtrip() {
+    let a: SocketAddrV4 = "10.0.0.1:6881".parse().unwrap();
+    let b: SocketAddrV4 = "10.0.0.2:51413".parse().unwrap();
+    let message = PexMessage::new(&[a, b], &[a]);
+    let encoded = serde_bencode::to_bytes(&message).unwrap();
+    let decoded: PexMessage = serde_bencode::from_bytes(&encoded).unwrap();
+    assert_eq!(decoded, message);
+    assert_eq!(
+        decoded.added(),
+        vec![
+            PexPeer {
+                addr: SocketAddr::V4(a),
+                flags: 0
+            },
+            PexPeer {
+                addr: SocketAddr::V4(b),
+                flags: 0
+            }
+        ]
+    );
+    assert_eq!(decoded.dropped(), vec![SocketAddr::V4(a)]);
+
+    // keys can be missing, and IPv6 peers carry their own flags
+    let mut encoded = b"d6:added618:".to_vec();
+    encoded.extend([0; 15]);
+    encoded.extend([1, 0x1a, 0xe1]);
+    encoded.extend(b"8:added6.f1:\x02e");
+    let decoded: PexMessage = serde_bencode::from_bytes(&encoded).unwrap();
+    assert_eq!(
+        decoded.added(),
+        vec![PexPeer {
+            addr: "[::1]:6881".parse().unwrap(),
+            flags: flags::SEED
+        }]
+    );
+}
+
+#[test]
+fn pex_delta_rate_limit() {
+    let mut state = PexState::default();
+    let connected: HashSet<SocketAddrV4> = (1..=60)
+        .map(|port| SocketAddrV4::new(Ipv4Addr::LOCALHOST, port))
+        .collect();
+    let first = state.delta(&connected).unwrap();
+    assert_eq!(first.added.len(), 6 * MAX_PEERS);
+    // too soon for another message, even though there is more to tell
+    assert!(state.delta(&connected).is_none());
+    state.last = Some(Instant::now() - INTERVAL);
+    let second = state.delta(&HashSet::new()).unwrap();
+    assert!(second.added.is_empty());
+    assert_eq!(second.dropped.len(), 6 * MAX_PEERS);
+}
@@ -1,8 +1,16 @@
 use crate::{peer::Peer, torrent::Torrent};
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use tokio::sync::Notify;
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Piece {
+    /// Whether any of the peers suggested this piece to us.
+    suggested: bool,
+    /// How many of the peers have the piece.
+    availability: usize,
+    /// Picks between pieces that are just as rare, so that we don't all go for the same one.
+    tiebreak: u32,
     peers: HashSet<usize>,
     piece_i: usize,
     length: usize,
@@ -11,11 +19,11 @@ pub struct Piece {
 
 impl Ord for Piece {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.peers
-            .len()
-            .cmp(&other.peers.len())
-            // tie-break by _random_ ordering of HashSet to avoid deterministic contention
-            .then(self.peers.iter().cmp(other.peers.iter()))
+        // the greatest piece is downloaded first: rarest first, unless a peer suggested one
+        self.suggested
+            .cmp(&other.suggested)
+            .then(other.availability.cmp(&self.availability))
+            .then(self.tiebreak.cmp(&other.tiebreak))
             .then(self.hash.cmp(&other.hash))
             .then(self.length.cmp(&other.length))
             .then(self.piece_i.cmp(&other.piece_i))
@@ -29,7 +37,12 @@ impl PartialOrd for Piece {
 }
 
 impl Piece {
-    pub(crate) fn new(piece_i: usize, t: &Torrent, peers: &[Peer]) -> Self {
+    pub(crate) fn new(
+        piece_i: usize,
+        t: &Torrent,
+        peers: &[Peer],
+        availability: &Availability,
+    ) -> Self {
         let piece_hash = t.info.pieces.0[piece_i];
         let piece_size = if piece_i == t.info.pieces.0.len() - 1 {
             let md = t.length() % t.info.plength;
@@ -42,6 +55,7 @@ impl Piece {
             t.info.plength
         };
 
+        let suggested = peers.iter().any(|peer| peer.suggests(piece_i));
         let peers = peers
             .iter()
             .enumerate()
@@ -49,6 +63,9 @@ impl Piece {
             .collect();
 
         Self {
+            suggested,
+            availability: availability.count(piece_i),
+            tiebreak: rand::random(),
             peers,
             piece_i,
             length: piece_size,
@@ -71,4 +88,145 @@ impl Piece {
     pub(crate) fn length(&self) -> usize {
         self.length
     }
-}
\ No newline at end of file
+}
+
+/// The blocks of the piece being downloaded that no peer is working on.
+///
+/// Peers take blocks from here to request them, and put back the ones they don't deliver after
+/// all. Waiting for a block takes nothing, so a peer's participation in the piece can be dropped
+/// at any point without losing track of blocks.
+#[derive(Debug)]
+pub(crate) struct Blocks {
+    queue: Mutex<VecDeque<usize>>,
+    returned: Notify,
+}
+
+impl Blocks {
+    /// All `nblocks` blocks of a piece, none of which are taken yet.
+    pub(crate) fn new(nblocks: usize) -> Self {
+        Self {
+            queue: Mutex::new((0..nblocks).collect()),
+            returned: Notify::new(),
+        }
+    }
+
+    pub(crate) fn take(&self) -> Option<usize> {
+        self.lock().pop_front()
+    }
+
+    /// Hand `block` to whichever peer gets to it first.
+    pub(crate) fn put(&self, block: usize) {
+        self.lock().push_back(block);
+        self.returned.notify_waiters();
+    }
+
+    /// Wait until there is a block to take.
+    pub(crate) async fn available(&self) {
+        loop {
+            // set up before looking, so that a block put back in between isn't missed
+            let returned = self.returned.notified();
+            if !self.lock().is_empty() {
+                return;
+            }
+            returned.await;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<usize>> {
+        self.queue.lock().expect("no panics while holding the lock")
+    }
+}
+
+/// How many of our peers have each piece of the torrent.
+#[derive(Debug)]
+pub(crate) struct Availability {
+    counts: Vec<usize>,
+}
+
+impl Availability {
+    pub(crate) fn new(npieces: usize) -> Self {
+        Self {
+            counts: vec![0; npieces],
+        }
+    }
+
+    pub(crate) fn count(&self, piece_i: usize) -> usize {
+        self.counts[piece_i]
+    }
+
+    /// Count a peer that joined with, or newly announced, `pieces`.
+    pub(crate) fn add(&mut self, pieces: impl IntoIterator<Item = usize>) {
+        for piece_i in pieces {
+            self.counts[piece_i] += 1;
+        }
+    }
+
+    /// Stop counting a peer that had `pieces`.
+    pub(crate) fn remove(&mut self, pieces: impl IntoIterator<Item = usize>) {
+        for piece_i in pieces {
+            self.counts[piece_i] -= 1;
+        }
+    }
+}
+
+#[test]
+fn availability_counts() {
+    let mut availability = Availability::new(4);
+    availability.add([0, 1]);
+    availability.add([1, 3]);
+    availability.add([2]);
+    assert_eq!(
+        (0..4).map(|i| availability.count(i)).collect::<Vec<_>>(),
+        vec![1, 2, 1, 1]
+    );
+    availability.remove([1, 3]);
+    assert_eq!(availability.count(1), 1);
+    assert_eq!(availability.count(3), 0);
+}
+
+#[tokio::test]
+async fn blocks_handed_back() {
+    let blocks = Blocks::new(2);
+    assert_eq!(blocks.take(), Some(0));
+    assert_eq!(blocks.take(), Some(1));
+    assert_eq!(blocks.take(), None);
+
+    let waiting = blocks.available();
+    tokio::pin!(waiting);
+    assert!(futures_util::poll!(waiting.as_mut()).is_pending());
+    blocks.put(1);
+    waiting.await;
+    assert_eq!(blocks.take(), Some(1));
+}
+
+#[test]
+fn rarest_first() {
+    use std::collections::BinaryHeap;
+
+    let piece = |piece_i: usize, availability: usize, suggested: bool| Piece {
+        suggested,
+        availability,
+        tiebreak: rand::random(),
+        peers: HashSet::new(),
+        piece_i,
+        length: 1,
+        hash: [0; 20],
+    };
+    let mut pieces = BinaryHeap::from([
+        piece(0, 3, false),
+        piece(1, 1, false),
+        piece(2, 5, true),
+        piece(3, 2, false),
+    ]);
+    let order: Vec<_> = std::iter::from_fn(|| pieces.pop().map(|piece| piece.index())).collect();
+    assert_eq!(order, vec![2, 1, 3, 0]);
+
+    // pieces that are just as rare come out in any order
+    let firsts: HashSet<_> = (0..100)
+        .map(|_| {
+            let mut pieces = BinaryHeap::from([piece(0, 1, false), piece(1, 1, false)]);
+            pieces.pop().unwrap().index()
+        })
+        .collect();
+    assert_eq!(firsts.len(), 2);
+}
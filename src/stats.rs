@@ -0,0 +1,263 @@
+//! What we know about each of the peers we're connected to, for anyone keeping an eye on a
+//! download.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Rates are averaged over this many seconds.
+const RATE_WINDOW: usize = 10;
+
+/// A snapshot of a peer connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStats {
+    pub addr: SocketAddrV4,
+    /// The client the peer runs, as far as its peer id tells.
+    pub client: String,
+    /// How long we've been connected.
+    pub age: Duration,
+    /// Bytes of piece data received from the peer.
+    pub downloaded: u64,
+    /// Bytes of piece data sent to the peer.
+    pub uploaded: u64,
+    pub blocks_downloaded: u64,
+    pub blocks_uploaded: u64,
+    /// Bytes per second received from the peer lately.
+    pub download_rate: f64,
+    /// Bytes per second sent to the peer lately.
+    pub upload_rate: f64,
+    /// How long the peer takes to answer a request that doesn't wait behind our others, smoothed,
+    /// once it has answered one.
+    pub rtt: Option<Duration>,
+    /// Whether the peer is choking us.
+    pub choked: bool,
+    /// Whether we told the peer we want to download from it.
+    pub interesting: bool,
+    /// Whether we are choking the peer.
+    pub choking: bool,
+    /// Whether the peer wants to download from us.
+    pub interested: bool,
+}
+
+/// A transfer rate, over the last few seconds.
+#[derive(Debug)]
+pub(crate) struct Rate {
+    /// Bytes transferred in each of the last few seconds, latest last.
+    buckets: VecDeque<u64>,
+    /// When the latest bucket started.
+    latest: Instant,
+    /// When we started counting, so that a young connection isn't averaged over time it
+    /// wasn't around for.
+    started: Instant,
+}
+
+impl Rate {
+    pub(crate) fn new(now: Instant) -> Self {
+        Self {
+            buckets: VecDeque::from([0]),
+            latest: now,
+            started: now,
+        }
+    }
+
+    pub(crate) fn add(&mut self, bytes: u64, now: Instant) {
+        let elapsed = now.duration_since(self.latest).as_secs();
+        for _ in 0..elapsed.min(RATE_WINDOW as u64) {
+            if self.buckets.len() == RATE_WINDOW {
+                self.buckets.pop_front();
+            }
+            self.buckets.push_back(0);
+        }
+        self.latest += Duration::from_secs(elapsed);
+        *self.buckets.back_mut().expect("never empty") += bytes;
+    }
+
+    /// Bytes per second.
+    pub(crate) fn get(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.latest);
+        // buckets that have fallen out of the window since we last added to them don't count
+        let total: u64 = self
+            .buckets
+            .iter()
+            .rev()
+            .take(RATE_WINDOW.saturating_sub(elapsed.as_secs() as usize))
+            .sum();
+        // the window ends with the second we're in, however far into it we are
+        let window = Duration::from_secs(RATE_WINDOW as u64 - 1)
+            + Duration::from_nanos(elapsed.subsec_nanos().into());
+        let span = now
+            .duration_since(self.started)
+            .min(window)
+            .max(Duration::from_secs(1));
+        total as f64 / span.as_secs_f64()
+    }
+}
+
+/// Two-letter client codes of the "-XX1234-" peer id style, for the clients one meets most.
+const CLIENTS: &[(&[u8; 2], &str)] = &[
+    (b"AZ", "Vuze"),
+    (b"BC", "BitComet"),
+    (b"BI", "BiglyBT"),
+    (b"BT", "BitTorrent"),
+    (b"DE", "Deluge"),
+    (b"FD", "Free Download Manager"),
+    (b"KT", "KTorrent"),
+    (b"LT", "libtorrent"),
+    (b"lt", "rTorrent"),
+    (b"qB", "qBittorrent"),
+    (b"RB", "bittorrent"),
+    (b"TR", "Transmission"),
+    (b"TX", "Tixati"),
+    (b"UM", "µTorrent Mac"),
+    (b"UT", "µTorrent"),
+    (b"WW", "WebTorrent"),
+];
+
+/// Make out which client sent `peer_id`, from the conventions most clients follow for its
+/// first few bytes.
+pub fn client_name(peer_id: &[u8; 20]) -> String {
+    // "-XX1234-": a client code and four version characters
+    if peer_id[0] == b'-'
+        && peer_id[7] == b'-'
+        && peer_id[1..7].iter().all(u8::is_ascii_alphanumeric)
+    {
+        let code: &[u8; 2] = peer_id[1..3].try_into().expect("two bytes");
+        let name = CLIENTS
+            .iter()
+            .find(|(known, _)| *known == code)
+            .map_or_else(
+                || String::from_utf8_lossy(code).into_owned(),
+                |(_, name)| name.to_string(),
+            );
+        let mut version: Vec<_> = peer_id[3..7].iter().map(|&c| c as char).collect();
+        while version.len() > 2 && version.last() == Some(&'0') {
+            version.pop();
+        }
+        let version: Vec<_> = version.iter().map(char::to_string).collect();
+        return format!("{name} {}", version.join("."));
+    }
+    // "M7-4-3--": mainline's own style, a letter and a version in dashed digits
+    if peer_id[0] == b'M' {
+        let version: Vec<_> = peer_id[1..8]
+            .split(|&c| c == b'-')
+            .take_while(|part| !part.is_empty())
+            .collect();
+        if !version.is_empty()
+            && version
+                .iter()
+                .all(|part| part.iter().all(u8::is_ascii_digit))
+        {
+            let version: Vec<_> = version
+                .iter()
+                .map(|part| String::from_utf8_lossy(part))
+                .collect();
+            return format!("BitTorrent {}", version.join("."));
+        }
+    }
+    String::from("unknown")
+}
+
+/// Lay out `peers` as a table, one per line, with a header.
+pub fn table(peers: &[PeerStats]) -> String {
+    let mut table = format!(
+        "{:<21} {:<20} {:>7} {:>10} {:>10} {:>10} {:>10} {:>7} {:>6} {:>6}\n",
+        "peer", "client", "age", "down", "up", "down/s", "up/s", "rtt", "us", "them"
+    );
+    for peer in peers {
+        let rtt = match peer.rtt {
+            Some(rtt) => format!("{}ms", rtt.as_millis()),
+            None => String::from("-"),
+        };
+        let client: String = peer.client.chars().take(20).collect();
+        writeln!(
+            table,
+            "{:<21} {:<20} {:>6}s {:>10} {:>10} {:>10} {:>10} {:>7} {:>6} {:>6}",
+            peer.addr.to_string(),
+            client,
+            peer.age.as_secs(),
+            bytes(peer.downloaded as f64),
+            bytes(peer.uploaded as f64),
+            bytes(peer.download_rate),
+            bytes(peer.upload_rate),
+            rtt,
+            states(peer.interesting, peer.choking),
+            states(peer.interested, peer.choked),
+        )
+        .expect("writing to a String doesn't fail");
+    }
+    table
+}
+
+/// A side's interest, and whether it chokes the other side: "I" and "C" when it does, lower
+/// case when it doesn't.
+fn states(interested: bool, choking: bool) -> String {
+    format!(
+        "{}{}",
+        if interested { 'I' } else { 'i' },
+        if choking { 'C' } else { 'c' }
+    )
+}
+
+fn bytes(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{value:.0}{}", UNITS[unit])
+    } else {
+        format!("{value:.1}{}", UNITS[unit])
+    }
+}
+
+#[test]
+fn rate_window() {
+    let start = Instant::now();
+    let mut rate = Rate::new(start);
+    assert_eq!(rate.get(start), 0.0);
+
+    // a young connection is averaged over the time it's been around
+    rate.add(1000, start);
+    rate.add(1000, start + Duration::from_millis(1500));
+    assert_eq!(rate.get(start + Duration::from_secs(2)), 1000.0);
+
+    // but no further back than the window
+    for second in 2..30 {
+        rate.add(500, start + Duration::from_secs(second));
+    }
+    let now = start + Duration::from_secs(30);
+    assert_eq!(rate.get(now), 500.0);
+
+    // and what falls out of the window no longer counts, even if nothing was added since
+    let later = now + Duration::from_secs(5);
+    assert_eq!(rate.get(later), 2000.0 / 9.0);
+    assert_eq!(rate.get(now + Duration::from_secs(60)), 0.0);
+    rate.add(100, now + Duration::from_secs(60));
+    assert_eq!(rate.get(now + Duration::from_secs(60)), 100.0 / 9.0);
+}
+
+#[test]
+fn client_names() {
+    let named = |prefix: &[u8]| {
+        let mut peer_id = [b'x'; 20];
+        peer_id[..prefix.len()].copy_from_slice(prefix);
+        client_name(&peer_id)
+    };
+    assert_eq!(named(b"-qB4250-"), "qBittorrent 4.2.5");
+    assert_eq!(named(b"-TR3000-"), "Transmission 3.0");
+    assert_eq!(named(b"-UT355W-"), "µTorrent 3.5.5.W");
+    assert_eq!(named(b"-ZZ1234-"), "ZZ 1.2.3.4");
+    assert_eq!(
+        client_name(&crate::peer::new_peer_id()),
+        "bittorrent 0.0.0.1"
+    );
+    assert_eq!(named(b"M7-4-3--"), "BitTorrent 7.4.3");
+    assert_eq!(named(b"M10-2-3-"), "BitTorrent 10.2.3");
+    assert_eq!(named(b"-qB4250x"), "unknown");
+    assert_eq!(named(&[0; 8]), "unknown");
+}
@@ -1,11 +1,13 @@
 use super::{hashes::Hashes};
 
-use crate::download::Downloaded;
+use crate::discovery::{PeerSource, StaticPeers, TrackerSource};
+use crate::download::{DownloadConfig, Downloaded};
 
 use super::download;
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use std::net::SocketAddrV4;
 use std::path::Path;
 
 /// Metainfo files (also known as .torrent files) 
@@ -72,10 +74,7 @@ impl Torrent {
             serde_bencode::to_bytes(&self.info).expect("re-encode info section should be fine");
         let mut hasher = Sha1::new();
         hasher.update(&info_encoded);
-        hasher
-            .finalize()
-            .try_into()
-            .expect("GenericArray<_, 20> == [_; 20]")
+        hasher.finalize().into()
     }
 
     pub async fn read(file: impl AsRef<Path>) -> anyhow::Result<Self> {
@@ -104,7 +103,45 @@ impl Torrent {
         }
     }
 
+    /// Download the torrent using peers from its `announce` tracker.
     pub async fn download_all(&self) -> anyhow::Result<Downloaded> {
-        download::all(self).await
+        self.download_all_from(vec![Box::new(TrackerSource::new(self))])
+            .await
+    }
+
+    /// Download the torrent from only the given peers, without asking the tracker for more.
+    pub async fn download_all_from_peers(
+        &self,
+        peers: Vec<SocketAddrV4>,
+    ) -> anyhow::Result<Downloaded> {
+        self.download_all_from(vec![Box::new(StaticPeers(peers))])
+            .await
+    }
+
+    /// Download the torrent using peers from the given sources.
+    pub async fn download_all_from(
+        &self,
+        sources: Vec<Box<dyn PeerSource>>,
+    ) -> anyhow::Result<Downloaded> {
+        self.download_with(sources, DownloadConfig::default()).await
+    }
+
+    /// Download the torrent using peers from the given sources, with non-default settings.
+    pub async fn download_with(
+        &self,
+        sources: Vec<Box<dyn PeerSource>>,
+        config: DownloadConfig,
+    ) -> anyhow::Result<Downloaded> {
+        download::all(self, sources, config).await
+    }
+
+    /// Download only piece `piece_i` of the torrent, using peers from the given sources.
+    pub async fn download_piece_with(
+        &self,
+        piece_i: usize,
+        sources: Vec<Box<dyn PeerSource>>,
+        config: DownloadConfig,
+    ) -> anyhow::Result<Vec<u8>> {
+        download::piece(self, piece_i, sources, config).await
     }
 }
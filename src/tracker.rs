@@ -2,7 +2,12 @@ use crate::torrent::Torrent;
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
 
+pub mod client;
+pub mod server;
+
+pub use client::{TrackerClient, TrackerClientConfig};
 pub use peers::Peers;
+pub use scrape::{ScrapeFiles, ScrapeStats};
 
 /// Note: the info hash field is _not_ included.
 #[derive(Debug, Clone, Serialize)]
@@ -29,15 +34,56 @@ pub struct TrackerRequest {
     /// The compact representation is more commonly used in the wild, the non-compact
     /// representation is mostly supported for backward-compatibility.
     pub compact: u8,
+
+    /// Omit peer ids from a non-compact peer list. Ignored if `compact` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_peer_id: Option<u8>,
+
+    /// If not present, this is one of the announcements done at regular intervals.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+
+    /// The true IP address (or DNS name) of the client, if it differs from the one the request
+    /// comes from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+
+    /// The number of peers that the client would like to receive from the tracker.
+    ///
+    /// Trackers default to around 50 if this is left out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numwant: Option<usize>,
+
+    /// A value that is not shared with any other peers, used to prove our identity to the tracker
+    /// should our IP address change.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+
+    /// The `tracker id` the tracker gave us in a previous announce, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trackerid: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    /// The first request to the tracker.
+    Started,
+    /// Sent when the download completes (but not if it was already complete when started).
+    Completed,
+    /// Sent when the client is shutting down gracefully.
+    Stopped,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct TrackerResponse {
     /// An integer, indicating how often your client should make a request to the tracker in seconds.
-    ///
-    /// You can ignore this value for the purposes of this challenge.
     pub interval: usize,
 
+    /// An identifier the client should send back in its next announcements.
+    #[serde(rename = "tracker id")]
+    pub tracker_id: Option<String>,
+
     /// A string, which contains list of peers that your client can connect to.
     ///
     /// Each peer is represented using 6 bytes. The first 4 bytes are the peer's IP address and the
@@ -46,38 +92,436 @@ pub struct TrackerResponse {
 }
 
 impl TrackerResponse {
-    pub(crate) async fn query(t: &Torrent, info_hash: [u8; 20]) -> anyhow::Result<Self> {
-        let request = TrackerRequest {
+    /// Do a single regular announce for `t` with the default configuration.
+    pub async fn query(t: &Torrent, info_hash: [u8; 20]) -> anyhow::Result<Self> {
+        Self::query_with(&TrackerClient::default(), t, info_hash).await
+    }
+
+    /// Like [`TrackerResponse::query`], but sending the request with `client`.
+    pub async fn query_with(
+        client: &TrackerClient,
+        t: &Torrent,
+        info_hash: [u8; 20],
+    ) -> anyhow::Result<Self> {
+        Announcer::new(&t.announce, info_hash, AnnounceConfig::default())
+            .with_client(client.clone())
+            .announce(None, 0, 0, t.length())
+            .await
+    }
+}
+
+/// Announce settings that stay the same for a whole session.
+#[derive(Debug, Clone)]
+pub struct AnnounceConfig {
+    /// A unique identifier for your client.
+    pub peer_id: String,
+
+    /// The port your client is listening on.
+    pub port: u16,
+
+    /// See [`TrackerRequest::ip`].
+    pub ip: Option<String>,
+
+    /// See [`TrackerRequest::numwant`].
+    pub numwant: Option<usize>,
+
+    /// See [`TrackerRequest::key`].
+    pub key: Option<String>,
+
+    /// Ask for the compact peer list representation.
+    pub compact: bool,
+
+    /// See [`TrackerRequest::no_peer_id`].
+    pub no_peer_id: bool,
+}
+
+impl Default for AnnounceConfig {
+    fn default() -> Self {
+        Self {
             peer_id: String::from("00112233445566778899"),
             port: 6881,
-            uploaded: 0,
-            downloaded: 0,
-            left: t.length(),
-            compact: 1,
-        };
+            ip: None,
+            numwant: None,
+            key: None,
+            compact: true,
+            no_peer_id: false,
+        }
+    }
+}
+
+/// A series of announces of one torrent to one tracker.
+///
+/// Remembers the `tracker id` the tracker hands out, and echoes it back in later announces.
+#[derive(Debug, Clone)]
+pub struct Announcer {
+    announce: String,
+    info_hash: [u8; 20],
+    config: AnnounceConfig,
+    client: TrackerClient,
+    tracker_id: Option<String>,
+}
 
+impl Announcer {
+    pub fn new(announce: impl Into<String>, info_hash: [u8; 20], config: AnnounceConfig) -> Self {
+        Self {
+            announce: announce.into(),
+            info_hash,
+            config,
+            client: TrackerClient::default(),
+            tracker_id: None,
+        }
+    }
+
+    /// Send announces with `client` rather than the shared default client.
+    pub fn with_client(mut self, client: TrackerClient) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub fn announce_url(&self) -> &str {
+        &self.announce
+    }
+
+    pub fn tracker_id(&self) -> Option<&str> {
+        self.tracker_id.as_deref()
+    }
+
+    pub fn request(
+        &self,
+        event: Option<Event>,
+        uploaded: usize,
+        downloaded: usize,
+        left: usize,
+    ) -> TrackerRequest {
+        TrackerRequest {
+            peer_id: self.config.peer_id.clone(),
+            port: self.config.port,
+            uploaded,
+            downloaded,
+            left,
+            compact: u8::from(self.config.compact),
+            no_peer_id: self.config.no_peer_id.then_some(1),
+            event,
+            ip: self.config.ip.clone(),
+            numwant: self.config.numwant,
+            key: self.config.key.clone(),
+            trackerid: self.tracker_id.clone(),
+        }
+    }
+
+    pub async fn announce(
+        &mut self,
+        event: Option<Event>,
+        uploaded: usize,
+        downloaded: usize,
+        left: usize,
+    ) -> anyhow::Result<TrackerResponse> {
+        let request = self.request(event, uploaded, downloaded, left);
         let url_params =
             serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
-        let tracker_url = format!(
-            "{}?{}&info_hash={}",
-            t.announce,
-            url_params,
-            &urlencode(&info_hash)
-        );
-        let response = reqwest::get(tracker_url).await.context("query tracker")?;
-        let response = response.bytes().await.context("fetch tracker response")?;
+        let url_params = format!("{url_params}&info_hash={}", urlencode(&self.info_hash));
+        let response = self.client.get(&self.announce, &url_params).await?;
         let tracker_info: TrackerResponse =
             serde_bencode::from_bytes(&response).context("parse tracker response")?;
+        if let Some(tracker_id) = &tracker_info.tracker_id {
+            self.tracker_id = Some(tracker_id.clone());
+        }
         Ok(tracker_info)
     }
 }
 
+/// An HTTP tracker that answers every request with `body`, and hands out the query string of
+/// each request it gets.
+#[cfg(test)]
+pub(crate) async fn fake_tracker(
+    body: &'static [u8],
+) -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/announce", listener.local_addr().unwrap());
+    let (queries, received) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                if stream.read(&mut byte).await.unwrap() == 0 {
+                    break;
+                }
+                request.push(byte[0]);
+            }
+            let request = String::from_utf8_lossy(&request);
+            let target = request.split(' ').nth(1).unwrap_or_default();
+            let query = target.split_once('?').map_or("", |(_, query)| query);
+            let _ = queries.send(query.to_string());
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+        }
+    });
+    (url, received)
+}
+
+#[tokio::test]
+async fn announce_parameters() {
+    let (url, mut queries) = fake_tracker(b"d8:intervali900e5:peers0:10:tracker id3:xyze").await;
+    let mut announcer = Announcer::new(
+        url,
+        [0; 20],
+        AnnounceConfig {
+            numwant: Some(200),
+            key: Some(String::from("abcd")),
+            ..Default::default()
+        },
+    );
+    announcer
+        .announce(Some(Event::Started), 1, 2, 3)
+        .await
+        .unwrap();
+    assert_eq!(
+        queries.recv().await.unwrap(),
+        format!(
+            "peer_id=00112233445566778899&port=6881&uploaded=1&downloaded=2&left=3&compact=1\
+             &event=started&numwant=200&key=abcd&info_hash={}",
+            urlencode(&[0; 20])
+        )
+    );
+    assert_eq!(announcer.tracker_id(), Some("xyz"));
+
+    // the tracker id from the response is echoed back from then on
+    announcer.announce(None, 0, 0, 0).await.unwrap();
+    assert!(queries
+        .recv()
+        .await
+        .unwrap()
+        .contains("&key=abcd&trackerid=xyz&"));
+}
+
+/// Response to a scrape request (BEP 48).
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ScrapeResponse {
+    /// A dictionary containing one key/value pair for each torrent for which there are stats.
+    ///
+    /// The key is the 20-byte info hash, and the value holds the swarm counters for it.
+    pub files: ScrapeFiles,
+}
+
+impl ScrapeResponse {
+    /// Ask the tracker behind `announce` for the swarm counters of every torrent in `info_hashes`.
+    pub async fn query(announce: &str, info_hashes: &[[u8; 20]]) -> anyhow::Result<Self> {
+        Self::query_with(&TrackerClient::default(), announce, info_hashes).await
+    }
+
+    /// Like [`ScrapeResponse::query`], but sending the request with `client`.
+    pub async fn query_with(
+        client: &TrackerClient,
+        announce: &str,
+        info_hashes: &[[u8; 20]],
+    ) -> anyhow::Result<Self> {
+        let scrape_url = scrape_url(announce)
+            .with_context(|| format!("tracker {announce} does not support scrape"))?;
+
+        let response = client.get(&scrape_url, &scrape_params(info_hashes)).await?;
+        let scrape_info: ScrapeResponse =
+            serde_bencode::from_bytes(&response).context("parse scrape response")?;
+        Ok(scrape_info)
+    }
+}
+
+/// Derive the scrape URL from an announce URL.
+///
+/// By convention, if the text immediately following the last `/` in the announce URL is
+/// `announce`, it is replaced with `scrape` to get the scrape URL. Otherwise the tracker does not
+/// support scrape.
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let slash = announce.rfind('/')?;
+    let rest = announce[slash + 1..].strip_prefix("announce")?;
+    Some(format!("{}scrape{rest}", &announce[..slash + 1]))
+}
+
+/// The url-encoded query parameters of a scrape for `info_hashes`.
+fn scrape_params(info_hashes: &[[u8; 20]]) -> String {
+    info_hashes
+        .iter()
+        .map(|info_hash| format!("info_hash={}", urlencode(info_hash)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+#[test]
+fn scrape_url_convention() {
+    let cases = [
+        (
+            "http://example.com/announce",
+            Some("http://example.com/scrape"),
+        ),
+        (
+            "http://example.com/x/announce",
+            Some("http://example.com/x/scrape"),
+        ),
+        (
+            "http://example.com/announce.php",
+            Some("http://example.com/scrape.php"),
+        ),
+        (
+            "http://example.com/announce?x2%0644",
+            Some("http://example.com/scrape?x2%0644"),
+        ),
+        ("http://example.com/a", None),
+        ("http://example.com/announce?x=2/4", None),
+        ("http://example.com/x%064announce", None),
+    ];
+    for (announce, scrape) in cases {
+        assert_eq!(scrape_url(announce).as_deref(), scrape, "{announce}");
+    }
+
+    // the info hashes go after any query the scrape URL already has
+    let scrape = scrape_url("http://example.com/announce?x2%0644").unwrap();
+    let url = client::with_query(&scrape, &scrape_params(&[[0xaa; 20], [0x01; 20]])).unwrap();
+    assert_eq!(
+        url.as_str(),
+        format!(
+            "http://example.com/scrape?x2%0644&info_hash={}&info_hash={}",
+            "%aa".repeat(20),
+            "%01".repeat(20)
+        )
+    );
+}
+
+mod scrape {
+    use serde::de::{self, Deserialize, Deserializer, MapAccess, Visitor};
+    use serde::ser::{Serialize, SerializeMap, Serializer};
+    use std::collections::HashMap;
+    use std::fmt;
+
+    /// Swarm counters for a single torrent as reported by a scrape.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+    pub struct ScrapeStats {
+        /// The number of active peers that have completed downloading (seeders).
+        pub complete: usize,
+
+        /// The number of active peers that have not completed downloading (leechers).
+        pub incomplete: usize,
+
+        /// The number of peers that have ever completed downloading.
+        pub downloaded: usize,
+    }
+
+    #[derive(Debug, Clone, Default)]
+    pub struct ScrapeFiles(pub HashMap<[u8; 20], ScrapeStats>);
+    struct ScrapeFilesVisitor;
+
+    struct InfoHash([u8; 20]);
+    struct InfoHashVisitor;
+
+    impl<'de> Visitor<'de> for InfoHashVisitor {
+        type Value = InfoHash;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a 20-byte info hash")
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            let info_hash = v
+                .try_into()
+                .map_err(|_| E::custom(format!("length is {}", v.len())))?;
+            Ok(InfoHash(info_hash))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for InfoHash {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_bytes(InfoHashVisitor)
+        }
+    }
+
+    impl<'de> Visitor<'de> for ScrapeFilesVisitor {
+        type Value = ScrapeFiles;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a dictionary from 20-byte info hashes to swarm counters")
+        }
+
+        fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+        where
+            A: MapAccess<'de>,
+        {
+            let mut files = HashMap::with_capacity(map.size_hint().unwrap_or(0));
+            while let Some((InfoHash(info_hash), stats)) = map.next_entry()? {
+                files.insert(info_hash, stats);
+            }
+            Ok(ScrapeFiles(files))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for ScrapeFiles {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_map(ScrapeFilesVisitor)
+        }
+    }
+
+    impl Serialize for ScrapeFiles {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            let mut map = serializer.serialize_map(Some(self.0.len()))?;
+            for (info_hash, stats) in &self.0 {
+                map.serialize_entry(serde_bytes::Bytes::new(info_hash), stats)?;
+            }
+            map.end()
+        }
+    }
+}
+
+#[test]
+fn scrape_response_decode() {
+    let mut encoded = b"d5:filesd20:".to_vec();
+    encoded.extend([0xaa; 20]);
+    encoded.extend(b"d8:completei5e10:downloadedi50e10:incompletei10ee20:");
+    encoded.extend([0xbb; 20]);
+    encoded.extend(b"d8:completei0e10:downloadedi0e10:incompletei1e4:name3:fooeee");
+
+    let response: ScrapeResponse = serde_bencode::from_bytes(&encoded).unwrap();
+    assert_eq!(response.files.0.len(), 2);
+    assert_eq!(
+        response.files.0[&[0xaa; 20]],
+        ScrapeStats {
+            complete: 5,
+            incomplete: 10,
+            downloaded: 50
+        }
+    );
+    assert_eq!(response.files.0[&[0xbb; 20]].incomplete, 1);
+}
+
 mod peers {
-    use serde::de::{self, Deserialize, Deserializer, Visitor};
+    use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
     use serde::ser::{Serialize, Serializer};
     use std::fmt;
     use std::net::{Ipv4Addr, SocketAddrV4};
 
+    /// A peer in the non-compact representation; the peer id is not needed to connect.
+    #[derive(serde::Deserialize)]
+    struct PeerDict {
+        ip: String,
+        port: u16,
+    }
+
     #[derive(Debug, Clone)]
     pub struct Peers(pub Vec<SocketAddrV4>);
     struct PeersVisitor;
@@ -89,11 +533,26 @@ mod peers {
             formatter.write_str("6 bytes, the first 4 bytes are a peer's IP address and the last 2 are a peer's port number")
         }
 
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            // the non-compact representation is a list of dictionaries
+            let mut peers = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(PeerDict { ip, port }) = seq.next_element()? {
+                // hostnames and IPv6 addresses are allowed here, but we only speak IPv4
+                if let Ok(ip) = ip.parse::<Ipv4Addr>() {
+                    peers.push(SocketAddrV4::new(ip, port));
+                }
+            }
+            Ok(Peers(peers))
+        }
+
         fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
         where
             E: de::Error,
         {
-            if v.len() % 6 != 0 {
+            if !v.len().is_multiple_of(6) {
                 return Err(E::custom(format!("length is {}", v.len())));
             }
             // TODO: use array_chunks when stable; then we can also pattern-match in closure args
@@ -115,7 +574,7 @@ mod peers {
         where
             D: Deserializer<'de>,
         {
-            deserializer.deserialize_bytes(PeersVisitor)
+            deserializer.deserialize_any(PeersVisitor)
         }
     }
 
@@ -138,7 +597,7 @@ fn urlencode(t: &[u8; 20]) -> String {
     let mut encoded = String::with_capacity(3 * t.len());
     for &byte in t {
         encoded.push('%');
-        encoded.push_str(&hex::encode(&[byte]));
+        encoded.push_str(&hex::encode([byte]));
     }
     encoded
-}
\ No newline at end of file
+}
@@ -0,0 +1,124 @@
+use anyhow::Context;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Settings for the HTTP client used to talk to trackers.
+#[derive(Debug, Clone)]
+pub struct TrackerClientConfig {
+    /// Timeout for the whole request, from connecting until the body has been read.
+    pub timeout: Duration,
+
+    /// Timeout for only the connect phase.
+    pub connect_timeout: Duration,
+
+    /// The `User-Agent` header sent to trackers.
+    pub user_agent: String,
+
+    /// Send all tracker requests through this proxy.
+    ///
+    /// Supports `http://`, `https://`, `socks5://` and `socks5h://` URLs.
+    pub proxy: Option<String>,
+
+    /// Accept gzip-compressed responses.
+    pub gzip: bool,
+}
+
+impl Default for TrackerClientConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(10),
+            user_agent: format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            proxy: None,
+            gzip: true,
+        }
+    }
+}
+
+/// An HTTP client for tracker requests.
+///
+/// Cloning is cheap, and clones share the same connection pool.
+#[derive(Debug, Clone)]
+pub struct TrackerClient {
+    http: reqwest::Client,
+}
+
+impl Default for TrackerClient {
+    /// A client with the default configuration, shared across the whole process.
+    fn default() -> Self {
+        static SHARED: OnceLock<TrackerClient> = OnceLock::new();
+        SHARED
+            .get_or_init(|| {
+                TrackerClient::new(TrackerClientConfig::default())
+                    .expect("default tracker client configuration is valid")
+            })
+            .clone()
+    }
+}
+
+impl TrackerClient {
+    pub fn new(config: TrackerClientConfig) -> anyhow::Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .connect_timeout(config.connect_timeout)
+            .user_agent(config.user_agent)
+            .gzip(config.gzip);
+        if let Some(proxy) = &config.proxy {
+            let proxy =
+                reqwest::Proxy::all(proxy).with_context(|| format!("invalid proxy {proxy}"))?;
+            builder = builder.proxy(proxy);
+        }
+        let http = builder.build().context("build tracker http client")?;
+        Ok(Self { http })
+    }
+
+    /// GET `base` with the already url-encoded `params` added to its query string, and return
+    /// the response body.
+    pub async fn get(&self, base: &str, params: &str) -> anyhow::Result<bytes::Bytes> {
+        let url = with_query(base, params)?;
+        let response = self
+            .http
+            .get(url)
+            .send()
+            .await
+            .context("query tracker")?
+            .error_for_status()
+            .context("tracker responded with an error")?;
+        response.bytes().await.context("fetch tracker response")
+    }
+}
+
+/// Add url-encoded `params` to the URL `base`, keeping any query parameters it already has (such
+/// as private tracker passkeys).
+pub fn with_query(base: &str, params: &str) -> anyhow::Result<reqwest::Url> {
+    let mut url =
+        reqwest::Url::parse(base).with_context(|| format!("invalid tracker url {base}"))?;
+    let query = match url.query() {
+        Some(existing) if !existing.is_empty() => format!("{existing}&{params}"),
+        _ => params.to_string(),
+    };
+    url.set_query(Some(&query));
+    Ok(url)
+}
+
+#[test]
+fn query_merging() {
+    assert_eq!(
+        with_query("http://example.com/announce", "a=1&b=%ff")
+            .unwrap()
+            .as_str(),
+        "http://example.com/announce?a=1&b=%ff"
+    );
+    assert_eq!(
+        with_query("http://example.com/announce?passkey=abc", "a=1")
+            .unwrap()
+            .as_str(),
+        "http://example.com/announce?passkey=abc&a=1"
+    );
+    assert_eq!(
+        with_query("http://example.com/announce?", "a=1")
+            .unwrap()
+            .as_str(),
+        "http://example.com/announce?a=1"
+    );
+}
@@ -0,0 +1,440 @@
+use super::{Peers, ScrapeFiles, ScrapeResponse, ScrapeStats};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// An HTTP tracker that serves `/announce` and `/scrape`.
+///
+/// Peers are kept per info hash and forgotten if they have not announced for `peer_timeout`.
+/// Torrents left without peers are forgotten once a new one needs the room.
+pub struct Tracker {
+    /// How often (in seconds) peers are told to re-announce.
+    interval: u64,
+
+    /// How long a peer is kept in a swarm without re-announcing.
+    peer_timeout: Duration,
+
+    /// Maximum number of peers to return when the client doesn't ask for a specific number.
+    default_numwant: usize,
+
+    /// If set, only these info hashes are tracked; announces for any other are rejected.
+    allowlist: Option<HashSet<[u8; 20]>>,
+
+    /// Most torrents tracked at once; announces for more are rejected.
+    max_swarms: usize,
+
+    swarms: Mutex<HashMap<[u8; 20], Swarm>>,
+}
+
+#[derive(Debug, Default)]
+struct Swarm {
+    peers: HashMap<[u8; 20], SwarmPeer>,
+    /// Number of `completed` events we have seen for this torrent.
+    downloaded: usize,
+}
+
+#[derive(Debug)]
+struct SwarmPeer {
+    addr: SocketAddrV4,
+    left: usize,
+    last_seen: Instant,
+}
+
+impl Swarm {
+    fn expire(&mut self, peer_timeout: Duration) {
+        self.peers
+            .retain(|_, peer| peer.last_seen.elapsed() < peer_timeout);
+    }
+
+    fn stats(&self) -> ScrapeStats {
+        let complete = self.peers.values().filter(|peer| peer.left == 0).count();
+        ScrapeStats {
+            complete,
+            incomplete: self.peers.len() - complete,
+            downloaded: self.downloaded,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AnnounceResponse {
+    interval: u64,
+    complete: usize,
+    incomplete: usize,
+    peers: PeerList,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum PeerList {
+    Compact(Peers),
+    NonCompact(Vec<PeerDict>),
+}
+
+/// A peer in the non-compact (original BEP 3) peer list representation.
+#[derive(Debug, Serialize)]
+struct PeerDict {
+    #[serde(
+        rename = "peer id",
+        with = "serde_bytes",
+        skip_serializing_if = "Option::is_none"
+    )]
+    peer_id: Option<Vec<u8>>,
+    ip: String,
+    port: u16,
+}
+
+#[derive(Debug, Serialize)]
+struct Failure {
+    #[serde(rename = "failure reason")]
+    reason: String,
+}
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Self::new(1800)
+    }
+}
+
+impl Tracker {
+    pub fn new(interval: u64) -> Self {
+        Self {
+            interval,
+            // give peers a couple of missed announces before forgetting them
+            peer_timeout: Duration::from_secs(interval.saturating_mul(2).saturating_add(60)),
+            default_numwant: 50,
+            allowlist: None,
+            max_swarms: 10_000,
+            swarms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Only track the given info hashes.
+    pub fn allow(mut self, info_hashes: impl IntoIterator<Item = [u8; 20]>) -> Self {
+        self.allowlist
+            .get_or_insert_with(HashSet::new)
+            .extend(info_hashes);
+        self
+    }
+
+    pub fn peer_timeout(mut self, peer_timeout: Duration) -> Self {
+        self.peer_timeout = peer_timeout;
+        self
+    }
+
+    pub fn max_swarms(mut self, max_swarms: usize) -> Self {
+        self.max_swarms = max_swarms;
+        self
+    }
+
+    /// Serve announce and scrape requests on `addr` until the server fails.
+    pub async fn run(self, addr: SocketAddr) -> anyhow::Result<()> {
+        let tracker = Arc::new(self);
+        let make_svc = make_service_fn(move |conn: &hyper::server::conn::AddrStream| {
+            let tracker = Arc::clone(&tracker);
+            let remote = conn.remote_addr();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let tracker = Arc::clone(&tracker);
+                    async move { Ok::<_, Infallible>(tracker.handle(req, remote)) }
+                }))
+            }
+        });
+        hyper::Server::try_bind(&addr)?.serve(make_svc).await?;
+        Ok(())
+    }
+
+    fn handle(&self, req: Request<Body>, remote: SocketAddr) -> Response<Body> {
+        if req.method() != Method::GET {
+            return Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .body(Body::empty())
+                .expect("static response is valid");
+        }
+        let query = req.uri().query().unwrap_or("");
+        let body = match req.uri().path() {
+            "/announce" => self.announce(query, remote.ip()),
+            "/scrape" => self.scrape(query),
+            _ => {
+                return Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())
+                    .expect("static response is valid");
+            }
+        };
+        Response::builder()
+            .header("Content-Type", "text/plain")
+            .body(Body::from(body))
+            .expect("static response is valid")
+    }
+
+    /// Handle an announce with the given (still url-encoded) query string, returning the
+    /// bencoded response.
+    pub fn announce(&self, query: &str, remote: IpAddr) -> Vec<u8> {
+        match self.try_announce(query, remote) {
+            Ok(response) => response,
+            Err(e) => failure(e.to_string()),
+        }
+    }
+
+    fn try_announce(&self, query: &str, remote: IpAddr) -> anyhow::Result<Vec<u8>> {
+        let params = parse_query(query);
+        let param = |key: &str| {
+            params
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_slice())
+        };
+        let number = |key: &str| -> anyhow::Result<Option<usize>> {
+            param(key)
+                .map(|v| {
+                    std::str::from_utf8(v)
+                        .ok()
+                        .and_then(|v| v.parse().ok())
+                        .ok_or_else(|| anyhow::anyhow!("invalid {key}"))
+                })
+                .transpose()
+        };
+
+        let info_hash: [u8; 20] = param("info_hash")
+            .ok_or_else(|| anyhow::anyhow!("missing info_hash"))?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("invalid info_hash"))?;
+        let peer_id: [u8; 20] = param("peer_id")
+            .ok_or_else(|| anyhow::anyhow!("missing peer_id"))?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("invalid peer_id"))?;
+        let port: u16 = number("port")?
+            .ok_or_else(|| anyhow::anyhow!("missing port"))?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("invalid port"))?;
+        let left = number("left")?.ok_or_else(|| anyhow::anyhow!("missing left"))?;
+        let compact = number("compact")?.unwrap_or(0) == 1;
+        let no_peer_id = number("no_peer_id")?.unwrap_or(0) == 1;
+        let numwant = number("numwant")?.unwrap_or(self.default_numwant);
+        let event = param("event").unwrap_or(b"");
+        let ip = match param("ip") {
+            Some(ip) => std::str::from_utf8(ip)
+                .ok()
+                .and_then(|ip| ip.parse().ok())
+                .ok_or_else(|| anyhow::anyhow!("invalid ip"))?,
+            None => match remote {
+                IpAddr::V4(ip) => ip,
+                IpAddr::V6(ip) => ip
+                    .to_ipv4_mapped()
+                    .ok_or_else(|| anyhow::anyhow!("only IPv4 peers are supported"))?,
+            },
+        };
+
+        if let Some(allowlist) = &self.allowlist {
+            anyhow::ensure!(allowlist.contains(&info_hash), "torrent not tracked");
+        }
+
+        let mut swarms = self.swarms.lock().expect("tracker lock is never poisoned");
+        if !swarms.contains_key(&info_hash) {
+            // make room by forgetting the torrents nobody has announced in a while
+            swarms.retain(|_, swarm| {
+                swarm.expire(self.peer_timeout);
+                !swarm.peers.is_empty()
+            });
+            anyhow::ensure!(swarms.len() < self.max_swarms, "tracking too many torrents");
+        }
+        let swarm = swarms.entry(info_hash).or_default();
+        swarm.expire(self.peer_timeout);
+        match event {
+            b"stopped" => {
+                swarm.peers.remove(&peer_id);
+            }
+            _ => {
+                if event == b"completed" {
+                    swarm.downloaded += 1;
+                }
+                swarm.peers.insert(
+                    peer_id,
+                    SwarmPeer {
+                        addr: SocketAddrV4::new(ip, port),
+                        left,
+                        last_seen: Instant::now(),
+                    },
+                );
+            }
+        }
+
+        let others = swarm
+            .peers
+            .iter()
+            .filter(|&(id, _)| *id != peer_id)
+            .take(numwant);
+        let peers = if compact {
+            PeerList::Compact(Peers(others.map(|(_, peer)| peer.addr).collect()))
+        } else {
+            PeerList::NonCompact(
+                others
+                    .map(|(id, peer)| PeerDict {
+                        peer_id: (!no_peer_id).then(|| id.to_vec()),
+                        ip: peer.addr.ip().to_string(),
+                        port: peer.addr.port(),
+                    })
+                    .collect(),
+            )
+        };
+        let stats = swarm.stats();
+        let response = AnnounceResponse {
+            interval: self.interval,
+            complete: stats.complete,
+            incomplete: stats.incomplete,
+            peers,
+        };
+        Ok(serde_bencode::to_bytes(&response)?)
+    }
+
+    /// Handle a scrape with the given (still url-encoded) query string, returning the bencoded
+    /// response.
+    ///
+    /// A scrape without any `info_hash` returns every torrent we track.
+    pub fn scrape(&self, query: &str) -> Vec<u8> {
+        let info_hashes: Vec<[u8; 20]> = parse_query(query)
+            .into_iter()
+            .filter(|(k, _)| k == "info_hash")
+            .filter_map(|(_, v)| v.try_into().ok())
+            .collect();
+
+        let mut swarms = self.swarms.lock().expect("tracker lock is never poisoned");
+        let mut files = HashMap::new();
+        for (info_hash, swarm) in swarms.iter_mut() {
+            if !info_hashes.is_empty() && !info_hashes.contains(info_hash) {
+                continue;
+            }
+            swarm.expire(self.peer_timeout);
+            files.insert(*info_hash, swarm.stats());
+        }
+        serde_bencode::to_bytes(&ScrapeResponse {
+            files: ScrapeFiles(files),
+        })
+        .expect("scrape response is always encodable")
+    }
+}
+
+fn failure(reason: String) -> Vec<u8> {
+    serde_bencode::to_bytes(&Failure { reason }).expect("failure is always encodable")
+}
+
+/// Split a query string into its percent-decoded key/value pairs.
+///
+/// The values are kept as raw bytes since `info_hash` and `peer_id` need not be valid UTF-8.
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            let k = percent_encoding::percent_decode_str(k)
+                .decode_utf8_lossy()
+                .into_owned();
+            let v = percent_encoding::percent_decode_str(v).collect();
+            (k, v)
+        })
+        .collect()
+}
+
+#[test]
+fn announce_and_scrape() {
+    use super::urlencode;
+
+    let info_hash = [0xab; 20];
+    let tracker = Tracker::new(60).allow([info_hash]);
+    let announce = |peer_id: &[u8; 20], port: u16, left: usize, extra: &str| {
+        let query = format!(
+            "info_hash={}&peer_id={}&port={port}&uploaded=0&downloaded=0&left={left}{extra}",
+            urlencode(&info_hash),
+            urlencode(peer_id),
+        );
+        tracker.announce(&query, "127.0.0.1".parse().unwrap())
+    };
+
+    #[derive(serde::Deserialize)]
+    struct Compact {
+        complete: usize,
+        incomplete: usize,
+        peers: Peers,
+    }
+
+    announce(&[1; 20], 1000, 0, "&event=completed&compact=1");
+    let response: Compact =
+        serde_bencode::from_bytes(&announce(&[2; 20], 2000, 10, "&compact=1")).unwrap();
+    assert_eq!(response.complete, 1);
+    assert_eq!(response.incomplete, 1);
+    assert_eq!(
+        response.peers.0,
+        vec!["127.0.0.1:1000".parse::<SocketAddrV4>().unwrap()]
+    );
+
+    // non-compact lists decode to the same peers
+    let response: Compact = serde_bencode::from_bytes(&announce(&[2; 20], 2000, 10, "")).unwrap();
+    assert_eq!(response.peers.0.len(), 1);
+    assert_eq!(response.peers.0[0].port(), 1000);
+
+    let scrape: ScrapeResponse =
+        serde_bencode::from_bytes(&tracker.scrape(&format!("info_hash={}", urlencode(&info_hash))))
+            .unwrap();
+    assert_eq!(
+        scrape.files.0[&info_hash],
+        ScrapeStats {
+            complete: 1,
+            incomplete: 1,
+            downloaded: 1
+        }
+    );
+
+    announce(&[2; 20], 2000, 10, "&event=stopped");
+    let scrape: ScrapeResponse = serde_bencode::from_bytes(&tracker.scrape("")).unwrap();
+    assert_eq!(scrape.files.0[&info_hash].incomplete, 0);
+
+    // unknown torrents are refused
+    let query = format!(
+        "info_hash={}&peer_id={}&port=1&left=0",
+        urlencode(&[0; 20]),
+        urlencode(&[1; 20])
+    );
+    let failure = tracker.announce(&query, "127.0.0.1".parse().unwrap());
+    assert!(failure.starts_with(b"d14:failure reason"));
+}
+
+#[test]
+fn swarms_are_pruned_and_capped() {
+    use super::urlencode;
+
+    let tracker = Tracker::new(60)
+        .peer_timeout(Duration::from_millis(50))
+        .max_swarms(2);
+    let announce = |info_hash: &[u8; 20]| {
+        let query = format!(
+            "info_hash={}&peer_id={}&port=1&left=0",
+            urlencode(info_hash),
+            urlencode(&[1; 20])
+        );
+        tracker.announce(&query, "127.0.0.1".parse().unwrap())
+    };
+    let failed = |response: Vec<u8>| response.starts_with(b"d14:failure reason");
+
+    assert!(!failed(announce(&[1; 20])));
+    assert!(!failed(announce(&[2; 20])));
+    assert!(failed(announce(&[3; 20])));
+    // torrents already tracked are still announced to
+    assert!(!failed(announce(&[1; 20])));
+
+    // once their peers expire, the swarms make room for others
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(!failed(announce(&[3; 20])));
+    let scrape: ScrapeResponse = serde_bencode::from_bytes(&tracker.scrape("")).unwrap();
+    assert_eq!(scrape.files.0.len(), 1);
+    assert!(scrape.files.0.contains_key(&[3; 20]));
+
+    // and the interval can't overflow the peer timeout
+    let _ = Tracker::new(u64::MAX);
+}
@@ -0,0 +1,981 @@
+//! The Micro Transport Protocol (BEP 29): reliable, ordered streams over UDP, with congestion
+//! control that gets out of the way of other traffic.
+//!
+//! A [`UtpSocket`] carries any number of connections to and from other peers, each of which is
+//! a [`UtpStream`] that can be used wherever a `TcpStream` would be.
+
+mod ledbat;
+mod packet;
+
+use crate::download::AbortOnDrop;
+use anyhow::Context;
+use bytes::{Bytes, BytesMut};
+use ledbat::Ledbat;
+use packet::{Packet, PacketType};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context as TaskContext, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// The most payload we put in a packet, which keeps packets below the MTU of most paths,
+/// tunnels included.
+const MAX_PAYLOAD: usize = 1200;
+
+/// How much received data we hold on to for the stream to read.
+const RECEIVE_WINDOW: usize = 1 << 20;
+
+/// How much written data we hold on to until it's acknowledged.
+const SEND_BUFFER: usize = 1 << 20;
+
+/// How far ahead of the next packet in order we keep packets, and acknowledge them selectively.
+const MAX_OUT_OF_ORDER: u16 = 1024;
+
+/// How often connections check for packets that went unacknowledged for too long.
+const TICK: Duration = Duration::from_millis(50);
+
+/// How often a packet is sent before the peer is taken for gone.
+const MAX_TRANSMISSIONS: u32 = 6;
+
+/// How long we stay quiet before sending an ACK anyway, so that NATs along the way remember us.
+const KEEP_ALIVE: Duration = Duration::from_secs(29);
+
+/// How long a peer may stay silent before we give up on it.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many connections may wait to be accepted before new ones are refused.
+const BACKLOG: usize = 16;
+
+/// Connections by the address of the peer, and the connection id we receive with.
+type Connections = Arc<Mutex<HashMap<(SocketAddr, u16), Arc<Mutex<Connection>>>>>;
+
+/// A UDP socket that uTP connections are made and accepted on.
+///
+/// Cloning is cheap, and clones share the same socket. Connections outlive the socket handles,
+/// though accepting new ones stops once all handles are dropped.
+#[derive(Clone)]
+pub struct UtpSocket {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    socket: Arc<UdpSocket>,
+    connections: Connections,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<UtpStream>>,
+    _receive: AbortOnDrop<()>,
+}
+
+impl UtpSocket {
+    pub async fn bind(addr: SocketAddr) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(addr)
+            .await
+            .with_context(|| format!("bind uTP socket to {addr}"))?;
+        let socket = Arc::new(socket);
+        let connections = Connections::default();
+        let (incoming_tx, incoming) = mpsc::channel(BACKLOG);
+        let receive = tokio::spawn(receive(
+            Arc::clone(&socket),
+            Arc::clone(&connections),
+            incoming_tx,
+        ));
+        Ok(Self {
+            inner: Arc::new(Inner {
+                socket,
+                connections,
+                incoming: tokio::sync::Mutex::new(incoming),
+                _receive: AbortOnDrop(receive),
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        self.inner.socket.local_addr().context("get uTP address")
+    }
+
+    /// Open a connection to the peer at `addr`.
+    ///
+    /// This keeps trying for as long as the first packet keeps getting retransmitted, so callers
+    /// will usually want a timeout of their own.
+    pub async fn connect(&self, addr: SocketAddr) -> anyhow::Result<UtpStream> {
+        let stream = {
+            let mut connections = lock(&self.inner.connections);
+            // we receive on the id, and send on the one after it
+            let recv_id = loop {
+                let id: u16 = rand::random();
+                if !connections.contains_key(&(addr, id)) {
+                    break id;
+                }
+            };
+            let mut connection =
+                Connection::new(addr, recv_id, recv_id.wrapping_add(1), 1, Instant::now());
+            connection.push(Packet::new(PacketType::Syn, 0), Instant::now());
+            let connection = Arc::new(Mutex::new(connection));
+            connections.insert((addr, recv_id), Arc::clone(&connection));
+            UtpStream::start(connection, &self.inner.socket, &self.inner.connections)
+        };
+        stream.flush_outbox();
+        std::future::poll_fn(|cx| {
+            let mut connection = lock(&stream.connection);
+            if let Some(kind) = connection.error {
+                return Poll::Ready(Err(std::io::Error::from(kind)));
+            }
+            if connection.state == State::Connected {
+                return Poll::Ready(Ok(()));
+            }
+            connection.write_waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+        .with_context(|| format!("connect to {addr} over uTP"))?;
+        Ok(stream)
+    }
+
+    /// Wait for a peer to connect to us.
+    pub async fn accept(&self) -> anyhow::Result<(UtpStream, SocketAddr)> {
+        let stream = self
+            .inner
+            .incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .context("uTP socket closed")?;
+        let addr = stream.peer_addr();
+        Ok((stream, addr))
+    }
+}
+
+impl std::fmt::Debug for UtpSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UtpSocket")
+            .field("local_addr", &self.inner.socket.local_addr().ok())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Hands the packets that arrive on `socket` to their connections, and sets up the connections
+/// peers ask for.
+async fn receive(
+    socket: Arc<UdpSocket>,
+    connections: Connections,
+    incoming: mpsc::Sender<UtpStream>,
+) {
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let (n, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            // most likely an ICMP error for something we sent earlier
+            Err(_) => continue,
+        };
+        let Ok(packet) = Packet::decode(Bytes::copy_from_slice(&buf[..n])) else {
+            continue;
+        };
+        let now = Instant::now();
+        // a SYN carries the id the peer receives on, and we receive on the one after it
+        let recv_id = match packet.ty {
+            PacketType::Syn => packet.connection_id.wrapping_add(1),
+            _ => packet.connection_id,
+        };
+        let connection = lock(&connections).get(&(from, recv_id)).cloned();
+        if let Some(connection) = connection {
+            let mut connection = lock(&connection);
+            connection.on_packet(packet, now);
+            connection.flush_outbox(&socket);
+            continue;
+        }
+        if packet.ty != PacketType::Syn || incoming.capacity() == 0 {
+            // whatever it's about, the peer will find out by not hearing back
+            continue;
+        }
+
+        let mut connection =
+            Connection::new(from, recv_id, packet.connection_id, rand::random(), now);
+        connection.state = State::Connected;
+        connection.ack_nr = packet.seq_nr;
+        connection.on_packet(packet, now);
+        let connection = Arc::new(Mutex::new(connection));
+        lock(&connections).insert((from, recv_id), Arc::clone(&connection));
+        let stream = UtpStream::start(connection, &socket, &connections);
+        stream.flush_outbox();
+        // a full backlog was ruled out above, and a closed one means we're shutting down
+        let _ = incoming.try_send(stream);
+    }
+}
+
+/// A uTP connection to a peer.
+///
+/// Dropping it closes the connection gracefully: whatever was written is still delivered.
+pub struct UtpStream {
+    connection: Arc<Mutex<Connection>>,
+    socket: Arc<UdpSocket>,
+}
+
+impl UtpStream {
+    /// Start looking after `connection`, until it's done with.
+    fn start(
+        connection: Arc<Mutex<Connection>>,
+        socket: &Arc<UdpSocket>,
+        connections: &Connections,
+    ) -> Self {
+        tokio::spawn(tick(
+            Arc::clone(&connection),
+            Arc::clone(socket),
+            Arc::clone(connections),
+        ));
+        Self {
+            connection,
+            socket: Arc::clone(socket),
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        lock(&self.connection).addr
+    }
+
+    fn flush_outbox(&self) {
+        lock(&self.connection).flush_outbox(&self.socket);
+    }
+}
+
+impl std::fmt::Debug for UtpStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UtpStream")
+            .field("peer_addr", &self.peer_addr())
+            .finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let mut connection = lock(&self.connection);
+        if !connection.received.is_empty() {
+            let window_was_closed = connection.receive_window() < MAX_PAYLOAD;
+            let n = connection.received.len().min(buf.remaining());
+            buf.put_slice(&connection.received.split_to(n));
+            if window_was_closed && connection.receive_window() >= MAX_PAYLOAD {
+                // the peer holds off until it hears there's room again
+                let seq_nr = connection.seq_nr;
+                connection.queue(Packet::new(PacketType::State, seq_nr), Instant::now());
+                connection.flush_outbox(&self.socket);
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if connection.eof {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(kind) = connection.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        connection.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut connection = lock(&self.connection);
+        if let Some(kind) = connection.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if connection.closing {
+            return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+        }
+        let room =
+            SEND_BUFFER.saturating_sub(connection.send_buffer.len() + connection.unacked_bytes());
+        if room == 0 {
+            connection.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = room.min(buf.len());
+        connection.send_buffer.extend_from_slice(&buf[..n]);
+        connection.send(Instant::now());
+        connection.flush_outbox(&self.socket);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        // like TCP, written data is on its way once the connection has taken it
+        match lock(&self.connection).error {
+            Some(kind) => Poll::Ready(Err(kind.into())),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        let mut connection = lock(&self.connection);
+        if !connection.closing {
+            connection.closing = true;
+            connection.send(Instant::now());
+            connection.flush_outbox(&self.socket);
+        }
+        if connection.fin_acked {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(kind) = connection.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        connection.write_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut connection = lock(&self.connection);
+        connection.dropped = true;
+        connection.closing = true;
+        connection.received.clear();
+        connection.send(Instant::now());
+        connection.flush_outbox(&self.socket);
+    }
+}
+
+/// Retransmits what the peer doesn't acknowledge in time, and forgets the connection once it's
+/// done with.
+async fn tick(
+    connection: Arc<Mutex<Connection>>,
+    socket: Arc<UdpSocket>,
+    connections: Connections,
+) {
+    let mut interval = tokio::time::interval(TICK);
+    loop {
+        interval.tick().await;
+        let (key, done) = {
+            let mut connection = lock(&connection);
+            let now = Instant::now();
+            connection.on_tick(now);
+            connection.flush_outbox(&socket);
+            ((connection.addr, connection.recv_id), connection.done(now))
+        };
+        if done {
+            lock(&connections).remove(&key);
+            return;
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().expect("no panics while holding the lock")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// We sent a SYN, and are waiting for the peer to acknowledge it.
+    SynSent,
+    Connected,
+}
+
+/// A packet we sent, until the peer acknowledges it.
+#[derive(Debug)]
+struct Sent {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    acked: bool,
+    /// Whether we take it for lost, and have yet to send it again.
+    lost: bool,
+}
+
+/// The state of one connection, shared between its stream, the socket's receiving task, and
+/// its own ticking task.
+#[derive(Debug)]
+struct Connection {
+    state: State,
+    addr: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    /// The sequence number of the next packet we send.
+    seq_nr: u16,
+    /// The sequence number of the last packet we received in order.
+    ack_nr: u16,
+    /// What our timestamps count from.
+    epoch: Instant,
+    /// Packets we sent that the peer has yet to acknowledge, oldest first, along with any that
+    /// were acknowledged selectively after them.
+    unacked: VecDeque<Sent>,
+    /// Bytes sent that are neither acknowledged nor taken for lost.
+    in_flight: usize,
+    /// Data written that has yet to go out.
+    send_buffer: BytesMut,
+    ledbat: Ledbat,
+    /// How many more bytes the peer says it will take.
+    peer_window: usize,
+    /// How often in a row the peer acknowledged the same packet while others were in flight.
+    duplicate_acks: u32,
+    /// Data received in order that has yet to be read.
+    received: BytesMut,
+    /// Packets received after a gap, by sequence number.
+    out_of_order: HashMap<u16, Bytes>,
+    /// The sequence number of the peer's FIN, once it arrived.
+    fin_received: Option<u16>,
+    /// Whether everything up to the peer's FIN was received.
+    eof: bool,
+    /// Whether a FIN goes out after the last of our data.
+    closing: bool,
+    fin_sent: bool,
+    /// Whether the peer acknowledged our FIN, and with it everything we sent.
+    fin_acked: bool,
+    /// Whether the stream was dropped, so that nobody reads what still arrives.
+    dropped: bool,
+    error: Option<std::io::ErrorKind>,
+    /// How long the peer's last packet took to get here, to tell the peer in turn.
+    reply_delay: u32,
+    last_received: Instant,
+    last_sent: Instant,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    /// Packets to be sent as soon as the lock is released.
+    outbox: Vec<Packet>,
+}
+
+impl Connection {
+    fn new(addr: SocketAddr, recv_id: u16, send_id: u16, seq_nr: u16, now: Instant) -> Self {
+        Self {
+            state: State::SynSent,
+            addr,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr: 0,
+            epoch: now,
+            unacked: VecDeque::new(),
+            in_flight: 0,
+            send_buffer: BytesMut::new(),
+            ledbat: Ledbat::new(MAX_PAYLOAD, now),
+            peer_window: MAX_PAYLOAD,
+            duplicate_acks: 0,
+            received: BytesMut::new(),
+            out_of_order: HashMap::new(),
+            fin_received: None,
+            eof: false,
+            closing: false,
+            fin_sent: false,
+            fin_acked: false,
+            dropped: false,
+            error: None,
+            reply_delay: 0,
+            last_received: now,
+            last_sent: now,
+            read_waker: None,
+            write_waker: None,
+            outbox: Vec::new(),
+        }
+    }
+
+    fn timestamp(&self, now: Instant) -> u32 {
+        now.duration_since(self.epoch).as_micros() as u32
+    }
+
+    fn receive_window(&self) -> usize {
+        let out_of_order: usize = self.out_of_order.values().map(Bytes::len).sum();
+        RECEIVE_WINDOW.saturating_sub(self.received.len() + out_of_order)
+    }
+
+    fn unacked_bytes(&self) -> usize {
+        self.unacked
+            .iter()
+            .filter(|sent| !sent.acked)
+            .map(|sent| sent.packet.payload.len())
+            .sum()
+    }
+
+    /// Whether there's nothing left to do for the connection.
+    fn done(&self, now: Instant) -> bool {
+        // once both sides are through, we stick around for a little while in case our ACK of
+        // the peer's FIN got lost, and the peer sends it again
+        let closed = self.fin_acked
+            && self.eof
+            && now.duration_since(self.last_received) >= 2 * self.ledbat.rto();
+        self.error.is_some() || (self.dropped && (self.state == State::SynSent || closed))
+    }
+
+    fn fail(&mut self, kind: std::io::ErrorKind) {
+        self.error.get_or_insert(kind);
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Send `packet` with the latest of what we know about the connection.
+    fn queue(&mut self, mut packet: Packet, now: Instant) {
+        packet.connection_id = match packet.ty {
+            PacketType::Syn => self.recv_id,
+            _ => self.send_id,
+        };
+        packet.timestamp = self.timestamp(now);
+        packet.timestamp_diff = self.reply_delay;
+        packet.window = self.receive_window() as u32;
+        packet.ack_nr = self.ack_nr;
+        packet.sack = self.sack();
+        self.last_sent = now;
+        self.outbox.push(packet);
+    }
+
+    /// The selective ACK of what we received past a gap, if anything.
+    fn sack(&self) -> Option<Vec<u8>> {
+        if self.out_of_order.is_empty() {
+            return None;
+        }
+        let last = self
+            .out_of_order
+            .keys()
+            .map(|&seq_nr| seq_nr.wrapping_sub(self.ack_nr.wrapping_add(2)) as usize)
+            .max()
+            .expect("not empty");
+        let mut sack = vec![0; (last / 8 + 1).div_ceil(4) * 4];
+        for &seq_nr in self.out_of_order.keys() {
+            let i = seq_nr.wrapping_sub(self.ack_nr.wrapping_add(2)) as usize;
+            sack[i / 8] |= 1 << (i % 8);
+        }
+        Some(sack)
+    }
+
+    /// Send a packet that takes up a sequence number, and keep it until it's acknowledged.
+    fn push(&mut self, mut packet: Packet, now: Instant) {
+        packet.seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.in_flight += packet.payload.len();
+        self.queue(packet.clone(), now);
+        self.unacked.push_back(Sent {
+            packet,
+            sent_at: now,
+            transmissions: 1,
+            acked: false,
+            lost: false,
+        });
+    }
+
+    /// Send whatever the windows allow: lost packets first, then new data, then our FIN.
+    fn send(&mut self, now: Instant) {
+        if self.state != State::Connected {
+            return;
+        }
+        let window = self.ledbat.window().min(self.peer_window);
+        // one packet at a time can always go out, so that a closed window gets probed
+        let fits = |in_flight: usize, size: usize| in_flight == 0 || in_flight + size <= window;
+
+        let mut resend = Vec::new();
+        let mut in_flight = self.in_flight;
+        for (i, sent) in self.unacked.iter_mut().enumerate() {
+            if !sent.lost || sent.acked {
+                continue;
+            }
+            if !fits(in_flight, sent.packet.payload.len()) {
+                break;
+            }
+            sent.lost = false;
+            sent.transmissions += 1;
+            sent.sent_at = now;
+            in_flight += sent.packet.payload.len();
+            resend.push(i);
+        }
+        self.in_flight = in_flight;
+        for i in resend {
+            let packet = self.unacked[i].packet.clone();
+            self.queue(packet, now);
+        }
+
+        while !self.send_buffer.is_empty() && self.unacked.iter().all(|sent| !sent.lost) {
+            let size = self.send_buffer.len().min(MAX_PAYLOAD);
+            if !fits(self.in_flight, size) {
+                break;
+            }
+            let mut packet = Packet::new(PacketType::Data, 0);
+            packet.payload = self.send_buffer.split_to(size).freeze();
+            self.push(packet, now);
+        }
+
+        if self.closing && self.send_buffer.is_empty() && !self.fin_sent {
+            self.fin_sent = true;
+            self.push(Packet::new(PacketType::Fin, 0), now);
+        }
+    }
+
+    fn on_packet(&mut self, packet: Packet, now: Instant) {
+        self.last_received = now;
+        self.reply_delay = self.timestamp(now).wrapping_sub(packet.timestamp);
+        self.peer_window = packet.window as usize;
+        match packet.ty {
+            PacketType::Reset => {
+                self.fail(std::io::ErrorKind::ConnectionReset);
+                return;
+            }
+            PacketType::Syn => {
+                // our answer to it may have been lost
+                if self.state == State::Connected {
+                    self.queue(Packet::new(PacketType::State, self.seq_nr), now);
+                }
+                return;
+            }
+            _ => {}
+        }
+        if self.state == State::SynSent {
+            if packet.ty != PacketType::State || packet.ack_nr != self.seq_nr.wrapping_sub(1) {
+                return;
+            }
+            // the peer's first data will take up the sequence number it acknowledged us with
+            self.state = State::Connected;
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            self.wake();
+        }
+
+        self.on_ack(&packet, now);
+        match packet.ty {
+            PacketType::Data => {
+                self.on_data(packet.seq_nr, packet.payload);
+                self.queue(Packet::new(PacketType::State, self.seq_nr), now);
+            }
+            PacketType::Fin => {
+                self.fin_received = Some(packet.seq_nr);
+                self.on_data(packet.seq_nr, Bytes::new());
+                self.queue(Packet::new(PacketType::State, self.seq_nr), now);
+            }
+            _ => {}
+        }
+        self.send(now);
+    }
+
+    fn on_ack(&mut self, packet: &Packet, now: Instant) {
+        let mut acked = 0;
+        let mut rtt = None;
+        for sent in &mut self.unacked {
+            if sent.acked || !packet.acks(sent.packet.seq_nr) {
+                continue;
+            }
+            sent.acked = true;
+            // a packet taken for lost was already taken out of flight, and needn't go out again
+            if !std::mem::take(&mut sent.lost) {
+                self.in_flight -= sent.packet.payload.len();
+            }
+            acked += sent.packet.payload.len();
+            if sent.transmissions == 1 {
+                rtt = Some(now.duration_since(sent.sent_at));
+            }
+        }
+        while self.unacked.front().is_some_and(|sent| sent.acked) {
+            self.unacked.pop_front();
+        }
+        let fin_acked = self.fin_sent && self.unacked.is_empty();
+        if let Some(rtt) = rtt {
+            self.ledbat.on_rtt(rtt);
+        }
+
+        if acked > 0 || fin_acked != self.fin_acked {
+            self.fin_acked = fin_acked;
+            self.duplicate_acks = 0;
+            let delay = Some(packet.timestamp_diff).filter(|&delay| delay != 0);
+            self.ledbat.on_ack(acked, delay, now);
+            if let Some(waker) = self.write_waker.take() {
+                waker.wake();
+            }
+        } else if packet.ty == PacketType::State && !self.unacked.is_empty() {
+            self.duplicate_acks += 1;
+        }
+
+        // the oldest packet is missing while later ones made it: it's most likely lost
+        let later = self.unacked.iter().filter(|sent| sent.acked).count();
+        if let Some(oldest) = self.unacked.front_mut() {
+            if (later >= 3 || self.duplicate_acks >= 3) && !oldest.lost && oldest.transmissions == 1
+            {
+                oldest.lost = true;
+                self.in_flight -= oldest.packet.payload.len();
+                self.duplicate_acks = 0;
+                self.ledbat.on_loss(now);
+            }
+        }
+    }
+
+    fn on_data(&mut self, seq_nr: u16, payload: Bytes) {
+        let ahead = seq_nr.wrapping_sub(self.ack_nr);
+        if ahead == 0 || ahead > MAX_OUT_OF_ORDER {
+            // a duplicate, or too far ahead to keep
+            return;
+        }
+        if payload.len() > self.receive_window() {
+            // the peer ignored the window we told it about
+            return;
+        }
+        if ahead > 1 {
+            if payload.len() > MAX_PAYLOAD {
+                // it comes again in order, once what's missing before it arrives
+                return;
+            }
+            self.out_of_order.insert(seq_nr, payload);
+            return;
+        }
+        self.deliver(payload);
+        self.ack_nr = seq_nr;
+        loop {
+            let next = self.ack_nr.wrapping_add(1);
+            if let Some(payload) = self.out_of_order.remove(&next) {
+                self.deliver(payload);
+                self.ack_nr = next;
+            } else {
+                break;
+            }
+        }
+        if self.fin_received == Some(self.ack_nr) {
+            self.eof = true;
+            // anything after the FIN is meaningless
+            self.out_of_order.clear();
+        }
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn deliver(&mut self, payload: Bytes) {
+        if !self.dropped && !self.eof {
+            self.received.extend_from_slice(&payload);
+        }
+    }
+
+    fn on_tick(&mut self, now: Instant) {
+        if self.error.is_some() {
+            return;
+        }
+        if now.duration_since(self.last_received) > IDLE_TIMEOUT {
+            self.fail(std::io::ErrorKind::TimedOut);
+            return;
+        }
+        if self.dropped && self.fin_acked && !self.eof {
+            // all our data made it, and nobody is left to read the peer's
+            self.queue(Packet::new(PacketType::Reset, self.seq_nr), now);
+            self.fail(std::io::ErrorKind::ConnectionAborted);
+            return;
+        }
+        let rto = self.ledbat.rto();
+        let overdue = self
+            .unacked
+            .iter()
+            .find(|sent| !sent.acked && !sent.lost)
+            .is_some_and(|sent| now.duration_since(sent.sent_at) >= rto);
+        if overdue {
+            if self
+                .unacked
+                .iter()
+                .any(|sent| sent.transmissions >= MAX_TRANSMISSIONS)
+            {
+                self.fail(std::io::ErrorKind::TimedOut);
+                return;
+            }
+            // a SYN has nothing else to go out with
+            if self.state == State::SynSent {
+                let sent = self.unacked.front_mut().expect("the SYN is unacknowledged");
+                sent.transmissions += 1;
+                sent.sent_at = now;
+                let packet = sent.packet.clone();
+                self.ledbat.on_timeout();
+                self.queue(packet, now);
+                return;
+            }
+            // everything in flight is taken for lost, and goes out again as the window allows
+            for sent in &mut self.unacked {
+                if !sent.acked {
+                    sent.lost = true;
+                }
+            }
+            self.in_flight = 0;
+            self.ledbat.on_timeout();
+        }
+        self.send(now);
+        if self.state == State::Connected && now.duration_since(self.last_sent) >= KEEP_ALIVE {
+            self.queue(Packet::new(PacketType::State, self.seq_nr), now);
+        }
+    }
+
+    fn flush_outbox(&mut self, socket: &UdpSocket) {
+        for packet in self.outbox.drain(..) {
+            // a packet that doesn't fit into the socket's buffer counts as lost
+            let _ = socket.try_send_to(&packet.encode(), self.addr);
+        }
+    }
+}
+
+/// Relays packets between two peers, dropping some of them, as a path with losses would.
+#[cfg(test)]
+async fn lossy_relay(to: SocketAddr, drop_every: usize) -> SocketAddr {
+    let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = relay.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut from = None;
+        let mut buf = vec![0; 1 << 16];
+        for i in 1.. {
+            let (n, sender) = relay.recv_from(&mut buf).await.unwrap();
+            let destination = if sender == to {
+                match from {
+                    Some(from) => from,
+                    None => continue,
+                }
+            } else {
+                from = Some(sender);
+                to
+            };
+            if i % drop_every != 0 {
+                relay.send_to(&buf[..n], destination).await.unwrap();
+            }
+        }
+    });
+    addr
+}
+
+#[cfg(test)]
+async fn exchange(a: UtpStream, b: UtpStream, len: usize) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn transfer(mut stream: UtpStream, len: usize, seed: usize) {
+        let data: Vec<u8> = (0..len).map(|i| ((i * seed) % 251) as u8).collect();
+        let (mut read, mut write) = tokio::io::split(&mut stream);
+        let writing = async {
+            write.write_all(&data).await.unwrap();
+            write.shutdown().await.unwrap();
+        };
+        let reading = async {
+            let mut received = Vec::new();
+            read.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let ((), received) = tokio::join!(writing, reading);
+        let expected: Vec<u8> = (0..len).map(|i| ((i * (8 - seed)) % 251) as u8).collect();
+        assert!(received == expected, "received {} bytes", received.len());
+    }
+
+    tokio::join!(transfer(a, len, 3), transfer(b, len, 5));
+}
+
+#[tokio::test]
+async fn utp_transfer() {
+    let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let (connected, accepted) = tokio::join!(client.connect(server_addr), server.accept());
+    let a = connected.unwrap();
+    let (b, from) = accepted.unwrap();
+    assert_eq!(from, client.local_addr().unwrap());
+    assert_eq!(a.peer_addr(), server_addr);
+    tokio::time::timeout(Duration::from_secs(10), exchange(a, b, 1 << 20))
+        .await
+        .unwrap();
+
+    // both sides closed, so both sides forget the connection, if not straight away
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert!(lock(&client.inner.connections).is_empty());
+    assert!(lock(&server.inner.connections).is_empty());
+}
+
+#[tokio::test]
+async fn utp_lossy_path() {
+    let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let relay = lossy_relay(server.local_addr().unwrap(), 10).await;
+    let (connected, accepted) = tokio::join!(client.connect(relay), server.accept());
+    let (a, (b, _)) = (connected.unwrap(), accepted.unwrap());
+    tokio::time::timeout(Duration::from_secs(30), exchange(a, b, 1 << 17))
+        .await
+        .unwrap();
+}
+
+#[test]
+fn utp_selective_ack_after_timeout() {
+    let now = Instant::now();
+    let mut connection = Connection::new("127.0.0.1:1".parse().unwrap(), 1, 2, 1, now);
+    connection.state = State::Connected;
+    connection.peer_window = RECEIVE_WINDOW;
+    connection
+        .send_buffer
+        .extend_from_slice(&[0; 2 * MAX_PAYLOAD]);
+    connection.send(now);
+    assert_eq!(connection.in_flight, 2 * MAX_PAYLOAD);
+
+    // nothing comes back, so both are taken for lost, and the window only lets one go out again
+    let now = now + Duration::from_secs(2);
+    connection.on_tick(now);
+    assert_eq!(connection.in_flight, MAX_PAYLOAD);
+
+    let mut ack = Packet::new(PacketType::State, 1);
+    ack.window = RECEIVE_WINDOW as u32;
+    // the second one turns up after all
+    ack.sack = Some(vec![1, 0, 0, 0]);
+    connection.on_packet(ack.clone(), now);
+    connection.send(now);
+    assert_eq!(connection.in_flight, MAX_PAYLOAD);
+    assert_eq!(
+        connection
+            .unacked
+            .iter()
+            .map(|sent| sent.transmissions)
+            .sum::<u32>(),
+        3
+    );
+
+    ack.ack_nr = 2;
+    ack.sack = None;
+    connection.on_packet(ack, now);
+    assert_eq!(connection.in_flight, 0);
+    assert!(connection.unacked.is_empty());
+}
+
+#[test]
+fn utp_receive_window_enforced() {
+    let now = Instant::now();
+    let mut connection = Connection::new("127.0.0.1:1".parse().unwrap(), 1, 2, 1, now);
+    connection.state = State::Connected;
+
+    // out of order, only packets no larger than ours are kept
+    connection.on_data(2, Bytes::from(vec![0; MAX_PAYLOAD + 1]));
+    connection.on_data(3, Bytes::from(vec![0; MAX_PAYLOAD]));
+    assert_eq!(connection.out_of_order.len(), 1);
+
+    // nothing past what we said we'd take
+    connection
+        .received
+        .extend_from_slice(&vec![0; RECEIVE_WINDOW - 2 * MAX_PAYLOAD]);
+    connection.on_data(1, Bytes::from(vec![0; MAX_PAYLOAD + 1]));
+    assert_eq!(connection.ack_nr, 0);
+    connection.on_data(1, Bytes::from(vec![0; MAX_PAYLOAD]));
+    assert_eq!(connection.ack_nr, 1);
+    assert_eq!(connection.receive_window(), 0);
+}
+
+#[tokio::test]
+async fn utp_connect_unanswered() {
+    let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let connect = client.connect(silent.local_addr().unwrap());
+    assert!(tokio::time::timeout(Duration::from_millis(200), connect)
+        .await
+        .is_err());
+    // giving up on the connection forgets it
+    tokio::time::sleep(3 * TICK).await;
+    assert!(lock(&client.inner.connections).is_empty());
+}
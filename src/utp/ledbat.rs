@@ -0,0 +1,215 @@
+//! LEDBAT congestion control (RFC 6817), which backs off as soon as packets start queueing up
+//! along the path rather than once they get lost, so that uTP yields to other traffic.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How much queueing delay we're willing to add to the path, in microseconds.
+pub(crate) const TARGET_DELAY: u32 = 100_000;
+
+/// How quickly the window moves towards the target, in packets per round trip.
+const GAIN: f64 = 1.0;
+
+/// The lowest delay is remembered per minute, for this many minutes, so that a route change
+/// doesn't leave us with a base delay that's out of reach.
+const BASE_HISTORY: usize = 2;
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(30);
+
+/// The most data we keep in flight, however good the path looks.
+const MAX_WINDOW: usize = 1 << 22;
+
+#[derive(Debug)]
+pub(crate) struct Ledbat {
+    /// The largest packet payload, in bytes.
+    mss: usize,
+    /// How much data we may have in flight, in bytes.
+    window: usize,
+    /// The lowest one-way delay seen in each of the last few minutes, latest last.
+    base_delays: VecDeque<Option<u32>>,
+    /// When the latest entry of `base_delays` was started.
+    base_started: Instant,
+    /// The smoothed round-trip time and its variation, once we've measured one.
+    rtt: Option<(Duration, Duration)>,
+    rto: Duration,
+    /// When the window was last cut because of a lost packet.
+    last_loss: Option<Instant>,
+}
+
+impl Ledbat {
+    pub(crate) fn new(mss: usize, now: Instant) -> Self {
+        Self {
+            mss,
+            window: 2 * mss,
+            base_delays: VecDeque::from([None]),
+            base_started: now,
+            rtt: None,
+            rto: INITIAL_RTO,
+            last_loss: None,
+        }
+    }
+
+    /// How much data may be in flight, in bytes.
+    pub(crate) fn window(&self) -> usize {
+        self.window
+    }
+
+    /// How long to wait for an acknowledgement before taking a packet for lost.
+    pub(crate) fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// `acked` bytes were acknowledged, and the peer says the packet that acknowledged them
+    /// took `delay` microseconds, as far as the clocks of both sides go, to get there.
+    ///
+    /// The clocks needn't agree, so delays are only ever compared with each other, and may wrap
+    /// around.
+    pub(crate) fn on_ack(&mut self, acked: usize, delay: Option<u32>, now: Instant) {
+        if now.duration_since(self.base_started) >= Duration::from_secs(60) {
+            if self.base_delays.len() == BASE_HISTORY {
+                self.base_delays.pop_front();
+            }
+            self.base_delays.push_back(None);
+            self.base_started = now;
+        }
+        let queueing = match delay {
+            Some(delay) => {
+                let latest = self.base_delays.back_mut().expect("never empty");
+                *latest = Some(latest.map_or(delay, |base| lower(base, delay)));
+                let base = self
+                    .base_delays
+                    .iter()
+                    .flatten()
+                    .copied()
+                    .reduce(lower)
+                    .expect("just set");
+                delay.wrapping_sub(base)
+            }
+            // nothing to go by, which only happens at the start
+            None => 0,
+        };
+        let off_target = (TARGET_DELAY as f64 - queueing as f64) / TARGET_DELAY as f64;
+        let change =
+            GAIN * off_target.max(-1.0) * acked as f64 * self.mss as f64 / self.window as f64;
+        self.window =
+            (self.window as f64 + change).clamp(self.mss as f64, MAX_WINDOW as f64) as usize;
+    }
+
+    /// A packet was lost, but others are getting through.
+    pub(crate) fn on_loss(&mut self, now: Instant) {
+        // once per round trip, since a burst of losses is one congestion event
+        let round_trip = self.rtt.map_or(self.rto, |(rtt, _)| rtt);
+        if self
+            .last_loss
+            .is_some_and(|last| now.duration_since(last) < round_trip)
+        {
+            return;
+        }
+        self.last_loss = Some(now);
+        self.window = (self.window / 2).max(self.mss);
+    }
+
+    /// Nothing was acknowledged for a whole retransmission timeout.
+    pub(crate) fn on_timeout(&mut self) {
+        self.window = self.mss;
+        self.rto = (2 * self.rto).min(MAX_RTO);
+    }
+
+    /// A packet that was only sent once was acknowledged `rtt` after it was sent.
+    pub(crate) fn on_rtt(&mut self, rtt: Duration) {
+        // as TCP does it (RFC 6298)
+        let (srtt, rttvar) = match self.rtt {
+            None => (rtt, rtt / 2),
+            Some((srtt, rttvar)) => {
+                let error = srtt.abs_diff(rtt);
+                (srtt * 7 / 8 + rtt / 8, rttvar * 3 / 4 + error / 4)
+            }
+        };
+        self.rtt = Some((srtt, rttvar));
+        self.rto = (srtt + 4 * rttvar).clamp(MIN_RTO, MAX_RTO);
+    }
+}
+
+/// The lower of two delays that may have wrapped around.
+fn lower(a: u32, b: u32) -> u32 {
+    if (b.wrapping_sub(a) as i32) < 0 {
+        b
+    } else {
+        a
+    }
+}
+
+#[test]
+fn ledbat_window() {
+    let now = Instant::now();
+    let mut ledbat = Ledbat::new(1000, now);
+    assert_eq!(ledbat.window(), 2000);
+
+    // no queueing: the window grows by about a packet per window acknowledged
+    ledbat.on_ack(1000, Some(5000), now);
+    ledbat.on_ack(1000, Some(5000), now);
+    assert!(
+        (2800..=3000).contains(&ledbat.window()),
+        "{}",
+        ledbat.window()
+    );
+
+    // at the target it stays put, and past it, it shrinks
+    let window = ledbat.window();
+    ledbat.on_ack(1000, Some(5000 + TARGET_DELAY), now);
+    assert_eq!(ledbat.window(), window);
+    ledbat.on_ack(1000, Some(5000 + 2 * TARGET_DELAY), now);
+    assert!(ledbat.window() < window);
+
+    // a lower delay later on is the new base
+    let window = ledbat.window();
+    ledbat.on_ack(1000, Some(1000), now);
+    ledbat.on_ack(1000, Some(5000), now);
+    assert!(ledbat.window() > window);
+
+    // losses halve the window, but only once per round trip
+    for _ in 0..20 {
+        ledbat.on_ack(1000, Some(1000), now);
+    }
+    ledbat.on_rtt(Duration::from_millis(100));
+    let window = ledbat.window();
+    ledbat.on_loss(now);
+    ledbat.on_loss(now + Duration::from_millis(50));
+    assert_eq!(ledbat.window(), window / 2);
+    ledbat.on_loss(now + Duration::from_millis(200));
+    assert_eq!(ledbat.window(), window / 4);
+
+    // delays only mean anything relative to each other, even across a wrap-around
+    let mut ledbat = Ledbat::new(1000, now);
+    ledbat.on_ack(1000, Some(u32::MAX - 10), now);
+    ledbat.on_ack(1000, Some(20), now);
+    let window = ledbat.window();
+    assert!(window > 2000);
+    ledbat.on_ack(
+        1000,
+        Some((u32::MAX - 10).wrapping_add(2 * TARGET_DELAY)),
+        now,
+    );
+    assert!(ledbat.window() < window);
+
+    // and timeouts leave a single packet
+    ledbat.on_timeout();
+    assert_eq!(ledbat.window(), 1000);
+}
+
+#[test]
+fn ledbat_rto() {
+    let mut ledbat = Ledbat::new(1000, Instant::now());
+    assert_eq!(ledbat.rto(), INITIAL_RTO);
+    ledbat.on_rtt(Duration::from_millis(200));
+    assert_eq!(ledbat.rto(), Duration::from_millis(600));
+    for _ in 0..50 {
+        ledbat.on_rtt(Duration::from_millis(10));
+    }
+    assert_eq!(ledbat.rto(), MIN_RTO);
+    ledbat.on_timeout();
+    assert_eq!(ledbat.rto(), 2 * MIN_RTO);
+}
@@ -0,0 +1,212 @@
+//! The uTP packet format: a fixed header, a chain of extensions, and the payload.
+
+use anyhow::Context;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+pub(crate) const HEADER_LENGTH: usize = 20;
+
+const VERSION: u8 = 1;
+
+/// The extension that carries a selective ACK.
+const EXTENSION_SACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum PacketType {
+    Data = 0,
+    /// The last packet the sender sends, after all of its data.
+    Fin = 1,
+    /// An ACK, without data of its own.
+    State = 2,
+    /// Ends the connection at once.
+    Reset = 3,
+    /// Opens a connection.
+    Syn = 4,
+}
+
+impl PacketType {
+    fn from_u8(ty: u8) -> Option<Self> {
+        Some(match ty {
+            0 => PacketType::Data,
+            1 => PacketType::Fin,
+            2 => PacketType::State,
+            3 => PacketType::Reset,
+            4 => PacketType::Syn,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Packet {
+    pub(crate) ty: PacketType,
+    pub(crate) connection_id: u16,
+    /// When the packet was sent, in microseconds by the sender's clock.
+    pub(crate) timestamp: u32,
+    /// How long the last packet the sender received took to get there, by the clocks of both
+    /// sides. Only differences between these mean anything.
+    pub(crate) timestamp_diff: u32,
+    /// How many more bytes the sender is willing to receive.
+    pub(crate) window: u32,
+    pub(crate) seq_nr: u16,
+    /// The last packet the sender received in order.
+    pub(crate) ack_nr: u16,
+    /// Which of the packets after the next one in order the sender has received as well: bit
+    /// `i` stands for `ack_nr + 2 + i`.
+    pub(crate) sack: Option<Vec<u8>>,
+    pub(crate) payload: Bytes,
+}
+
+impl Packet {
+    pub(crate) fn new(ty: PacketType, seq_nr: u16) -> Self {
+        Self {
+            ty,
+            connection_id: 0,
+            timestamp: 0,
+            timestamp_diff: 0,
+            window: 0,
+            seq_nr,
+            ack_nr: 0,
+            sack: None,
+            payload: Bytes::new(),
+        }
+    }
+
+    /// Whether the packet acknowledges `seq_nr`, in order or selectively.
+    pub(crate) fn acks(&self, seq_nr: u16) -> bool {
+        if seq_le(seq_nr, self.ack_nr) {
+            return true;
+        }
+        let Some(sack) = &self.sack else {
+            return false;
+        };
+        let i = seq_nr.wrapping_sub(self.ack_nr.wrapping_add(2)) as usize;
+        i < 8 * sack.len() && sack[i / 8] & (1 << (i % 8)) != 0
+    }
+
+    pub(crate) fn encode(&self) -> Bytes {
+        let mut dst = BytesMut::with_capacity(HEADER_LENGTH + 34 + self.payload.len());
+        dst.put_u8((self.ty as u8) << 4 | VERSION);
+        dst.put_u8(if self.sack.is_some() {
+            EXTENSION_SACK
+        } else {
+            0
+        });
+        dst.put_u16(self.connection_id);
+        dst.put_u32(self.timestamp);
+        dst.put_u32(self.timestamp_diff);
+        dst.put_u32(self.window);
+        dst.put_u16(self.seq_nr);
+        dst.put_u16(self.ack_nr);
+        if let Some(sack) = &self.sack {
+            dst.put_u8(0);
+            dst.put_u8(sack.len() as u8);
+            dst.put_slice(sack);
+        }
+        dst.put_slice(&self.payload);
+        dst.freeze()
+    }
+
+    pub(crate) fn decode(mut src: Bytes) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            src.len() >= HEADER_LENGTH,
+            "packet too short ({} bytes)",
+            src.len()
+        );
+        let type_version = src.get_u8();
+        anyhow::ensure!(
+            type_version & 0x0f == VERSION,
+            "unknown version {}",
+            type_version & 0x0f
+        );
+        let ty = PacketType::from_u8(type_version >> 4)
+            .with_context(|| format!("unknown packet type {}", type_version >> 4))?;
+        let mut extension = src.get_u8();
+        let mut packet = Self {
+            ty,
+            connection_id: src.get_u16(),
+            timestamp: src.get_u32(),
+            timestamp_diff: src.get_u32(),
+            window: src.get_u32(),
+            seq_nr: src.get_u16(),
+            ack_nr: src.get_u16(),
+            sack: None,
+            payload: Bytes::new(),
+        };
+        while extension != 0 {
+            anyhow::ensure!(src.len() >= 2, "truncated extension");
+            let next = src.get_u8();
+            let length = src.get_u8() as usize;
+            anyhow::ensure!(src.len() >= length, "truncated extension");
+            let data = src.split_to(length);
+            // others we skip over, as we don't know what to make of them
+            if extension == EXTENSION_SACK {
+                anyhow::ensure!(
+                    length > 0 && length.is_multiple_of(4),
+                    "selective ACK of {length} bytes"
+                );
+                packet.sack = Some(data.to_vec());
+            }
+            extension = next;
+        }
+        packet.payload = src;
+        Ok(packet)
+    }
+}
+
+/// Whether sequence number `a` comes no later than `b`, allowing for wrap-around.
+pub(crate) fn seq_le(a: u16, b: u16) -> bool {
+    b.wrapping_sub(a) < 0x8000
+}
+
+#[test]
+fn packet_roundtrip() {
+    let mut packet = Packet::new(PacketType::Data, 65535);
+    packet.connection_id = 1234;
+    packet.timestamp = 1;
+    packet.timestamp_diff = 2;
+    packet.window = 3;
+    packet.ack_nr = 7;
+    packet.payload = Bytes::from_static(b"hello");
+    let bytes = packet.encode();
+    assert_eq!(bytes.len(), HEADER_LENGTH + 5);
+    assert_eq!(bytes[0], 0x01);
+    assert_eq!(Packet::decode(bytes).unwrap(), packet);
+
+    packet.ty = PacketType::State;
+    packet.sack = Some(vec![0b101, 0, 0, 0x80]);
+    packet.payload = Bytes::new();
+    let bytes = packet.encode();
+    assert_eq!(bytes.len(), HEADER_LENGTH + 6);
+    assert_eq!(Packet::decode(bytes).unwrap(), packet);
+    assert!(packet.acks(7) && packet.acks(6) && packet.acks(65000));
+    assert!(!packet.acks(8));
+    assert!(packet.acks(9) && !packet.acks(10) && packet.acks(11));
+    assert!(packet.acks(9 + 31) && !packet.acks(9 + 32));
+
+    let bytes = packet.encode();
+    for broken in [
+        &bytes[..HEADER_LENGTH - 1],
+        &bytes[..HEADER_LENGTH + 4],
+        &[
+            0x51, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ],
+        &[
+            0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ],
+    ] {
+        assert!(
+            Packet::decode(Bytes::copy_from_slice(broken)).is_err(),
+            "{broken:?}"
+        );
+    }
+}
+
+#[test]
+fn sequence_numbers_wrap() {
+    assert!(seq_le(1, 2));
+    assert!(seq_le(2, 2));
+    assert!(!seq_le(3, 2));
+    assert!(seq_le(65535, 0));
+    assert!(!seq_le(0, 65535));
+}